@@ -1,8 +1,9 @@
+use alloc::{vec, vec::Vec};
 use core::{mem, ptr, slice, str};
 use log::debug;
 use uefi::table::cfg::{self, ConfigTableEntry};
 
-use crate::utils::{self, Checksum, Magic, ParseError};
+use crate::utils::{self, Checksum, Hex, Magic, ParseError};
 
 /// The RSDP struct that points to ACPI tables.
 #[repr(C)]
@@ -44,6 +45,15 @@ pub struct ExtendedDescriptionPointer {
 
 impl Checksum for ExtendedDescriptionPointer {}
 
+/// The XSDT or RSDT found through the UEFI config table, along with the address of the RSDP that
+/// points to it.
+#[derive(Clone, Copy)]
+pub struct AcpiTables<'a> {
+    /// Physical address of the RSDP, which is passed to the kernel
+    pub rsdp_addr: u64,
+    pub sdt: &'a AcpiSystemDescriptionTable,
+}
+
 /// An ACPI table (XSDT or RSDT), including header and entries.
 ///
 /// As this table has a variable number of entries, it is not `Sized`.
@@ -57,6 +67,9 @@ impl AcpiSystemDescriptionTable {
     /// Returns true if the checksum is valid.
     ///
     /// This is a separate checksum from [`Checksum`] because `AcpiSystemDescriptionTable` is `?Sized`.
+    ///
+    /// The whole table is summed on every call, so it should only be validated once. This is
+    /// already done by [`AcpiSystemDescriptionTable::from_uefi_config_table`].
     pub fn checksum_valid(&self) -> bool {
         let table_size = 36 + self.entries.len();
         let data = unsafe {
@@ -69,18 +82,54 @@ impl AcpiSystemDescriptionTable {
         utils::checksum(data) == 0
     }
 
+    /// Returns the size in bytes of each entry in this table.
+    ///
+    /// The XSDT contains 64bit pointers, while the RSDT contains 32bit pointers.
+    pub fn entry_size(&self) -> usize {
+        if self.header.magic() == &XSDT_MAGIC {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// Returns the number of tables pointed to by this XSDT/RSDT.
+    pub fn num_entries(&self) -> usize {
+        self.entries.len() / self.entry_size()
+    }
+
+    /// Returns the address of the table pointed to by entry `index`.
+    ///
+    /// Entries are little endian pointers that may not be aligned.
+    fn entry(&self, index: usize) -> u64 {
+        let size = self.entry_size();
+        let mut addr = [0; 8];
+        addr[..size].copy_from_slice(&self.entries[index * size..(index + 1) * size]);
+        u64::from_le_bytes(addr)
+    }
+
     /// Parses the UEFI config tables to find the XSDT or RSDT (XSDT is preferred).
     ///
+    /// The table is returned along with the address of the RSDP, as kernels find ACPI through the
+    /// RSDP.
+    ///
     /// # Errors
     ///
     /// * `ParseError::NoTable`: ACPI table cannot be found
     /// * `ParseError::FailedChecksum`: RSDP or XSDT/RSDT checksum failed
     /// * `ParseError::InvalidSignature`: RSDP or XSDT/RSDT signature is invalid
-    /// * `ParseError::InvalidPointer`: A null pointer was found during parse
-    /// * `ParseError::InvalidSize`: The XSDT/RSDT table size is invalid
-    pub fn from_uefi_config_table(config_table: &[ConfigTableEntry]) -> Result<&Self, ParseError> {
+    /// * `ParseError::InvalidPointer`: A null or non-canonical pointer was found during parse
+    /// * `ParseError::InvalidSize`: The XSDT/RSDT table size is smaller than its header or larger
+    ///   than 1MiB, or its entries are not a whole number of pointers
+    pub fn from_uefi_config_table(
+        config_table: &[ConfigTableEntry],
+    ) -> Result<AcpiTables<'_>, ParseError> {
         // Get RSDP from UEFI config table
         let acpi_table = get_acpi_table(config_table)?;
+        // Return error if address cannot be dereferenced
+        if !utils::is_valid_pointer(acpi_table.address as u64) {
+            return Err(ParseError::InvalidPointer);
+        }
         let addr = acpi_table.address as *const ();
 
         // Convert to RSDP struct
@@ -130,6 +179,11 @@ impl AcpiSystemDescriptionTable {
             rsdp.rsdt_addr as u64
         };
 
+        // Return error if address cannot be dereferenced
+        if !utils::is_valid_pointer(table_addr) {
+            return Err(ParseError::InvalidPointer);
+        }
+
         // Convert to header struct
         // It may or may not be valid
         // Return error if null pointer
@@ -149,7 +203,7 @@ impl AcpiSystemDescriptionTable {
 
         // Convert to table struct
         // It may or may not be valid
-        if table_size < mem::size_of::<DescriptionHeader>() {
+        if !DescriptionHeader::size_valid(table_size) {
             return Err(ParseError::InvalidSize);
         }
         let table = unsafe {
@@ -166,23 +220,85 @@ impl AcpiSystemDescriptionTable {
             return Err(ParseError::FailedChecksum);
         }
 
+        // Return error if entries are not a whole number of pointers
+        // XSDT entries are 64bit pointers, while RSDT entries are 32bit pointers
+        if table.entries.len() % table.entry_size() != 0 {
+            return Err(ParseError::InvalidSize);
+        }
+
         //-----------------------------------
         // RSDT/XSDT is valid at this point
         //-----------------------------------
 
         debug!(
-            "Found {} of size 0x{:x} at 0x{:x}",
+            "Found {} of size 0x{:x} at {}",
             str::from_utf8(table_header.magic()).unwrap(),
             table_size,
-            addr as usize
+            Hex(table_addr)
         );
 
-        Ok(table)
+        Ok(AcpiTables {
+            rsdp_addr: acpi_table.address as u64,
+            sdt: table,
+        })
+    }
+}
+
+/// Finds tables pointed to by an XSDT/RSDT, checksumming each table at most once.
+///
+/// The whole table is summed to validate it, so the result is kept for tables that are looked up
+/// more than once.
+pub struct TableLookup<'a> {
+    sdt: &'a AcpiSystemDescriptionTable,
+    /// Checksum result of each entry, or `None` if it has not been summed yet
+    checksums: Vec<Option<bool>>,
+}
+
+impl<'a> TableLookup<'a> {
+    pub fn new(sdt: &'a AcpiSystemDescriptionTable) -> Self {
+        Self {
+            sdt,
+            checksums: vec![None; sdt.num_entries()],
+        }
+    }
+
+    /// Returns the first table with a signature matching `signature`.
+    ///
+    /// Tables with an invalid pointer, size, or checksum are skipped. Only tables with a matching
+    /// signature are checksummed.
+    pub fn find_table(&mut self, signature: &[u8; 4]) -> Option<&'a DescriptionHeader> {
+        let sdt = self.sdt;
+        self.checksums
+            .iter_mut()
+            .enumerate()
+            .find_map(|(i, checksum)| {
+                let addr = sdt.entry(i);
+                if !utils::is_valid_pointer(addr) {
+                    return None;
+                }
+
+                // The signature is checked first to avoid summing tables that are not needed
+                let header = unsafe { &*(addr as *const DescriptionHeader) };
+                if header.magic() != signature
+                    || !DescriptionHeader::size_valid(header.length as usize)
+                    || !*checksum.get_or_insert_with(|| header.checksum_valid())
+                {
+                    return None;
+                }
+                Some(header)
+            })
     }
 }
 
 const RSDT_MAGIC: [u8; 4] = [0x52, 0x53, 0x44, 0x54];
 const XSDT_MAGIC: [u8; 4] = [0x58, 0x53, 0x44, 0x54];
+const FADT_MAGIC: [u8; 4] = *b"FACP";
+
+/// Largest ACPI table length that is accepted.
+///
+/// Real tables are at most a few hundred KiB. A larger length is most likely corrupt, and reading
+/// the whole table to verify its checksum could fault if the memory after it is not mapped.
+const MAX_TABLE_SIZE: usize = 1024 * 1024;
 
 /// A header for an ACPI table.
 #[repr(C)]
@@ -192,18 +308,99 @@ pub struct DescriptionHeader {
     _revision: u8,
     _checksum: u8,
     _oem_id: [u8; 6],
-    _oem_table_id: u64,
+    // Not a `u64`, as that would pad the header to 40 bytes instead of 36
+    _oem_table_id: [u8; 8],
     _oem_revision: u32,
     _creator_id: u32,
     _creator_revision: u32,
 }
 
+impl DescriptionHeader {
+    /// Returns true if `length` is a plausible table length, which includes the header.
+    fn size_valid(length: usize) -> bool {
+        (mem::size_of::<Self>()..=MAX_TABLE_SIZE).contains(&length)
+    }
+
+    /// Returns the whole table that this header is a part of, including the header.
+    ///
+    /// The table's length should be at least the size of the header.
+    pub fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, self.length as usize) }
+    }
+
+    /// Returns true if the whole table sums to 0.
+    fn checksum_valid(&self) -> bool {
+        #[cfg(test)]
+        tests::CHECKSUMS.with(|count| count.set(count.get() + 1));
+
+        utils::checksum(self.data()) == 0
+    }
+}
+
 impl Magic<4> for DescriptionHeader {
     fn magic(&self) -> &[u8; 4] {
         &self.signature
     }
 }
 
+// FADT fields used to find the PM timer
+const FADT_PM_TIMER_OFFSET: usize = 76;
+const FADT_FLAGS_OFFSET: usize = 112;
+const FADT_X_PM_TIMER_OFFSET: usize = 208;
+const FADT_FLAG_TIMER_32BIT: u32 = 1 << 8;
+const GAS_SIZE: usize = 12;
+const GAS_SYSTEM_IO: u8 = 1;
+
+/// The ACPI power management timer.
+#[derive(Clone, Copy, Debug)]
+pub struct PmTimer {
+    /// I/O port of the timer
+    pub port: u16,
+    /// True if the timer counter is 32bits, rather than 24bits
+    pub is_32bit: bool,
+}
+
+impl PmTimer {
+    /// Frequency of the PM timer in Hz.
+    pub const FREQUENCY: u64 = 3_579_545;
+
+    /// Finds the PM timer using the FADT found through `tables`.
+    ///
+    /// The extended timer address is preferred if it exists. Only PM timers in the I/O port address
+    /// space are supported.
+    ///
+    /// Returns `None` if there is no FADT or it does not have a supported PM timer.
+    pub fn from_acpi(tables: &mut TableLookup) -> Option<Self> {
+        let fadt = tables.find_table(&FADT_MAGIC)?.data();
+        let read_u32 = |offset: usize| {
+            let bytes = fadt.get(offset..offset + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let is_32bit = read_u32(FADT_FLAGS_OFFSET).unwrap_or(0) & FADT_FLAG_TIMER_32BIT != 0;
+
+        // Try extended timer address, which is a generic address structure
+        if let Some(gas) = fadt.get(FADT_X_PM_TIMER_OFFSET..FADT_X_PM_TIMER_OFFSET + GAS_SIZE) {
+            let addr = u64::from_le_bytes(gas[4..].try_into().unwrap());
+            if gas[0] == GAS_SYSTEM_IO && addr != 0 && addr <= u16::MAX as u64 {
+                return Some(Self {
+                    port: addr as u16,
+                    is_32bit,
+                });
+            }
+        }
+
+        // Fall back to 32bit timer address
+        match read_u32(FADT_PM_TIMER_OFFSET)? {
+            0 => None,
+            port if port <= u16::MAX as u32 => Some(Self {
+                port: port as u16,
+                is_32bit,
+            }),
+            _ => None,
+        }
+    }
+}
+
 fn get_acpi_table(config_table: &[ConfigTableEntry]) -> Result<&ConfigTableEntry, ParseError> {
     // Search for ACPI 2.0 table.
     if let Some(entry) = config_table.iter().find(|e| e.guid == cfg::ACPI2_GUID) {
@@ -219,3 +416,136 @@ fn get_acpi_table(config_table: &[ConfigTableEntry]) -> Result<&ConfigTableEntry
 
     Err(ParseError::NoTable)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    std::thread_local! {
+        /// Number of tables summed by `DescriptionHeader::checksum_valid` on this thread
+        pub static CHECKSUMS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Returns an ACPI table with a valid checksum, unless `valid` is false.
+    ///
+    /// The table is stored in `u64`s to align the header.
+    fn table(signature: &[u8; 4], body: &[u8], valid: bool) -> Vec<u64> {
+        let length = mem::size_of::<DescriptionHeader>() + body.len();
+        let mut bytes = vec![0u8; length];
+        bytes[..4].copy_from_slice(signature);
+        bytes[4..8].copy_from_slice(&(length as u32).to_le_bytes());
+        bytes[mem::size_of::<DescriptionHeader>()..].copy_from_slice(body);
+        bytes[9] = 0u8.wrapping_sub(utils::checksum(&bytes));
+        if !valid {
+            bytes[9] = bytes[9].wrapping_add(1);
+        }
+
+        let mut words = vec![0u64; (length + 7) / 8];
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), words.as_mut_ptr() as *mut u8, length) }
+        words
+    }
+
+    /// Returns an XSDT that points to every table in `tables`.
+    fn xsdt(tables: &[&Vec<u64>]) -> Vec<u64> {
+        let entries: Vec<u8> = tables
+            .iter()
+            .flat_map(|table| (table.as_ptr() as u64).to_le_bytes())
+            .collect();
+        table(&XSDT_MAGIC, &entries, true)
+    }
+
+    fn as_sdt(xsdt: &[u64]) -> &AcpiSystemDescriptionTable {
+        let length = u32::from_le_bytes(xsdt[0].to_le_bytes()[4..].try_into().unwrap());
+        unsafe {
+            &*ptr::from_raw_parts(
+                xsdt.as_ptr() as *const (),
+                length as usize - mem::size_of::<DescriptionHeader>(),
+            )
+        }
+    }
+
+    #[test]
+    fn header_size() {
+        assert_eq!(mem::size_of::<DescriptionHeader>(), 36);
+    }
+
+    #[test]
+    fn each_table_is_checksummed_once() {
+        let fadt = table(&FADT_MAGIC, &[0; 32], true);
+        let ssdt = table(b"SSDT", &[0xaa; 4096], true);
+        let bad = table(b"HPET", &[0; 16], false);
+        let xsdt = xsdt(&[&fadt, &ssdt, &bad]);
+        let sdt = as_sdt(&xsdt);
+        assert!(sdt.checksum_valid());
+        assert_eq!(sdt.num_entries(), 3);
+
+        CHECKSUMS.with(|count| count.set(0));
+        let mut tables = TableLookup::new(sdt);
+        for _ in 0..3 {
+            let found = tables.find_table(b"SSDT").unwrap();
+            assert_eq!(found as *const _ as usize, ssdt.as_ptr() as usize);
+            assert!(tables.find_table(&FADT_MAGIC).is_some());
+            assert!(tables.find_table(b"HPET").is_none());
+            assert!(tables.find_table(b"APIC").is_none());
+        }
+
+        // Only the three tables with a matching signature are summed, once each
+        assert_eq!(CHECKSUMS.with(Cell::get), 3);
+    }
+
+    /// Returns an XSDP that points to `xsdt`, stored in `u64`s to align it.
+    fn xsdp(xsdt: &[u64]) -> Vec<u64> {
+        let mut bytes = [0u8; mem::size_of::<ExtendedDescriptionPointer>()];
+        bytes[..8].copy_from_slice(RootDescriptionPointer::valid_magic());
+        bytes[15] = 2;
+        bytes[24..32].copy_from_slice(&(xsdt.as_ptr() as u64).to_le_bytes());
+        // The RSDP part and the whole XSDP are each summed to 0
+        bytes[8] = 0u8.wrapping_sub(utils::checksum(&bytes[..20]));
+        bytes[32] = 0u8.wrapping_sub(utils::checksum(&bytes));
+
+        let mut words = vec![0u64; bytes.len() / 8 + 1];
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), words.as_mut_ptr() as *mut u8, bytes.len())
+        }
+        words
+    }
+
+    /// Sets the length field of `table` to `length`, without updating its checksum.
+    fn set_length(table: &mut [u64], length: u32) {
+        let mut word = table[0].to_le_bytes();
+        word[4..].copy_from_slice(&length.to_le_bytes());
+        table[0] = u64::from_le_bytes(word);
+    }
+
+    #[test]
+    fn huge_table_length() {
+        let config_table = |xsdp: &[u64]| {
+            [ConfigTableEntry {
+                guid: cfg::ACPI2_GUID,
+                address: xsdp.as_ptr() as *const _,
+            }]
+        };
+        let valid_xsdt = xsdt(&[]);
+        let valid_xsdp = xsdp(&valid_xsdt);
+        assert!(
+            AcpiSystemDescriptionTable::from_uefi_config_table(&config_table(&valid_xsdp)).is_ok()
+        );
+
+        // Only the header is read, as the length is checked first
+        let mut huge_xsdt = table(&XSDT_MAGIC, &[], true);
+        set_length(&mut huge_xsdt, MAX_TABLE_SIZE as u32 + 1);
+        let huge_xsdp = xsdp(&huge_xsdt);
+        assert!(matches!(
+            AcpiSystemDescriptionTable::from_uefi_config_table(&config_table(&huge_xsdp)),
+            Err(ParseError::InvalidSize)
+        ));
+
+        // Tables found through the XSDT are skipped instead
+        let mut ssdt = table(b"SSDT", &[], true);
+        set_length(&mut ssdt, 0x4000_0000);
+        let xsdt = xsdt(&[&ssdt]);
+        let mut tables = TableLookup::new(as_sdt(&xsdt));
+        assert!(tables.find_table(b"SSDT").is_none());
+    }
+}
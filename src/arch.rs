@@ -0,0 +1,15 @@
+//! Architecture specific constants and routines.
+//!
+//! Everything outside of this module should be architecture-neutral. Code that depends on the
+//! target architecture (such as paging and passing control to the kernel) belongs in the
+//! submodule for that architecture.
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::*;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::*;
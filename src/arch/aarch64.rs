@@ -0,0 +1,61 @@
+use core::arch::asm;
+
+/// Direct serial output, which is not supported on AArch64 yet.
+///
+/// The UART address depends on the board, so output is discarded until it is read from the device
+/// tree.
+pub mod serial {
+    use core::fmt::{self, Write};
+
+    /// Discards `s`.
+    pub fn write_str(_s: &str) {}
+
+    /// A [`Write`] implementation that discards everything written to it.
+    pub struct SerialWriter;
+
+    impl Write for SerialWriter {
+        fn write_str(&mut self, _s: &str) -> fmt::Result {
+            Ok(())
+        }
+    }
+}
+
+/// ELF machine type (ISA) of AArch64 executables.
+pub const ELF_ISA: u16 = 0xb7;
+
+/// Size offset (`TCR_EL1.T0SZ`) of the lower virtual address range, which UEFI identity maps memory
+/// in.
+///
+/// An offset of 16 is a 48bit range, which every AArch64 core supports with 4KiB pages.
+const T0SZ: u32 = 16;
+/// Size offset (`TCR_EL1.T1SZ`) of the upper virtual address range, which BOOTBOOT kernels are
+/// linked in.
+const T1SZ: u32 = 16;
+
+/// Returns true if `addr` is in the lower or upper virtual address range.
+///
+/// Unlike x86_64, AArch64 does not sign extend addresses. The lower range is translated with
+/// `TTBR0` and its addresses have the top `T0SZ` bits clear, and the upper range is translated with
+/// `TTBR1` and its addresses have the top `T1SZ` bits set. Addresses between the ranges fault.
+/// Top byte ignore is not enabled, so tagged addresses are not valid.
+pub fn is_canonical(addr: u64) -> bool {
+    let in_lower = addr >> (64 - T0SZ) == 0;
+    let in_upper = addr >> (64 - T1SZ) == (1 << T1SZ) - 1;
+    in_lower || in_upper
+}
+
+/// Halts the processor until the next interrupt.
+pub fn halt() {
+    unsafe {
+        asm!("wfi", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Returns the ID of the current core, which is affinity level 0 of the MPIDR register.
+pub fn core_id() -> u32 {
+    let mpidr: u64;
+    unsafe {
+        asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack, preserves_flags));
+    }
+    (mpidr & 0xff) as u32
+}
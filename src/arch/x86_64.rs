@@ -0,0 +1,79 @@
+use core::arch::{
+    asm,
+    x86_64::{__cpuid, __cpuid_count, _rdtsc},
+};
+
+pub mod cpu;
+pub mod paging;
+pub mod serial;
+
+/// ELF machine type (ISA) of x86_64 executables.
+pub const ELF_ISA: u16 = 0x3e;
+
+/// Returns true if `addr` is a canonical virtual address.
+///
+/// With 48bit virtual addresses, bits 48 to 63 must be copies of bit 47.
+pub fn is_canonical(addr: u64) -> bool {
+    ((addr << 16) as i64 >> 16) as u64 == addr
+}
+
+/// Returns the current value of the time stamp counter.
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Reads a 32bit value from an I/O port.
+///
+/// # Safety
+///
+/// Reading some I/O ports has side effects on the device behind them.
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Reads an 8bit value from an I/O port.
+///
+/// # Safety
+///
+/// Reading some I/O ports has side effects on the device behind them.
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Writes an 8bit value to an I/O port.
+///
+/// # Safety
+///
+/// Writing to I/O ports controls the device behind them.
+pub unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Halts the processor until the next interrupt.
+pub fn halt() {
+    unsafe {
+        asm!("hlt", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Returns the local APIC ID of the current core.
+///
+/// CPUID leaf 1 only has the 8bit xAPIC ID, which is not unique on systems with more than 255
+/// cores that use x2APIC. If CPUID leaf 0xb is supported, the full 32bit x2APIC ID is returned
+/// instead. Both IDs are the same on systems with fewer cores.
+pub fn core_id() -> u32 {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf >= 0xb {
+        let topology = unsafe { __cpuid_count(0xb, 0) };
+        // Leaf 0xb is only valid if it reports at least one logical processor
+        if topology.ebx != 0 {
+            return topology.edx;
+        }
+    }
+
+    unsafe { __cpuid(1) }.ebx >> 24
+}
@@ -0,0 +1,44 @@
+//! CPU feature detection using CPUID.
+//!
+//! This is detection only. The loader does not build page tables yet, so it never sets EFER.NXE,
+//! programs the PAT, or maps 1GiB pages, and these results are only logged. When paging is
+//! implemented, each feature must be checked before it is used, as enabling an unsupported feature
+//! (such as setting EFER.NXE) faults on older processors.
+
+use core::arch::x86_64::{__cpuid, CpuidResult};
+
+/// Extended CPUID leaf with the NX and 1GiB page feature bits.
+const EXTENDED_FEATURES_LEAF: u32 = 0x8000_0001;
+
+/// Returns the result of CPUID `leaf`, or `None` if the processor does not support `leaf`.
+fn cpuid(leaf: u32) -> Option<CpuidResult> {
+    // Leaf 0 and 0x8000_0000 return the highest supported standard and extended leaf
+    let max_leaf = unsafe { __cpuid(leaf & 0x8000_0000) }.eax;
+    if leaf > max_leaf {
+        return None;
+    }
+
+    Some(unsafe { __cpuid(leaf) })
+}
+
+/// Returns true if `bit` is set in EDX of CPUID `leaf`.
+fn has_edx_bit(leaf: u32, bit: u32) -> bool {
+    matches!(cpuid(leaf), Some(result) if result.edx & (1 << bit) != 0)
+}
+
+/// Returns true if the processor supports the no-execute page bit.
+pub fn has_nx() -> bool {
+    has_edx_bit(EXTENDED_FEATURES_LEAF, 20)
+}
+
+/// Returns true if the processor supports the page attribute table.
+pub fn has_pat() -> bool {
+    has_edx_bit(1, 16)
+}
+
+/// Returns true if the processor supports 1GiB pages.
+///
+/// 2MiB pages are supported by every x86_64 processor, so they can be used instead.
+pub fn has_1g_pages() -> bool {
+    has_edx_bit(EXTENDED_FEATURES_LEAF, 26)
+}
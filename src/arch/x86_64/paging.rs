@@ -0,0 +1,143 @@
+//! Inspection of 4-level x86_64 page tables.
+//!
+//! The loader does not build its own page tables yet, so this is used to dump the tables that are
+//! active when it runs. Page tables are read through their physical addresses, which only works
+//! while memory is identity mapped.
+
+use core::{
+    arch::asm,
+    fmt::{self, Display, Formatter},
+};
+use log::debug;
+
+use crate::utils::Hex;
+
+const ENTRIES_PER_TABLE: usize = 512;
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const HUGE_PAGE: u64 = 1 << 7;
+const NO_EXECUTE: u64 = 1 << 63;
+/// Bits 12 to 51 of an entry, which hold the physical address of a table or page.
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Effective access flags of a mapping, combined from every level of the page tables.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PageFlags {
+    pub writable: bool,
+    pub no_execute: bool,
+}
+
+impl Display for PageFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            if self.writable { "RW" } else { "R-" },
+            if self.no_execute { " NX" } else { "" }
+        )
+    }
+}
+
+/// Returns the physical address of the active PML4, from CR3.
+pub fn current_pml4() -> u64 {
+    let cr3: u64;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+    cr3 & ADDRESS_MASK
+}
+
+/// Calls `mapping` with the virtual address, physical address, size, and flags of every page
+/// mapped by the PML4 at `pml4_addr`, in order of virtual address.
+///
+/// # Safety
+///
+/// `pml4_addr` must be the physical address of a valid PML4, and every table it refers to must
+/// be identity mapped.
+pub unsafe fn walk(pml4_addr: u64, mut mapping: impl FnMut(u64, u64, u64, PageFlags)) {
+    let flags = PageFlags {
+        writable: true,
+        no_execute: false,
+    };
+    walk_table(pml4_addr, 4, 0, flags, &mut mapping);
+}
+
+/// Walks the table at `table_addr`, which is at `level` (4 for the PML4, 1 for a page table) and
+/// maps the virtual addresses starting at `virt_base`.
+unsafe fn walk_table(
+    table_addr: u64,
+    level: u32,
+    virt_base: u64,
+    flags: PageFlags,
+    mapping: &mut dyn FnMut(u64, u64, u64, PageFlags),
+) {
+    let table = &*(table_addr as *const [u64; ENTRIES_PER_TABLE]);
+    // Each entry maps 4KiB at level 1, 2MiB at level 2, 1GiB at level 3, and 512GiB at level 4
+    let entry_size = 1u64 << (12 + 9 * (level - 1));
+    for (i, &entry) in table.iter().enumerate() {
+        if entry & PRESENT == 0 {
+            continue;
+        }
+
+        // Virtual addresses are sign extended from bit 47
+        let virt = virt_base + i as u64 * entry_size;
+        let virt = ((virt << 16) as i64 >> 16) as u64;
+        // A mapping is only writable if every level allows it, and is not executable if any level
+        // forbids it
+        let flags = PageFlags {
+            writable: flags.writable && entry & WRITABLE != 0,
+            no_execute: flags.no_execute || entry & NO_EXECUTE != 0,
+        };
+
+        if level == 1 || (level <= 3 && entry & HUGE_PAGE != 0) {
+            // Huge pages are aligned to their size, and the low bits hold the PAT flag
+            let phys = entry & ADDRESS_MASK & !(entry_size - 1);
+            mapping(virt, phys, entry_size, flags);
+        } else {
+            walk_table(entry & ADDRESS_MASK, level - 1, virt, flags, mapping);
+        }
+    }
+}
+
+/// Logs every mapped virtual range of the PML4 at `pml4_addr` with its physical address and flags.
+///
+/// Pages that are contiguous in both virtual and physical memory and have the same flags are
+/// logged as one range.
+///
+/// # Safety
+///
+/// See [`walk`].
+pub unsafe fn dump(pml4_addr: u64) {
+    debug!("Page tables at {}:", Hex(pml4_addr));
+
+    // Range that is being extended: virtual address, physical address, size, and flags
+    let mut range: Option<(u64, u64, u64, PageFlags)> = None;
+    let log_range = |(virt, phys, size, flags): (u64, u64, u64, PageFlags)| {
+        debug!(
+            "{}-{} -> {} ({} KiB, {})",
+            Hex(virt),
+            Hex(virt + (size - 1)),
+            Hex(phys),
+            size / 1024,
+            flags
+        );
+    };
+    walk(pml4_addr, |virt, phys, size, flags| match range {
+        Some((range_virt, range_phys, range_size, range_flags))
+            if range_virt + range_size == virt
+                && range_phys + range_size == phys
+                && range_flags == flags =>
+        {
+            range = Some((range_virt, range_phys, range_size + size, flags));
+        }
+        _ => {
+            if let Some(range) = range {
+                log_range(range);
+            }
+            range = Some((virt, phys, size, flags));
+        }
+    });
+    if let Some(range) = range {
+        log_range(range);
+    }
+}
@@ -0,0 +1,75 @@
+//! Direct output to the COM1 serial port.
+//!
+//! This does not depend on UEFI or the logger, so it can be used before they are initialized and
+//! from the panic handler.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{inb, outb};
+
+/// I/O port of the first serial port.
+const COM1: u16 = 0x3f8;
+/// Offsets of the UART registers from the base port
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+/// Line status bit that is set when the transmit buffer can take another byte.
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+/// Number of times to poll the line status before dropping a byte, so that a missing or stuck
+/// serial port cannot hang the bootloader.
+const TRANSMIT_TIMEOUT: usize = 100_000;
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Sets COM1 to 115200 baud with 8 data bits, no parity, and one stop bit.
+fn init() {
+    unsafe {
+        outb(COM1 + INTERRUPT_ENABLE, 0x00);
+        // Set the baud rate divisor to 1 while the divisor latch is enabled
+        outb(COM1 + LINE_CONTROL, 0x80);
+        outb(COM1, 0x01);
+        outb(COM1 + INTERRUPT_ENABLE, 0x00);
+        outb(COM1 + LINE_CONTROL, 0x03);
+        outb(COM1 + FIFO_CONTROL, 0xc7);
+        outb(COM1 + MODEM_CONTROL, 0x0b);
+    }
+}
+
+/// Writes `byte` to COM1, initializing the port first if needed.
+pub fn write_byte(byte: u8) {
+    if !INITIALIZED.swap(true, Ordering::Relaxed) {
+        init();
+    }
+
+    for _ in 0..TRANSMIT_TIMEOUT {
+        if unsafe { inb(COM1 + LINE_STATUS) } & TRANSMIT_EMPTY != 0 {
+            unsafe { outb(COM1, byte) };
+            return;
+        }
+    }
+}
+
+/// Writes `s` to COM1, translating `\n` to `\r\n`.
+pub fn write_str(s: &str) {
+    for byte in s.bytes() {
+        if byte == b'\n' {
+            write_byte(b'\r');
+        }
+        write_byte(byte);
+    }
+}
+
+/// A [`Write`] implementation that prints to COM1, for formatting without the logger.
+pub struct SerialWriter;
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_str(s);
+        Ok(())
+    }
+}
@@ -0,0 +1,5 @@
+mod crc32;
+mod sha256;
+
+pub use crc32::crc32;
+pub use sha256::{sha256, sha256_from_hex};
@@ -0,0 +1,172 @@
+/// Size of a SHA-256 digest in bytes.
+pub const SHA256_SIZE: usize = 32;
+
+const BLOCK_SIZE: usize = 64;
+
+/// SHA-256 round constants.
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 initial hash values.
+const H: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Returns the SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> [u8; SHA256_SIZE] {
+    let mut state = H;
+
+    // Process every full block
+    let mut blocks = data.chunks_exact(BLOCK_SIZE);
+    for block in &mut blocks {
+        compress(&mut state, block.try_into().unwrap());
+    }
+
+    // Pad the last block with a 1 bit, zeros, and the message length in bits
+    // This takes up two blocks if there is no room for the length in the first
+    let remainder = blocks.remainder();
+    let mut last_blocks = [0; 2 * BLOCK_SIZE];
+    last_blocks[..remainder.len()].copy_from_slice(remainder);
+    last_blocks[remainder.len()] = 0x80;
+    let last_blocks_len = if remainder.len() < BLOCK_SIZE - 8 {
+        BLOCK_SIZE
+    } else {
+        2 * BLOCK_SIZE
+    };
+    let bit_len = (data.len() as u64).wrapping_mul(8);
+    last_blocks[last_blocks_len - 8..last_blocks_len].copy_from_slice(&bit_len.to_be_bytes());
+    for block in last_blocks[..last_blocks_len].chunks_exact(BLOCK_SIZE) {
+        compress(&mut state, block.try_into().unwrap());
+    }
+
+    // Convert state to big endian digest
+    let mut digest = [0; SHA256_SIZE];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// Parses a SHA-256 digest from the first 64 characters of `hex`.
+///
+/// Returns `None` if `hex` does not start with 64 hexadecimal characters.
+pub fn sha256_from_hex(hex: &str) -> Option<[u8; SHA256_SIZE]> {
+    let hex = hex.as_bytes().get(..2 * SHA256_SIZE)?;
+
+    let mut digest = [0; SHA256_SIZE];
+    for (byte, pair) in digest.iter_mut().zip(hex.chunks_exact(2)) {
+        let high = (pair[0] as char).to_digit(16)?;
+        let low = (pair[1] as char).to_digit(16)?;
+        *byte = (high << 4 | low) as u8;
+    }
+    Some(digest)
+}
+
+/// Updates `state` with a single 64 byte `block`.
+fn compress(state: &mut [u32; 8], block: &[u8; BLOCK_SIZE]) {
+    // Create message schedule
+    let mut w = [0; 64];
+    for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_be_bytes(bytes.try_into().unwrap());
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    // Run compression rounds
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let temp1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(K[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let temp2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(temp1);
+        d = c;
+        c = b;
+        b = a;
+        a = temp1.wrapping_add(temp2);
+    }
+
+    // Add compressed block to state
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn digest(hex: &str) -> [u8; SHA256_SIZE] {
+        sha256_from_hex(hex).unwrap()
+    }
+
+    #[test]
+    fn fips_180_2_vectors() {
+        assert_eq!(
+            sha256(b"abc"),
+            digest("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            sha256(b""),
+            digest("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            digest("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+        );
+        assert_eq!(
+            sha256(&vec![b'a'; 1_000_000]),
+            digest("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0")
+        );
+    }
+
+    #[test]
+    fn padding_boundaries() {
+        // 55 bytes fit the length in the last block, 56 bytes need an extra block
+        assert_eq!(
+            sha256(&[b'a'; 55]),
+            digest("9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318")
+        );
+        assert_eq!(
+            sha256(&[b'a'; 56]),
+            digest("b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a")
+        );
+        assert_eq!(
+            sha256(&[b'a'; 64]),
+            digest("ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb")
+        );
+    }
+
+    #[test]
+    fn from_hex() {
+        let hex = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD  core\n";
+        assert_eq!(sha256_from_hex(hex), Some(sha256(b"abc")));
+        assert_eq!(sha256_from_hex("ba78"), None);
+        assert_eq!(sha256_from_hex(&"g".repeat(64)), None);
+    }
+}
@@ -1,19 +1,23 @@
-use core::{mem, slice};
+use alloc::vec::Vec;
+use core::{mem, ops::Range, ptr, str};
 
-use crate::utils::Magic;
+use crate::{arch, utils::Magic};
 
 /// An error resulting from parsing an ELF file.
 #[derive(Copy, Clone, Debug)]
 pub enum ElfParseError {
     InvalidAbi,
+    InvalidEntry,
     InvalidFileType,
     InvalidIsa,
     InvalidMagic,
     InvalidOffset,
     InvalidSize,
     InvalidVersion,
+    MissingLoadSegment,
     Not64Bit,
     NotLittleEndian,
+    OverlappingSegments,
     TooManyHeaders,
 }
 
@@ -24,11 +28,12 @@ const SIZE_64_BITS: u8 = 2;
 const LITTLE_ENDIAN: u8 = 1;
 
 const EXEC_FILE_TYPE: u16 = 2;
+/// File type of position independent executables, which are shared objects with an entry point.
+const DYN_FILE_TYPE: u16 = 3;
 
 const ELF_IDENT_VERSION: u8 = 1;
 const ELF_OLD_VERSION: u32 = 1;
 const SYSTEMV_ABI: u8 = 0;
-const X86_64_ISA: u16 = 0x3e;
 
 /// The header for an ELF64 file.
 #[repr(C)]
@@ -65,18 +70,24 @@ impl ElfHeader64 {
         self.ident[5]
     }
 
-    /// Returns every section and program header in this ELF file.
+    /// Returns a copy of every section and program header in this ELF file.
+    ///
+    /// Entries may be larger than the header structs, as newer toolchains may add fields after the
+    /// known ones. Each header is read from the start of its entry and the rest is ignored.
     ///
     /// # Errors
     ///
     /// * `ElfParseError::TooManyHeaders`: Specified headers do not fit in `data`
-    /// * `ElfParseError::InvalidSize`: Section/program header specified size does not match struct
+    /// * `ElfParseError::InvalidSize`: Section/program header specified size is smaller than struct
     /// size
-    /// * `ElfParseError::InvalidOffset`: Section/program header offset goes past `data` end
+    /// * `ElfParseError::InvalidOffset`: The section/program headers go past `data` end
+    ///
+    /// A header table with no entries is allowed to have any offset, as stripped kernels may not
+    /// have a section header table at all.
     pub fn get_headers(
         &self,
         data: &[u8],
-    ) -> Result<(&[ElfSectionHeader64], &[ElfProgramHeader64]), ElfParseError> {
+    ) -> Result<(Vec<ElfSectionHeader64>, Vec<ElfProgramHeader64>), ElfParseError> {
         // Get size of all headers
         let ph_size = self.ph_num as usize * self.ph_entry_size as usize;
         let sh_size = self.sh_num as usize * self.sh_entry_size as usize;
@@ -84,32 +95,108 @@ impl ElfHeader64 {
         if headers_size > data.len() {
             return Err(ElfParseError::TooManyHeaders);
         }
-        // Ensure entry sizes match header sizes
-        if self.ph_entry_size as usize != mem::size_of::<ElfProgramHeader64>()
-            || self.sh_entry_size as usize != mem::size_of::<ElfSectionHeader64>()
+        // Ensure entries are large enough to hold the headers
+        // Empty header tables are allowed to have any entry size
+        if (self.ph_num != 0
+            && (self.ph_entry_size as usize) < mem::size_of::<ElfProgramHeader64>())
+            || (self.sh_num != 0
+                && (self.sh_entry_size as usize) < mem::size_of::<ElfSectionHeader64>())
         {
             return Err(ElfParseError::InvalidSize);
         }
-        // Ensure offsets point to valid headers
-        if self.sh_offset as usize >= data.len() || self.ph_offset as usize >= data.len() {
+        // Ensure each header table fits in `data` from its own offset, as the combined size above
+        // does not account for where the tables are
+        // Empty tables are never read, so their offsets are not checked
+        let fits = |offset: usize, size: usize| match offset.checked_add(size) {
+            _ if size == 0 => true,
+            Some(end) => end <= data.len(),
+            None => false,
+        };
+        if !fits(self.sh_offset, sh_size) || !fits(self.ph_offset, ph_size) {
             return Err(ElfParseError::InvalidOffset);
         }
-        // Get slices of section and program headers
-        let section_headers = unsafe {
-            slice::from_raw_parts(
-                &data[self.sh_offset as usize] as *const u8 as *const ElfSectionHeader64,
-                self.sh_num as usize,
-            )
-        };
-        let program_headers = unsafe {
-            slice::from_raw_parts(
-                &data[self.ph_offset as usize] as *const u8 as *const ElfProgramHeader64,
-                self.ph_num as usize,
-            )
-        };
+        // Copy each header from the start of its entry
+        let section_headers = read_entries(
+            data,
+            self.sh_offset,
+            self.sh_entry_size as usize,
+            self.sh_num as usize,
+        );
+        let program_headers = read_entries(
+            data,
+            self.ph_offset,
+            self.ph_entry_size as usize,
+            self.ph_num as usize,
+        );
         Ok((section_headers, program_headers))
     }
 
+    /// Returns the entry point of this executable, which is jumped to when handing off to the
+    /// kernel.
+    ///
+    /// # Errors
+    ///
+    /// * `ElfParseError::InvalidEntry`: Entry point is null, non-canonical, or not in the negative
+    ///   (higher half) address space that BOOTBOOT kernels are linked at
+    ///
+    /// Position independent executables are usually linked at zero, so their entry point is only
+    /// required to be non-null and canonical. Whether they can be loaded at those addresses is up
+    /// to the caller.
+    pub fn entry_point(&self) -> Result<usize, ElfParseError> {
+        let entry = self.entry as u64;
+        let higher_half = (entry as i64) < 0;
+        if entry == 0 || (!higher_half && !self.is_pie()) || !arch::is_canonical(entry) {
+            return Err(ElfParseError::InvalidEntry);
+        }
+        Ok(self.entry)
+    }
+
+    /// Ensures that the entry point is inside one of the LOAD segments in `program_headers`, so
+    /// that jumping to it runs loaded code.
+    ///
+    /// # Errors
+    ///
+    /// * `ElfParseError::InvalidEntry`: Entry point is not in the memory range of any LOAD segment
+    pub fn validate_entry(
+        &self,
+        program_headers: &[ElfProgramHeader64],
+    ) -> Result<(), ElfParseError> {
+        let in_segment = |ph: &&ElfProgramHeader64| {
+            self.entry >= ph.vaddr && self.entry - ph.vaddr < ph.mem_size
+        };
+        match ElfProgramHeader64::load_segments(program_headers).find(in_segment) {
+            Some(_) => Ok(()),
+            None => Err(ElfParseError::InvalidEntry),
+        }
+    }
+
+    /// Returns true if this is a position independent executable, which may need to be relocated.
+    ///
+    /// After being parsed in [`ElfHeader64::new`], this header is otherwise guaranteed to be a
+    /// regular executable.
+    pub fn is_pie(&self) -> bool {
+        self.file_type == DYN_FILE_TYPE
+    }
+
+    /// Returns the contents of the section named `section_name` in `data`, which is the whole ELF
+    /// file, such as `.text` or a custom section.
+    ///
+    /// Returns `None` if the headers are invalid, there is no section with `section_name`, or the
+    /// section or the section name string table goes past the end of `data`.
+    // Not used by the loader, which only needs the symbol tables, but kept for tools that inspect
+    // kernels
+    #[allow(dead_code)]
+    pub fn get_section_by_name<'a>(&self, data: &'a [u8], section_name: &[u8]) -> Option<&'a [u8]> {
+        let (section_headers, _) = self.get_headers(data).ok()?;
+        let str_table = section_headers
+            .get(self.sh_string_index as usize)?
+            .bytes(data)?;
+        section_headers
+            .iter()
+            .find(|sh| sh.has_name(section_name, str_table))?
+            .bytes(data)
+    }
+
     /// Returns the version number in the identification part of the header.
     ///
     /// After being parsed in [`ElfHeader64::new`], this version is guarenteed to be 1.
@@ -124,15 +211,17 @@ impl ElfHeader64 {
     ///
     ///
     /// * `ElfParseError::InvalidAbi`: ABI is not SystemV
-    /// * `ElfParseError::InvalidFileType`: ELF is not executable
-    /// * `ElfParseError::InvalidIsa`: ISA is not X86_64
+    /// * `ElfParseError::InvalidFileType`: ELF is neither an executable nor a position independent
+    ///   executable
+    /// * `ElfParseError::InvalidIsa`: ISA does not match the target architecture
     /// * `ElfParseError::InvalidMagic`: Magic values are invalid
     /// * `ElfParseError::InvalidSize`: ELF header size value does not match real header size
     /// * `ElfParseError::InvalidVersion`: ELF version is not current
     /// * `ElfParseError::Not64Bit`: ELF file is 32bits
     /// * `ElfParseError::NotLittleEndian`: ELF file is big endian
     pub fn new(data: [u8; mem::size_of::<Self>()]) -> Result<Self, ElfParseError> {
-        let header = unsafe { *(&data[0] as *const u8 as *const Self) };
+        // `data` is a byte array, so it may not be aligned for the header
+        let header = unsafe { ptr::read_unaligned(data.as_ptr() as *const Self) };
         // Ensure magic is valid
         if header.magic() != Self::valid_magic() {
             return Err(ElfParseError::InvalidMagic);
@@ -141,7 +230,7 @@ impl ElfHeader64 {
         if header.class() != SIZE_64_BITS {
             return Err(ElfParseError::Not64Bit);
         }
-        // Ensure little endian, as this only runs on x86_64
+        // Ensure little endian, as this only runs on x86_64 and AArch64
         if header.data() != LITTLE_ENDIAN {
             return Err(ElfParseError::NotLittleEndian);
         }
@@ -153,12 +242,12 @@ impl ElfHeader64 {
         if header.os_abi() != SYSTEMV_ABI {
             return Err(ElfParseError::InvalidAbi);
         }
-        // Ensure file type is executable
-        if header.file_type != EXEC_FILE_TYPE {
+        // Ensure file type is executable or position independent executable
+        if header.file_type != EXEC_FILE_TYPE && header.file_type != DYN_FILE_TYPE {
             return Err(ElfParseError::InvalidFileType);
         }
-        // Ensure ISA is x86_64
-        if header.isa != X86_64_ISA {
+        // Ensure ISA matches the architecture this bootloader was built for
+        if header.isa != arch::ELF_ISA {
             return Err(ElfParseError::InvalidIsa);
         }
         // Ensure header size is valid
@@ -168,6 +257,20 @@ impl ElfHeader64 {
         Ok(header)
     }
 
+    /// Parses the start of `data` into an ELF64 header like [`ElfHeader64::new`].
+    ///
+    /// # Errors
+    ///
+    /// * `ElfParseError::InvalidSize`: `data` is smaller than an ELF64 header
+    ///
+    /// Otherwise, returns the same errors as [`ElfHeader64::new`].
+    pub fn from_slice(data: &[u8]) -> Result<Self, ElfParseError> {
+        let data = data
+            .get(..mem::size_of::<Self>())
+            .ok_or(ElfParseError::InvalidSize)?;
+        Self::new(data.try_into().unwrap())
+    }
+
     /// Returns the ABI used in this ELF64 header.
     ///
     /// After being parsed in [`ElfHeader64::new`], this header is guarenteed to use SystemV ABI.
@@ -187,8 +290,23 @@ impl Magic<4> for ElfHeader64 {
     }
 }
 
+/// Returns a copy of `count` structs from a table in `data` that starts at `offset`, with each
+/// struct at the start of an entry of `entry_size` bytes.
+///
+/// The table must fit in `data`, and `entry_size` must be at least the size of `T`.
+fn read_entries<T: Copy>(data: &[u8], offset: usize, entry_size: usize, count: usize) -> Vec<T> {
+    (0..count)
+        .map(|i| {
+            let entry = &data[offset + i * entry_size..offset + (i + 1) * entry_size];
+            unsafe { ptr::read_unaligned(entry.as_ptr() as *const T) }
+        })
+        .collect()
+}
+
 pub const ELF_SH_TYPE_SYMTAB: u32 = 2;
 pub const ELF_SH_TYPE_STRTAB: u32 = 3;
+pub const ELF_SH_TYPE_NOBITS: u32 = 8;
+pub const ELF_SH_TYPE_DYNSYM: u32 = 11;
 
 /// An ELF64 section header.
 #[repr(C)]
@@ -207,7 +325,34 @@ pub struct ElfSectionHeader64 {
 }
 
 impl ElfSectionHeader64 {
-    /// Returns the first section in `section_table` with a name matching `section_name` and a
+    /// Returns the contents of this section in `data`, which is the whole ELF file.
+    ///
+    /// Sections with the NOBITS type, such as `.bss`, do not take up space in the file, so their
+    /// contents are always empty.
+    ///
+    /// Returns `None` if the section goes past the end of `data`.
+    pub fn bytes<'a>(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+        if self.section_type == ELF_SH_TYPE_NOBITS {
+            return Some(&[]);
+        }
+        data.get(self.offset..self.offset.checked_add(self.size)?)
+    }
+
+    /// Returns true if this section's name in `str_table` is exactly `section_name`.
+    ///
+    /// The name must be followed by a null terminator or the end of `str_table`, so that a search
+    /// for `.sym` does not match `.symtab`.
+    fn has_name(&self, section_name: &[u8], str_table: &[u8]) -> bool {
+        let name_idx = self.name_idx as usize;
+        match str_table.get(name_idx..) {
+            Some(name) if name.starts_with(section_name) => {
+                matches!(name.get(section_name.len()), None | Some(0))
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the first section in `section_table` named exactly `section_name` and with a
     /// section type matching `section_type`.
     ///
     /// Returns `None` if no section with `section_name` exists.
@@ -219,18 +364,32 @@ impl ElfSectionHeader64 {
         section_type: u32,
         str_table: &[u8],
     ) -> Option<&'a Self> {
-        section_headers.iter().find(|sh| {
-            let name_idx = sh.name_idx as usize;
-            if name_idx + section_name.len() > str_table.len() {
-                return false;
-            }
-            &str_table[name_idx..name_idx + section_name.len()] == section_name
-                && sh.section_type == section_type
-        })
+        section_headers
+            .iter()
+            .find(|sh| sh.has_name(section_name, str_table) && sh.section_type == section_type)
     }
 }
 
 pub const ELF_PH_TYPE_LOAD: u32 = 1;
+pub const ELF_PH_TYPE_NOTE: u32 = 4;
+pub const ELF_PH_TYPE_TLS: u32 = 7;
+
+/// Note type of the GNU build-id, which is a unique identifier for a build of an executable.
+pub const ELF_NOTE_TYPE_GNU_BUILD_ID: u32 = 3;
+
+/// Owner name of GNU notes, including the null terminator.
+const ELF_NOTE_NAME_GNU: &[u8] = b"GNU\0";
+
+pub const ELF_PF_X: u32 = 1;
+pub const ELF_PF_W: u32 = 2;
+pub const ELF_PF_R: u32 = 4;
+/// Alignment of segments that do not have a valid alignment.
+const DEFAULT_SEGMENT_ALIGN: usize = 4096;
+
+/// Largest virtual address range in bytes that the LOAD segments of an executable can cover.
+///
+/// This keeps a corrupt segment size from allocating most of memory.
+pub const MAX_LOAD_SIZE: usize = 256 * 1024 * 1024;
 
 /// An ELF64 program header.
 #[repr(C)]
@@ -246,6 +405,188 @@ pub struct ElfProgramHeader64 {
     align: usize,
 }
 
+impl ElfProgramHeader64 {
+    /// Returns every segment in `program_headers` with the LOAD type.
+    pub fn load_segments(program_headers: &[Self]) -> impl Iterator<Item = &Self> {
+        program_headers
+            .iter()
+            .filter(|ph| ph.program_type == ELF_PH_TYPE_LOAD)
+    }
+
+    /// Returns the first segment in `program_headers` with the TLS type, which is the template for
+    /// each thread's thread-local storage.
+    ///
+    /// The template's first `file_size` bytes are initialized from the file, and the rest of its
+    /// `mem_size` bytes are zeroed.
+    ///
+    /// Returns `None` if the executable does not use thread-local storage.
+    pub fn tls_segment(program_headers: &[Self]) -> Option<&Self> {
+        program_headers
+            .iter()
+            .find(|ph| ph.program_type == ELF_PH_TYPE_TLS)
+    }
+
+    /// Returns the virtual address range that covers every LOAD segment in `program_headers`, from
+    /// the lowest segment start to the highest segment end in memory.
+    ///
+    /// # Errors
+    ///
+    /// * `ElfParseError::MissingLoadSegment`: There are no program headers, or none of them have
+    ///   the LOAD type
+    /// * `ElfParseError::InvalidSize`: A LOAD segment's end in memory overflows, or the range is
+    ///   larger than [`MAX_LOAD_SIZE`]
+    pub fn load_span(program_headers: &[Self]) -> Result<Range<usize>, ElfParseError> {
+        let mut span: Option<Range<usize>> = None;
+        for ph in Self::load_segments(program_headers) {
+            let end = ph
+                .vaddr
+                .checked_add(ph.mem_size)
+                .ok_or(ElfParseError::InvalidSize)?;
+            span = Some(match span {
+                Some(span) => span.start.min(ph.vaddr)..span.end.max(end),
+                None => ph.vaddr..end,
+            });
+        }
+        match span {
+            Some(span) if span.len() > MAX_LOAD_SIZE => Err(ElfParseError::InvalidSize),
+            Some(span) => Ok(span),
+            None => Err(ElfParseError::MissingLoadSegment),
+        }
+    }
+
+    /// Ensures that no two LOAD segments in `program_headers` overlap in virtual memory, so that
+    /// loading one segment cannot overwrite another.
+    ///
+    /// Segments with a size of 0 in memory never overlap.
+    ///
+    /// # Errors
+    ///
+    /// * `ElfParseError::OverlappingSegments`: Two LOAD segments share part of their memory range
+    /// * `ElfParseError::InvalidSize`: A LOAD segment's end in memory overflows
+    pub fn validate_no_overlap(program_headers: &[Self]) -> Result<(), ElfParseError> {
+        let mut segments: Vec<&Self> = Self::load_segments(program_headers)
+            .filter(|ph| ph.mem_size != 0)
+            .collect();
+        segments.sort_unstable_by_key(|ph| ph.vaddr);
+        let mut prev_end = 0;
+        for (i, ph) in segments.iter().enumerate() {
+            if i > 0 && ph.vaddr < prev_end {
+                return Err(ElfParseError::OverlappingSegments);
+            }
+            prev_end = ph
+                .vaddr
+                .checked_add(ph.mem_size)
+                .ok_or(ElfParseError::InvalidSize)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the alignment of this segment in memory and in the file.
+    ///
+    /// Returns 4096 if the alignment is not a power of two, which includes an alignment of 0.
+    pub fn align(&self) -> usize {
+        if self.align.is_power_of_two() {
+            self.align
+        } else {
+            DEFAULT_SEGMENT_ALIGN
+        }
+    }
+
+    /// Returns the raw permission flags of this segment.
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Returns true if this segment is readable.
+    pub fn is_readable(&self) -> bool {
+        self.flags & ELF_PF_R != 0
+    }
+
+    /// Returns true if this segment is writable.
+    pub fn is_writable(&self) -> bool {
+        self.flags & ELF_PF_W != 0
+    }
+
+    /// Returns true if this segment is executable.
+    pub fn is_executable(&self) -> bool {
+        self.flags & ELF_PF_X != 0
+    }
+
+    /// Returns the virtual address that this segment is loaded at.
+    pub fn virt_addr(&self) -> usize {
+        self.vaddr
+    }
+
+    /// Ensures that this segment can be loaded from `data`.
+    ///
+    /// # Errors
+    ///
+    /// * `ElfParseError::InvalidOffset`: Segment contents go past `data` end
+    /// * `ElfParseError::InvalidSize`: Segment size in the file is larger than its size in memory
+    pub fn validate(&self, data: &[u8]) -> Result<(), ElfParseError> {
+        match self.offset.checked_add(self.file_size) {
+            Some(end) if end <= data.len() => {}
+            _ => return Err(ElfParseError::InvalidOffset),
+        }
+        if self.file_size > self.mem_size {
+            return Err(ElfParseError::InvalidSize);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the GNU build-id in the NOTE segments of `program_headers`, where `data` is the whole
+/// ELF file.
+///
+/// Each note has a 12-byte header with the name size, descriptor size, and note type, followed by
+/// the name and descriptor, which are each padded to 4 bytes. The build-id is the descriptor of
+/// the note with the NT_GNU_BUILD_ID type and the name `GNU`.
+///
+/// Returns `None` if there is no build-id, or if the notes go past the end of their segment.
+pub fn read_build_id<'a>(
+    data: &'a [u8],
+    program_headers: &[ElfProgramHeader64],
+) -> Option<&'a [u8]> {
+    let read_u32 = |bytes: &[u8], idx: usize| -> Option<usize> {
+        let field = bytes.get(idx..idx + 4)?;
+        Some(u32::from_le_bytes([field[0], field[1], field[2], field[3]]) as usize)
+    };
+    let align4 = |size: usize| size.checked_add(3).map(|size| size & !3);
+
+    for ph in program_headers
+        .iter()
+        .filter(|ph| ph.program_type == ELF_PH_TYPE_NOTE)
+    {
+        let mut notes = data.get(ph.offset..ph.offset.checked_add(ph.file_size)?)?;
+        while notes.len() >= 12 {
+            let name_size = read_u32(notes, 0)?;
+            let desc_size = read_u32(notes, 4)?;
+            let note_type = read_u32(notes, 8)? as u32;
+            let desc_start = align4(12 + name_size)?;
+            let desc_end = desc_start.checked_add(desc_size)?;
+            let name = notes.get(12..12 + name_size)?;
+            let desc = notes.get(desc_start..desc_end)?;
+            if note_type == ELF_NOTE_TYPE_GNU_BUILD_ID && name == ELF_NOTE_NAME_GNU {
+                return Some(desc);
+            }
+            notes = notes.get(align4(desc_end)?..).unwrap_or(&[]);
+        }
+    }
+    None
+}
+
+/// Symbol types, which are the `STT_*` values of `st_info` in the ELF specification.
+pub const ELF_SYM_TYPE_NOTYPE: u8 = 0;
+pub const ELF_SYM_TYPE_OBJECT: u8 = 1;
+pub const ELF_SYM_TYPE_FUNC: u8 = 2;
+pub const ELF_SYM_TYPE_SECTION: u8 = 3;
+pub const ELF_SYM_TYPE_FILE: u8 = 4;
+
+/// Symbol bindings, which are the `STB_*` values of `st_info` in the ELF specification.
+pub const ELF_SYM_BIND_LOCAL: u8 = 0;
+pub const ELF_SYM_BIND_GLOBAL: u8 = 1;
+pub const ELF_SYM_BIND_WEAK: u8 = 2;
+
 /// And ELF64 symbol
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -255,11 +596,15 @@ pub struct ElfSymbol64 {
     other: u8,
     sh_index: u16,
     pub value: usize,
-    size: u64,
+    pub size: u64,
 }
 
 impl ElfSymbol64 {
-    /// Returns the first symbol in `symbol_table` with a name matching `symbol_name`.
+    /// Returns the symbol in `symbol_table` named exactly `symbol_name`.
+    ///
+    /// Global and weak symbols are preferred over local symbols, so that a local symbol with the
+    /// same name in another file does not shadow the global symbol. Otherwise, the first matching
+    /// symbol is returned.
     ///
     /// Returns `None` if no symbol with `symbol_name` exists.
     ///
@@ -269,12 +614,640 @@ impl ElfSymbol64 {
         symbol_name: &[u8],
         symbol_str_table: &[u8],
     ) -> Option<&'a Self> {
-        symbol_table.iter().find(|symbol| {
+        let mut matches = symbol_table
+            .iter()
+            .filter(|symbol| symbol.has_name(symbol_name, symbol_str_table));
+        let first = matches.next()?;
+        if first.symbol_binding() != ELF_SYM_BIND_LOCAL {
+            return Some(first);
+        }
+        matches
+            .find(|symbol| symbol.symbol_binding() != ELF_SYM_BIND_LOCAL)
+            .or(Some(first))
+    }
+
+    /// Returns true if this symbol's name in `symbol_str_table` is exactly `symbol_name`.
+    ///
+    /// The name must be followed by a null terminator or the end of `symbol_str_table`, so that a
+    /// search for `fb` does not match `fbx`.
+    fn has_name(&self, symbol_name: &[u8], symbol_str_table: &[u8]) -> bool {
+        match symbol_str_table.get(self.name_idx as usize..) {
+            Some(name) if name.starts_with(symbol_name) => {
+                matches!(name.get(symbol_name.len()), None | Some(0))
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns an iterator over every symbol in `symbol_table` with a name that starts with
+    /// `prefix`, such as `_bootboot_`.
+    ///
+    /// The names are found in the `symbol_str_table`.
+    pub fn iter_by_prefix<'a: 'b, 'b>(
+        symbol_table: &'a [Self],
+        prefix: &'b [u8],
+        symbol_str_table: &'b [u8],
+    ) -> impl Iterator<Item = &'a Self> + 'b {
+        symbol_table.iter().filter(move |symbol| {
             let name_idx = symbol.name_idx as usize;
-            if name_idx + symbol_name.len() > symbol_str_table.len() {
-                return false;
+            match symbol_str_table.get(name_idx..) {
+                Some(name) => name.starts_with(prefix),
+                None => false,
             }
-            &symbol_str_table[name_idx..name_idx + symbol_name.len()] == symbol_name
         })
     }
+
+    /// Returns the type of this symbol (`st_type`), such as [`ELF_SYM_TYPE_FUNC`].
+    pub fn symbol_type(&self) -> u8 {
+        self.info & 0xf
+    }
+
+    /// Returns the binding of this symbol (`st_bind`), such as [`ELF_SYM_BIND_GLOBAL`].
+    pub fn symbol_binding(&self) -> u8 {
+        self.info >> 4
+    }
+
+    /// Returns the name of this symbol from `symbol_str_table`.
+    ///
+    /// Returns `None` if the name is out of bounds, is not null terminated, or is not UTF-8.
+    pub fn name<'a>(&self, symbol_str_table: &'a [u8]) -> Option<&'a str> {
+        let name = symbol_str_table.get(self.name_idx as usize..)?;
+        let len = name.iter().position(|&c| c == 0)?;
+        str::from_utf8(&name[..len]).ok()
+    }
+
+    /// Returns an iterator over every symbol in `symbol_table` of type `symbol_type`, along with
+    /// its name from `symbol_str_table`.
+    ///
+    /// Symbols with an invalid name are skipped.
+    pub fn symbols_of_type<'a>(
+        symbol_table: &'a [Self],
+        symbol_str_table: &'a [u8],
+        symbol_type: u8,
+    ) -> impl Iterator<Item = (&'a str, &'a Self)> {
+        symbol_table
+            .iter()
+            .filter(move |symbol| symbol.symbol_type() == symbol_type)
+            .filter_map(move |symbol| Some((symbol.name(symbol_str_table)?, symbol)))
+    }
+
+    /// Returns the name of the function symbol in `symbol_table` that is closest to and not after
+    /// `addr`, along with the offset of `addr` from the start of that function.
+    ///
+    /// Returns `None` if there is no such function, such as when the kernel was stripped and
+    /// `symbol_table` is empty.
+    pub fn resolve_address<'a>(
+        symbol_table: &'a [Self],
+        symbol_str_table: &'a [u8],
+        addr: usize,
+    ) -> Option<(&'a str, usize)> {
+        Self::symbols_of_type(symbol_table, symbol_str_table, ELF_SYM_TYPE_FUNC)
+            .filter(|(_, symbol)| symbol.value != 0 && symbol.value <= addr)
+            .max_by_key(|(_, symbol)| symbol.value)
+            .map(|(name, symbol)| (name, addr - symbol.value))
+    }
+}
+
+/// An ELF64 executable for the target architecture, for testing code that loads kernels.
+///
+/// The file has the ELF header, then the program headers, then the contents of each segment and
+/// section, each aligned to 8 bytes, then the section headers. If there are any sections, a null
+/// section is added before them and `.shstrtab` is added after them.
+#[cfg(test)]
+#[derive(Default)]
+pub struct TestElf<'a> {
+    pub pie: bool,
+    pub entry: usize,
+    /// Program type, virtual address, memory size, and contents of each segment
+    pub segments: Vec<(u32, usize, usize, &'a [u8])>,
+    /// Name, section type, and contents of each section
+    pub sections: Vec<(&'a str, u32, &'a [u8])>,
+    /// Size of each program header entry, or the size of [`ElfProgramHeader64`] if 0
+    pub ph_entry_size: usize,
+}
+
+#[cfg(test)]
+impl TestElf<'_> {
+    /// Returns the whole ELF file.
+    pub fn build(&self) -> Vec<u8> {
+        let align8 = |file: &mut Vec<u8>| file.resize((file.len() + 7) & !7, 0);
+        let write = |file: &mut Vec<u8>, offset: usize, header: &dyn Fn(*mut u8)| {
+            header(file[offset..].as_mut_ptr());
+        };
+
+        let ph_entry_size = match self.ph_entry_size {
+            0 => mem::size_of::<ElfProgramHeader64>(),
+            size => size,
+        };
+        let ph_offset = mem::size_of::<ElfHeader64>();
+        let mut file = alloc::vec![0; ph_offset + ph_entry_size * self.segments.len()];
+
+        // Segment contents and program headers
+        for (i, &(program_type, vaddr, mem_size, data)) in self.segments.iter().enumerate() {
+            align8(&mut file);
+            let ph = ElfProgramHeader64 {
+                program_type,
+                flags: ELF_PF_R | ELF_PF_X,
+                offset: file.len(),
+                vaddr,
+                paddr: vaddr,
+                file_size: data.len(),
+                mem_size,
+                align: 4096,
+            };
+            file.extend_from_slice(data);
+            write(&mut file, ph_offset + i * ph_entry_size, &|ptr| unsafe {
+                ptr::write_unaligned(ptr as *mut ElfProgramHeader64, ph)
+            });
+        }
+
+        // Section contents, with `.shstrtab` last
+        let mut section_headers = Vec::new();
+        if !self.sections.is_empty() {
+            let mut shstrtab = alloc::vec![0];
+            let mut names = Vec::new();
+            for (name, _, _) in self.sections.iter().chain([&(".shstrtab", 0, &[][..])]) {
+                names.push(shstrtab.len() as u32);
+                shstrtab.extend_from_slice(name.as_bytes());
+                shstrtab.push(0);
+            }
+            let shstrtab_section = (".shstrtab", ELF_SH_TYPE_STRTAB, &shstrtab[..]);
+            section_headers.push(ElfSectionHeader64 {
+                name_idx: 0,
+                section_type: 0,
+                flags: 0,
+                addr: 0,
+                offset: 0,
+                size: 0,
+                link: 0,
+                info: 0,
+                addr_align: 0,
+                entry_size: 0,
+            });
+            for (i, &(_, section_type, data)) in
+                self.sections.iter().chain([&shstrtab_section]).enumerate()
+            {
+                align8(&mut file);
+                section_headers.push(ElfSectionHeader64 {
+                    name_idx: names[i],
+                    section_type,
+                    flags: 0,
+                    addr: 0,
+                    offset: file.len(),
+                    size: data.len(),
+                    link: 0,
+                    info: 0,
+                    addr_align: 8,
+                    entry_size: match section_type {
+                        ELF_SH_TYPE_SYMTAB | ELF_SH_TYPE_DYNSYM => mem::size_of::<ElfSymbol64>(),
+                        _ => 0,
+                    },
+                });
+                file.extend_from_slice(data);
+            }
+        }
+
+        // Section headers
+        align8(&mut file);
+        let sh_offset = if section_headers.is_empty() {
+            0
+        } else {
+            file.len()
+        };
+        for sh in &section_headers {
+            let bytes = unsafe {
+                core::slice::from_raw_parts(
+                    sh as *const ElfSectionHeader64 as *const u8,
+                    mem::size_of::<ElfSectionHeader64>(),
+                )
+            };
+            file.extend_from_slice(bytes);
+        }
+
+        let mut ident = [0; ELF_HEADER_NIDENT];
+        ident[..4].copy_from_slice(ElfHeader64::valid_magic());
+        ident[4] = SIZE_64_BITS;
+        ident[5] = LITTLE_ENDIAN;
+        ident[6] = ELF_IDENT_VERSION;
+        ident[7] = SYSTEMV_ABI;
+        let header = ElfHeader64 {
+            ident,
+            file_type: if self.pie {
+                DYN_FILE_TYPE
+            } else {
+                EXEC_FILE_TYPE
+            },
+            isa: arch::ELF_ISA,
+            version: 1,
+            entry: self.entry,
+            ph_offset,
+            sh_offset,
+            _flags: 0,
+            header_size: mem::size_of::<ElfHeader64>() as u16,
+            ph_entry_size: ph_entry_size as u16,
+            ph_num: self.segments.len() as u16,
+            sh_entry_size: mem::size_of::<ElfSectionHeader64>() as u16,
+            sh_num: section_headers.len() as u16,
+            sh_string_index: section_headers.len().saturating_sub(1) as u16,
+        };
+        write(&mut file, 0, &|ptr| unsafe {
+            ptr::write_unaligned(ptr as *mut ElfHeader64, header)
+        });
+        file
+    }
+}
+
+/// Returns a symbol table with a null symbol followed by `symbols`, and the string table with
+/// their names, for [`TestElf`].
+///
+/// Each symbol is the name, value, and binding.
+#[cfg(test)]
+pub fn test_symbols(symbols: &[(&str, usize, u8)]) -> (Vec<u8>, Vec<u8>) {
+    let mut symtab = alloc::vec![0; mem::size_of::<ElfSymbol64>()];
+    let mut strtab = alloc::vec![0];
+    for &(name, value, binding) in symbols {
+        let symbol = ElfSymbol64 {
+            name_idx: strtab.len() as u32,
+            info: binding << 4 | ELF_SYM_TYPE_OBJECT,
+            other: 0,
+            sh_index: 1,
+            value,
+            size: 8,
+        };
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+        symtab.extend_from_slice(unsafe {
+            core::slice::from_raw_parts(
+                &symbol as *const ElfSymbol64 as *const u8,
+                mem::size_of::<ElfSymbol64>(),
+            )
+        });
+    }
+    (symtab, strtab)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn truncated_header_tables() {
+        let code = [0x90; 0x1000];
+        let kernel = TestElf {
+            entry: 0xffff_ffff_ffe0_0000,
+            segments: vec![(ELF_PH_TYPE_LOAD, 0xffff_ffff_ffe0_0000, 0x1000, &code)],
+            sections: vec![(".text", 1, &code)],
+            ..Default::default()
+        }
+        .build();
+        let header = ElfHeader64::from_slice(&kernel).unwrap();
+        assert!(header.get_headers(&kernel).is_ok());
+
+        // The file is still larger than every header combined, but the section header table at the
+        // end of the file is cut off
+        let truncated = &kernel[..kernel.len() - 8];
+        let headers_size = mem::size_of::<ElfHeader64>()
+            + mem::size_of::<ElfProgramHeader64>()
+            + 3 * mem::size_of::<ElfSectionHeader64>();
+        assert!(truncated.len() > headers_size);
+        assert!(matches!(
+            header.get_headers(truncated),
+            Err(ElfParseError::InvalidOffset)
+        ));
+
+        // The program header table starts inside the file, but goes past its end
+        let mut header = header;
+        header.ph_offset = kernel.len() - 8;
+        assert!(matches!(
+            header.get_headers(&kernel),
+            Err(ElfParseError::InvalidOffset)
+        ));
+    }
+
+    #[test]
+    fn entry_point_of_position_independent_kernel() {
+        let code = [0x90; 0x1000];
+        let kernel = |pie, entry| {
+            TestElf {
+                pie,
+                entry,
+                segments: vec![(ELF_PH_TYPE_LOAD, entry, 0x1000, &code)],
+                ..Default::default()
+            }
+            .build()
+        };
+
+        // Only position independent kernels may be linked in the lower half
+        let pie = kernel(true, 0x1000);
+        let header = ElfHeader64::from_slice(&pie).unwrap();
+        assert!(header.is_pie());
+        assert_eq!(header.entry_point().ok(), Some(0x1000));
+        let exec = kernel(false, 0x1000);
+        assert!(matches!(
+            ElfHeader64::from_slice(&exec).unwrap().entry_point(),
+            Err(ElfParseError::InvalidEntry)
+        ));
+
+        let pie = kernel(true, 0xffff_ffff_ffe0_0000);
+        let header = ElfHeader64::from_slice(&pie).unwrap();
+        assert_eq!(header.entry_point().ok(), Some(0xffff_ffff_ffe0_0000));
+        let pie = kernel(true, 0);
+        assert!(matches!(
+            ElfHeader64::from_slice(&pie).unwrap().entry_point(),
+            Err(ElfParseError::InvalidEntry)
+        ));
+    }
+
+    /// Returns the parsed symbol table and the string table of `symbols`.
+    fn symbol_tables(symbols: &[(&str, usize, u8)]) -> (Vec<ElfSymbol64>, Vec<u8>) {
+        let (symtab, strtab) = test_symbols(symbols);
+        let symbols = symtab
+            .chunks_exact(mem::size_of::<ElfSymbol64>())
+            .map(|bytes| unsafe { ptr::read_unaligned(bytes.as_ptr() as *const ElfSymbol64) })
+            .collect();
+        (symbols, strtab)
+    }
+
+    #[test]
+    fn find_symbol_prefers_global_symbol() {
+        let (symbols, strtab) = symbol_tables(&[
+            ("fbx", 0x1000, ELF_SYM_BIND_GLOBAL),
+            ("fb", 0x2000, ELF_SYM_BIND_LOCAL),
+            ("fb", 0x3000, ELF_SYM_BIND_GLOBAL),
+        ]);
+        let fb = ElfSymbol64::find_symbol(&symbols, b"fb", &strtab).unwrap();
+        assert_eq!(fb.value, 0x3000);
+        assert_eq!(fb.symbol_binding(), ELF_SYM_BIND_GLOBAL);
+
+        // Without a global symbol, the first local symbol is used
+        let (symbols, strtab) = symbol_tables(&[
+            ("fbx", 0x1000, ELF_SYM_BIND_GLOBAL),
+            ("fb", 0x2000, ELF_SYM_BIND_LOCAL),
+            ("fb", 0x3000, ELF_SYM_BIND_LOCAL),
+        ]);
+        let fb = ElfSymbol64::find_symbol(&symbols, b"fb", &strtab).unwrap();
+        assert_eq!(fb.value, 0x2000);
+    }
+
+    #[test]
+    fn find_symbol_matches_whole_name() {
+        let (symbols, strtab) = symbol_tables(&[
+            ("_bootboot_fbx", 0x1000, ELF_SYM_BIND_GLOBAL),
+            ("_bootboot_fb", 0x2000, ELF_SYM_BIND_GLOBAL),
+        ]);
+        let fb = ElfSymbol64::find_symbol(&symbols, b"_bootboot_fb", &strtab).unwrap();
+        assert_eq!(fb.value, 0x2000);
+        assert!(ElfSymbol64::find_symbol(&symbols, b"_bootboot_f", &strtab).is_none());
+
+        // A name at the end of the string table without a null terminator still matches
+        let unterminated = &strtab[..strtab.len() - 1];
+        let fb = ElfSymbol64::find_symbol(&symbols, b"_bootboot_fb", unterminated).unwrap();
+        assert_eq!(fb.value, 0x2000);
+
+        let values: Vec<usize> = ElfSymbol64::iter_by_prefix(&symbols, b"_bootboot_", &strtab)
+            .map(|symbol| symbol.value)
+            .collect();
+        assert_eq!(values, [0x1000, 0x2000]);
+    }
+
+    #[test]
+    fn overlapping_load_segments() {
+        let code = [0x90; 0x100];
+        let kernel = |second_vaddr| {
+            TestElf {
+                entry: 0xffff_ffff_ffe0_0000,
+                segments: vec![
+                    (ELF_PH_TYPE_LOAD, 0xffff_ffff_ffe0_0000, 0x2000, &code),
+                    (ELF_PH_TYPE_LOAD, second_vaddr, 0x1000, &code),
+                ],
+                ..Default::default()
+            }
+            .build()
+        };
+
+        let overlapping = kernel(0xffff_ffff_ffe0_1000);
+        let (_, program_headers) = ElfHeader64::from_slice(&overlapping)
+            .unwrap()
+            .get_headers(&overlapping)
+            .unwrap();
+        assert!(matches!(
+            ElfProgramHeader64::validate_no_overlap(&program_headers),
+            Err(ElfParseError::OverlappingSegments)
+        ));
+
+        // Segments that only touch do not overlap
+        let adjacent = kernel(0xffff_ffff_ffe0_2000);
+        let (_, program_headers) = ElfHeader64::from_slice(&adjacent)
+            .unwrap()
+            .get_headers(&adjacent)
+            .unwrap();
+        assert!(ElfProgramHeader64::validate_no_overlap(&program_headers).is_ok());
+    }
+
+    #[test]
+    fn huge_load_segment() {
+        let code = [0x90; 0x100];
+        let span = |mem_size| {
+            let kernel = TestElf {
+                entry: 0xffff_ffff_8000_0000,
+                segments: vec![(ELF_PH_TYPE_LOAD, 0xffff_ffff_8000_0000, mem_size, &code)],
+                ..Default::default()
+            }
+            .build();
+            let (_, program_headers) = ElfHeader64::from_slice(&kernel)
+                .unwrap()
+                .get_headers(&kernel)
+                .unwrap();
+            ElfProgramHeader64::load_span(&program_headers)
+        };
+
+        assert_eq!(
+            span(MAX_LOAD_SIZE).ok(),
+            Some(0xffff_ffff_8000_0000..0xffff_ffff_8000_0000 + MAX_LOAD_SIZE)
+        );
+        assert!(matches!(
+            span(MAX_LOAD_SIZE + 1),
+            Err(ElfParseError::InvalidSize)
+        ));
+        // The end of the segment overflows
+        assert!(matches!(span(usize::MAX), Err(ElfParseError::InvalidSize)));
+    }
+
+    #[test]
+    fn find_section_matches_whole_name() {
+        let symtab = [1; 24];
+        let sym = [2; 8];
+        let kernel = TestElf {
+            sections: vec![
+                (".symtab", ELF_SH_TYPE_SYMTAB, &symtab),
+                (".sym", ELF_SH_TYPE_SYMTAB, &sym),
+            ],
+            ..Default::default()
+        }
+        .build();
+        let header = ElfHeader64::from_slice(&kernel).unwrap();
+        let (section_headers, _) = header.get_headers(&kernel).unwrap();
+        let str_table = section_headers[header.sh_string_index as usize]
+            .bytes(&kernel)
+            .unwrap();
+
+        let find = |name: &[u8]| {
+            ElfSectionHeader64::find_section(&section_headers, name, ELF_SH_TYPE_SYMTAB, str_table)
+                .and_then(|sh| sh.bytes(&kernel))
+        };
+        assert_eq!(find(b".sym"), Some(&sym[..]));
+        assert_eq!(find(b".symtab"), Some(&symtab[..]));
+        assert_eq!(find(b".sy"), None);
+    }
+
+    #[test]
+    fn larger_program_header_entries() {
+        let code = [0x90; 0x100];
+        let data = [0; 0x80];
+        let mut kernel = TestElf {
+            entry: 0xffff_ffff_ffe0_0000,
+            segments: vec![
+                (ELF_PH_TYPE_LOAD, 0xffff_ffff_ffe0_0000, 0x1000, &code),
+                (ELF_PH_TYPE_LOAD, 0xffff_ffff_ffe0_1000, 0x2000, &data),
+            ],
+            ph_entry_size: 64,
+            ..Default::default()
+        }
+        .build();
+        // Fill the padding after each program header, which must not be read
+        let header = ElfHeader64::from_slice(&kernel).unwrap();
+        for i in 0..2 {
+            let padding = header.ph_offset + i * 64 + mem::size_of::<ElfProgramHeader64>();
+            kernel[padding..padding + 8].fill(0xff);
+        }
+
+        let (_, program_headers) = header.get_headers(&kernel).unwrap();
+        assert_eq!(program_headers.len(), 2);
+        assert_eq!(program_headers[0].virt_addr(), 0xffff_ffff_ffe0_0000);
+        assert_eq!(program_headers[0].file_size, code.len());
+        assert_eq!(program_headers[1].program_type, ELF_PH_TYPE_LOAD);
+        assert_eq!(program_headers[1].virt_addr(), 0xffff_ffff_ffe0_1000);
+        assert_eq!(program_headers[1].mem_size, 0x2000);
+
+        // Entries smaller than a program header are still rejected
+        let mut header = header;
+        header.ph_entry_size = 48;
+        assert!(matches!(
+            header.get_headers(&kernel),
+            Err(ElfParseError::InvalidSize)
+        ));
+    }
+
+    #[test]
+    fn resolve_address() {
+        // Stripped kernels have no symbols
+        assert_eq!(ElfSymbol64::resolve_address(&[], &[], 0x1000), None);
+
+        let (mut symbols, strtab) = symbol_tables(&[
+            ("data", 0x1000, ELF_SYM_BIND_GLOBAL),
+            ("_start", 0x2000, ELF_SYM_BIND_GLOBAL),
+            ("main", 0x2100, ELF_SYM_BIND_GLOBAL),
+        ]);
+        // Only function symbols are used
+        assert_eq!(
+            ElfSymbol64::resolve_address(&symbols, &strtab, 0x2010),
+            None
+        );
+        for symbol in &mut symbols[2..] {
+            symbol.info = ELF_SYM_BIND_GLOBAL << 4 | ELF_SYM_TYPE_FUNC;
+        }
+        assert_eq!(
+            ElfSymbol64::resolve_address(&symbols, &strtab, 0x2010),
+            Some(("_start", 0x10))
+        );
+        assert_eq!(
+            ElfSymbol64::resolve_address(&symbols, &strtab, 0x2100),
+            Some(("main", 0))
+        );
+        assert_eq!(
+            ElfSymbol64::resolve_address(&symbols, &strtab, 0x1fff),
+            None
+        );
+    }
+
+    #[test]
+    fn load_span_without_load_segments() {
+        assert!(matches!(
+            ElfProgramHeader64::load_span(&[]),
+            Err(ElfParseError::MissingLoadSegment)
+        ));
+
+        // Other segment types are not loaded
+        let note = [0; 16];
+        let kernel = TestElf {
+            segments: vec![(ELF_PH_TYPE_NOTE, 0xffff_ffff_ffe0_0000, 0x10, &note)],
+            ..Default::default()
+        }
+        .build();
+        let (_, program_headers) = ElfHeader64::from_slice(&kernel)
+            .unwrap()
+            .get_headers(&kernel)
+            .unwrap();
+        assert!(matches!(
+            ElfProgramHeader64::load_span(&program_headers),
+            Err(ElfParseError::MissingLoadSegment)
+        ));
+    }
+
+    #[test]
+    fn segment_permissions() {
+        let ph = |flags| ElfProgramHeader64 {
+            program_type: ELF_PH_TYPE_LOAD,
+            flags,
+            offset: 0,
+            vaddr: 0,
+            paddr: 0,
+            file_size: 0,
+            mem_size: 0,
+            align: 0,
+        };
+
+        let text = ph(0x5);
+        assert_eq!(text.flags(), ELF_PF_R | ELF_PF_X);
+        assert!(text.is_readable());
+        assert!(!text.is_writable());
+        assert!(text.is_executable());
+
+        let data = ph(ELF_PF_R | ELF_PF_W);
+        assert!(data.is_readable() && data.is_writable() && !data.is_executable());
+        let none = ph(0);
+        assert!(!none.is_readable() && !none.is_writable() && !none.is_executable());
+    }
+
+    #[test]
+    fn get_section_by_name() {
+        let text = [0x90; 32];
+        let custom = [0xaa; 12];
+        let mut kernel = TestElf {
+            sections: vec![(".text", 1, &text), (".custom", 1, &custom)],
+            ..Default::default()
+        }
+        .build();
+        let header = ElfHeader64::from_slice(&kernel).unwrap();
+        assert_eq!(
+            header.get_section_by_name(&kernel, b".text"),
+            Some(&text[..])
+        );
+        assert_eq!(
+            header.get_section_by_name(&kernel, b".custom"),
+            Some(&custom[..])
+        );
+        assert_eq!(header.get_section_by_name(&kernel, b".data"), None);
+
+        // Make the body of `.custom` go past the end of the file
+        let size_field = header.sh_offset + 2 * mem::size_of::<ElfSectionHeader64>() + 32;
+        let len = kernel.len() as u64;
+        kernel[size_field..size_field + 8].copy_from_slice(&len.to_le_bytes());
+        assert_eq!(header.get_section_by_name(&kernel, b".custom"), None);
+        assert_eq!(
+            header.get_section_by_name(&kernel, b".text"),
+            Some(&text[..])
+        );
+    }
 }
@@ -1,31 +1,95 @@
-use alloc::string::{String, ToString};
-use log::debug;
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use log::{debug, warn};
 use uefi::{
-    prelude::Status,
+    prelude::{BootServices, Status},
     proto::media::file::{Directory, FileAttribute, FileMode},
     Error as UefiError, Result as UefiResult,
 };
 
 use crate::utils::ParseError;
-use crate::{open_file, read_to_string, Initrd};
+use crate::{open_file, read_to_vec, BootbootProtocolLevel, Framebuffer, FramebufferType, Initrd};
 
+// The environment is passed to the kernel in a 4KiB page
+pub const ENVIRONMENT_PAGE_SIZE: usize = 4096;
 // Since length does not include null terminator, max length is 4KiB - 1 or 4095 bytes
-const ENVIRONMENT_MAX_SIZE: usize = 4095;
+const ENVIRONMENT_MAX_SIZE: usize = ENVIRONMENT_PAGE_SIZE - 1;
 const SCREEN_MIN_WIDTH: usize = 640;
 const SCREEN_MIN_HEIGHT: usize = 480;
+const SCREEN_DEFAULT_RESOLUTION: (usize, usize) = (1024, 768);
+// Defaults can be overridden at compile time
+pub const DEFAULT_KERNEL: &str = match option_env!("TOYBB_DEFAULT_KERNEL") {
+    Some(kernel) => kernel,
+    None => "sys/core",
+};
+const DEFAULT_SCREEN: &str = match option_env!("TOYBB_DEFAULT_SCREEN") {
+    Some(screen) => screen,
+    None => "1024x768",
+};
+// If set, keys in the boot disk config override keys in the initrd config instead of replacing it
+const MERGE_CONFIG: bool = option_env!("TOYBB_MERGE_CONFIG").is_some();
+// Initial stack size for each core is at least a page
+const INITSTACK_DEFAULT_SIZE: usize = 4096;
+const INITSTACK_MIN_SIZE: usize = 4096;
+// Kernel load address must be aligned to a page
+const LOAD_ADDR_ALIGN: u64 = 4096;
+// Extra MMIO ranges are mapped with 4KiB pages
+const MMIO_MAP_ALIGN: u64 = 4096;
 
 /// Bootboot environment.
 ///
 /// Contains:
 ///
 /// * Preferred screen resolution
+/// * Preferred framebuffer pixel format, if set
 /// * Kernel file name in initrd
 /// * Flag showing whether SMP is disabled
+/// * Flags showing whether ACPI and SMBIOS parsing are disabled
+/// * Initial stack size for each core in bytes
+/// * Initrd module file names in the `BOOTBOOT` directory
+/// * Subdirectory of the `BOOTBOOT` directory that is searched for an initrd first, if set
+/// * GPT partition GUID of the partition with the `BOOTBOOT` directory to boot from, if set
+/// * Expected CRC-32 checksum of initrd, if set
+/// * Flag showing whether to stop before handing off to the kernel
+/// * Flag showing whether to log the firmware's page tables in debug builds
+/// * Physical address to load the kernel at, if set
+/// * BOOTBOOT protocol level, if it is forced instead of detected from the kernel's symbols
+/// * Extra MMIO ranges to map for the kernel
 pub struct Environment {
     pub env_raw: String,
     pub screen: (usize, usize),
+    pub fb_format: Option<FramebufferType>,
     pub kernel: String,
     pub no_smp: bool,
+    pub no_acpi: bool,
+    pub no_smbios: bool,
+    pub initstack: usize,
+    pub initrd: Vec<String>,
+    pub initrd_dir: Option<String>,
+    /// GPT partition GUID in the byte order it is stored on disk
+    pub boot_drive: Option<[u8; 16]>,
+    pub initrd_crc32: Option<u32>,
+    pub dry_run: bool,
+    pub dump_page_tables: bool,
+    pub load_addr: Option<u64>,
+    pub protocol: Option<BootbootProtocolLevel>,
+    pub mmio_maps: Vec<MmioMap>,
+}
+
+/// An MMIO range to map uncached for the kernel, set with `map=<addr>:<size>` or
+/// `map=<addr>:<size>@<virt>` in the environment.
+///
+/// Every address and the size are page aligned. Paging is not implemented yet, so these ranges
+/// are only checked against the memory map and logged.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MmioMap {
+    pub phys: u64,
+    pub size: u64,
+    /// Virtual address of the range, which is the same as `phys` if it is identity mapped
+    pub virt: u64,
 }
 
 impl Environment {
@@ -34,13 +98,28 @@ impl Environment {
     /// The following steps are run until a valid environment is returned:
     ///
     /// 1. Try to read `BOOTBOOT/CONFIG` from boot partition and parse environment.
-    /// 2. Try to read `sys/config` from `initrd` and parse environment.
+    /// 2. Try to read `sys/config` from `initrd` (if it was read) and parse environment.
     /// 3. If neither file contains a valid environment, return a default environment.
-    pub fn get_env(bootdir: &mut Directory, initrd: &Initrd) -> Self {
+    ///
+    /// If `TOYBB_MERGE_CONFIG` was set at compile time and both files exist, the initrd config is
+    /// used as a base, and each key set in the boot disk config overrides that key only.
+    pub fn get_env(bootdir: &mut Directory, initrd: Option<&Initrd>) -> Self {
         // Try to parse environment, first from boot disk, then from initrd
         if let Ok(env_raw) = get_env_raw(bootdir, initrd) {
-            if let Ok(env) = Self::from_string(env_raw) {
-                return env;
+            match Self::from_string(env_raw) {
+                Ok(env) => {
+                    debug!(
+                        "Environment uses {} of {} bytes",
+                        env.raw_len(),
+                        ENVIRONMENT_MAX_SIZE
+                    );
+                    return env;
+                }
+                Err(ParseError::TooLarge) => warn!(
+                    "Environment is larger than {} bytes and was ignored",
+                    ENVIRONMENT_MAX_SIZE
+                ),
+                Err(_) => warn!("Environment is invalid and was ignored"),
             }
         }
 
@@ -51,20 +130,60 @@ impl Environment {
 
     /// Parses a raw config file to obtain a BOOTBOOT environment.
     ///
+    /// This is the first of two phases, and does not depend on any hardware. Directives that depend
+    /// on the available graphics modes are only stored here, and are applied by
+    /// [`Environment::apply`].
+    ///
+    /// Non-ASCII characters and control characters other than tab and newline are removed, as the
+    /// environment is passed to the kernel as ASCII text. A warning is logged if any non-ASCII
+    /// characters were removed; the rest of the config file is still used.
+    ///
+    /// A warning is logged if the config file is empty or does not contain any recognized keys.
+    ///
     /// # Errors
     ///
-    /// Returns an error if the raw config file is larger than 4KiB.
+    /// * `ParseError::TooLarge`: The raw config file is larger than 4KiB
     pub fn from_string(env_raw: String) -> Result<Self, ParseError> {
         // Return error if environment is too large
-        if env_raw.as_bytes().len() > ENVIRONMENT_MAX_SIZE {
+        if !Self::would_fit(&env_raw) {
             return Err(ParseError::TooLarge);
         }
 
+        // Remove non-ASCII characters, warning about them as they might be part of a value
+        let non_ascii = env_raw.chars().filter(|c| !c.is_ascii()).count();
+        if non_ascii > 0 {
+            warn!(
+                "Removed {} non-ASCII characters from environment",
+                non_ascii
+            );
+        }
+
+        // Remove control characters
+        let env_raw: String = env_raw
+            .chars()
+            .filter(|&c| c.is_ascii() && (!c.is_ascii_control() || c == '\t' || c == '\n'))
+            .collect();
+
         // Parse environment
         let mut i: usize = 0;
-        let mut screen: (usize, usize) = (1024, 768); // default screen size
-        let mut kernel_filename = "sys/core";
+        let mut screen = default_screen();
+        let mut fb_format = None;
+        let mut kernel_filename = String::from(DEFAULT_KERNEL);
         let mut no_smp = false;
+        let mut no_acpi = false;
+        let mut no_smbios = false;
+        let mut initstack = INITSTACK_DEFAULT_SIZE;
+        let mut initrd = Vec::new();
+        let mut initrd_dir = None;
+        let mut boot_drive = None;
+        let mut initrd_crc32 = None;
+        let mut dry_run = false;
+        let mut dump_page_tables = false;
+        let mut load_addr = None;
+        let mut protocol = None;
+        let mut mmio_maps = Vec::new();
+        // Number of keys found, to warn about config files that have no effect
+        let mut keys_found = 0;
         loop {
             // Increment unless at start
             // This is done at the beginning of the loop so that it does not need to be put before
@@ -121,61 +240,33 @@ impl Environment {
             // Get screen size
             let screen_key = "screen=";
             if env_raw[i..].starts_with(screen_key) {
-                // Get length of width in characters
                 i += screen_key.len();
-                let width_offset = env_raw[i..].find('x');
-                if width_offset.is_none() {
-                    continue;
-                }
-                let width_offset = width_offset.unwrap();
-
-                // Parse screen width
-                let width = env_raw[i..i + width_offset].parse::<usize>();
-                if width.is_err() {
-                    continue;
-                }
-                let width = width.unwrap();
-
-                // Ensure screen width is valid
-                let width = if width < SCREEN_MIN_WIDTH {
-                    SCREEN_MIN_WIDTH
-                } else {
-                    width
-                };
-
-                // Get offset to height
-                i += width_offset + 1;
-                let height_offset = env_raw[i..].find(char::is_whitespace);
-                if height_offset.is_none() {
-                    continue;
+                keys_found += 1;
+                let len = env_raw[i..]
+                    .find(char::is_whitespace)
+                    .unwrap_or(env_raw.len() - i);
+                // Ignore invalid resolutions, keeping the default
+                if let Some(resolution) = parse_resolution(&env_raw[i..i + len]) {
+                    screen = resolution;
                 }
-                let height_offset = height_offset.unwrap();
-
-                // Parse height
-                let height = env_raw[i..i + height_offset].parse::<usize>();
-                if height.is_err() {
-                    continue;
-                }
-                let height = height.unwrap();
-                i += height_offset;
-
-                // Ensure screen height is valid
-                let height = if height < SCREEN_MIN_HEIGHT {
-                    SCREEN_MIN_HEIGHT
-                } else {
-                    height
-                };
-
-                // Set screen resolution
-                screen = (width, height);
+                i += len;
+                continue;
+            }
 
-                // Skip characters until new line is found
-                while i < env_raw.len() {
-                    if env_raw[i..].starts_with('\n') {
-                        break;
-                    }
-                    i += 1;
+            // Get framebuffer pixel format
+            let fb_format_key = "fbformat=";
+            if env_raw[i..].starts_with(fb_format_key) {
+                i += fb_format_key.len();
+                keys_found += 1;
+                let len = env_raw[i..]
+                    .find(char::is_whitespace)
+                    .unwrap_or(env_raw.len() - i);
+                // Ignore invalid formats, using any format
+                match parse_fb_format(&env_raw[i..i + len]) {
+                    Some(format) => fb_format = Some(format),
+                    None => debug!("Invalid framebuffer format: {}", &env_raw[i..i + len]),
                 }
+                i += len;
                 continue;
             }
 
@@ -183,23 +274,26 @@ impl Environment {
             let kernel_key = "kernel=";
             if env_raw[i..].starts_with(kernel_key) {
                 i += kernel_key.len();
+                keys_found += 1;
                 // Ensure not at end of file
                 if i >= env_raw.len() {
                     continue;
                 }
-                // Skip whitespace until kernel path starts
-                let mut j = i;
-                while j < env_raw.len() {
-                    if env_raw[j..].starts_with(char::is_whitespace) {
-                        break;
+                // Parse kernel path, which may be quoted
+                // An unterminated quote takes up the rest of the file, so the default is kept
+                let (value, value_len) = match parse_value(&env_raw[i..]) {
+                    Some(value) => value,
+                    None => {
+                        debug!("Unterminated quote in kernel path");
+                        i = env_raw.len();
+                        continue;
                     }
-                    j += 1;
-                }
+                };
                 // Set kernel filename
-                if j - i >= 1 {
-                    kernel_filename = &env_raw[i..j];
+                if !value.is_empty() {
+                    kernel_filename = value;
                 }
-                i = j;
+                i += value_len;
                 continue;
             }
 
@@ -207,29 +301,497 @@ impl Environment {
             let smp_disable_key = "nosmp=1";
             if env_raw[i..].starts_with(smp_disable_key) {
                 i += smp_disable_key.len();
+                keys_found += 1;
                 no_smp = true;
+                continue;
+            }
+
+            // Check for ACPI disable
+            let acpi_disable_key = "noacpi=1";
+            if env_raw[i..].starts_with(acpi_disable_key) {
+                i += acpi_disable_key.len();
+                keys_found += 1;
+                no_acpi = true;
+                continue;
+            }
+
+            // Check for SMBIOS disable
+            let smbios_disable_key = "nosmbios=1";
+            if env_raw[i..].starts_with(smbios_disable_key) {
+                i += smbios_disable_key.len();
+                keys_found += 1;
+                no_smbios = true;
+                continue;
+            }
+
+            // Check for dry run
+            let dry_run_key = "dryrun=1";
+            if env_raw[i..].starts_with(dry_run_key) {
+                i += dry_run_key.len();
+                keys_found += 1;
+                dry_run = true;
+                continue;
+            }
+
+            // Check for page table dump
+            let dump_page_tables_key = "dumppt=1";
+            if env_raw[i..].starts_with(dump_page_tables_key) {
+                i += dump_page_tables_key.len();
+                keys_found += 1;
+                dump_page_tables = true;
+                continue;
+            }
+
+            // Get initial stack size
+            let initstack_key = "initstack=";
+            if env_raw[i..].starts_with(initstack_key) {
+                i += initstack_key.len();
+                keys_found += 1;
+                let len = env_raw[i..]
+                    .find(char::is_whitespace)
+                    .unwrap_or(env_raw.len() - i);
+                // Ignore invalid sizes, keeping the default
+                if let Some(size) = parse_size(&env_raw[i..i + len]) {
+                    initstack = size.max(INITSTACK_MIN_SIZE);
+                }
+                i += len;
+                continue;
+            }
+
+            // Get kernel load address
+            let load_addr_key = "loadaddr=";
+            if env_raw[i..].starts_with(load_addr_key) {
+                i += load_addr_key.len();
+                keys_found += 1;
+                let len = env_raw[i..]
+                    .find(char::is_whitespace)
+                    .unwrap_or(env_raw.len() - i);
+                // Ignore invalid or unaligned addresses, loading the kernel anywhere
+                match parse_address(&env_raw[i..i + len]) {
+                    Some(addr) if addr % LOAD_ADDR_ALIGN == 0 => load_addr = Some(addr),
+                    _ => debug!("Invalid kernel load address: {}", &env_raw[i..i + len]),
+                }
+                i += len;
+                continue;
+            }
+
+            // Get extra MMIO range, which may be set multiple times
+            let map_key = "map=";
+            if env_raw[i..].starts_with(map_key) {
+                i += map_key.len();
+                keys_found += 1;
+                let len = env_raw[i..]
+                    .find(char::is_whitespace)
+                    .unwrap_or(env_raw.len() - i);
+                // Ignore invalid or unaligned ranges
+                match parse_mmio_map(&env_raw[i..i + len]) {
+                    Some(map) => mmio_maps.push(map),
+                    None => debug!("Invalid MMIO range: {}", &env_raw[i..i + len]),
+                }
+                i += len;
+                continue;
+            }
+
+            // Get protocol level
+            let protocol_key = "protocol=";
+            if env_raw[i..].starts_with(protocol_key) {
+                i += protocol_key.len();
+                keys_found += 1;
+                let len = env_raw[i..]
+                    .find(char::is_whitespace)
+                    .unwrap_or(env_raw.len() - i);
+                // Ignore invalid levels, detecting the level from the kernel
+                match parse_protocol(&env_raw[i..i + len]) {
+                    Some(level) => protocol = Some(level),
+                    None => debug!("Invalid protocol level: {}", &env_raw[i..i + len]),
+                }
+                i += len;
+                continue;
+            }
+
+            // Get initrd checksum
+            let initrd_crc32_key = "initrd_crc32=";
+            if env_raw[i..].starts_with(initrd_crc32_key) {
+                i += initrd_crc32_key.len();
+                keys_found += 1;
+                let len = env_raw[i..]
+                    .find(char::is_whitespace)
+                    .unwrap_or(env_raw.len() - i);
+                // Ignore invalid checksums, skipping verification
+                match parse_crc32(&env_raw[i..i + len]) {
+                    Some(crc32) => initrd_crc32 = Some(crc32),
+                    None => debug!("Invalid initrd CRC-32: {}", &env_raw[i..i + len]),
+                }
+                i += len;
+                continue;
+            }
+
+            // Get initrd subdirectory
+            let initrd_dir_key = "initrddir=";
+            if env_raw[i..].starts_with(initrd_dir_key) {
+                i += initrd_dir_key.len();
+                keys_found += 1;
+                // Ensure not at end of file
+                if i >= env_raw.len() {
+                    continue;
+                }
+                // Parse directory path, which may be quoted
+                let (value, value_len) = match parse_value(&env_raw[i..]) {
+                    Some(value) => value,
+                    None => {
+                        debug!("Unterminated quote in initrd directory");
+                        i = env_raw.len();
+                        continue;
+                    }
+                };
+                // Slashes around the path are ignored, and an empty path keeps the standard location
+                let value = value.trim_matches('/');
+                if !value.is_empty() {
+                    initrd_dir = Some(String::from(value));
+                }
+                i += value_len;
+                continue;
+            }
+
+            // Get partition to boot from
+            let boot_drive_key = "bootdrive=";
+            if env_raw[i..].starts_with(boot_drive_key) {
+                i += boot_drive_key.len();
+                keys_found += 1;
+                let len = env_raw[i..]
+                    .find(char::is_whitespace)
+                    .unwrap_or(env_raw.len() - i);
+                // Ignore invalid GUIDs, booting from the boot partition
+                match parse_guid(&env_raw[i..i + len]) {
+                    Some(guid) => boot_drive = Some(guid),
+                    None => debug!("Invalid boot drive GUID: {}", &env_raw[i..i + len]),
+                }
+                i += len;
+                continue;
+            }
+
+            // Get initrd modules
+            let initrd_key = "initrd=";
+            if env_raw[i..].starts_with(initrd_key) {
+                i += initrd_key.len();
+                keys_found += 1;
+                // Ensure not at end of file
+                if i >= env_raw.len() {
+                    continue;
+                }
+                // Parse comma separated list of modules, which may be quoted
+                let (value, value_len) = match parse_value(&env_raw[i..]) {
+                    Some(value) => value,
+                    None => {
+                        debug!("Unterminated quote in initrd modules");
+                        i = env_raw.len();
+                        continue;
+                    }
+                };
+                initrd = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|module| !module.is_empty())
+                    .map(String::from)
+                    .collect();
+                i += value_len;
+                continue;
+            }
+        }
+
+        // A config file without any keys is likely a mistake
+        if keys_found == 0 {
+            if env_raw.trim().is_empty() {
+                warn!("Environment is empty, using default values");
+            } else {
+                warn!("Environment has no recognized keys, using default values");
             }
         }
 
-        let kernel = String::from(kernel_filename);
         Ok(Environment {
             env_raw,
             screen,
-            kernel,
+            fb_format,
+            kernel: kernel_filename,
             no_smp,
+            no_acpi,
+            no_smbios,
+            initstack,
+            initrd,
+            initrd_dir,
+            boot_drive,
+            initrd_crc32,
+            dry_run,
+            dump_page_tables,
+            load_addr,
+            protocol,
+            mmio_maps,
         })
     }
+
+    /// Applies the directives that depend on hardware, which are `screen=` and `fbformat=`, now that
+    /// the graphics modes can be read through boot services.
+    ///
+    /// This is the second phase after [`Environment::from_string`]. The graphics mode that most
+    /// closely matches the directives is set, and the result is logged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the framebuffer could not be set up (see
+    /// [`Framebuffer::from_boot_services`]).
+    pub fn apply(&self, bt: &BootServices) -> UefiResult<Framebuffer> {
+        let framebuffer = Framebuffer::from_boot_services(bt, self.screen, self.fb_format)?;
+        debug!(
+            "Applied screen={}x{} and fbformat={:?}: {}x{} with format {:?}",
+            self.screen.0,
+            self.screen.1,
+            self.fb_format,
+            framebuffer.width,
+            framebuffer.height,
+            framebuffer.fb_type
+        );
+        Ok(framebuffer)
+    }
+
+    /// Writes the environment text to `page`, which is passed to the kernel.
+    ///
+    /// The text is followed by a null terminator, and the rest of the page is filled with zeros.
+    /// Text that does not fit with the null terminator is cut off, although [`from_string`] never
+    /// creates an environment that large.
+    ///
+    /// [`from_string`]: Environment::from_string
+    pub fn write_page(&self, page: &mut [u8; ENVIRONMENT_PAGE_SIZE]) {
+        let len = self.env_raw.len().min(ENVIRONMENT_MAX_SIZE);
+        page[..len].copy_from_slice(&self.env_raw.as_bytes()[..len]);
+        page[len..].fill(0);
+    }
+
+    /// Returns the size of the raw environment in bytes.
+    pub fn raw_len(&self) -> usize {
+        self.env_raw.len()
+    }
+
+    /// Returns true if `env_raw` is small enough to be used as an environment.
+    ///
+    /// The environment must fit in a 4KiB page along with a null terminator.
+    pub fn would_fit(env_raw: &str) -> bool {
+        env_raw.len() <= ENVIRONMENT_MAX_SIZE
+    }
 }
 
 impl Default for Environment {
+    /// Returns the default environment.
+    ///
+    /// The default kernel and screen resolution can be set at compile time with the
+    /// `TOYBB_DEFAULT_KERNEL` and `TOYBB_DEFAULT_SCREEN` environment variables.
     fn default() -> Self {
+        let screen = default_screen();
         Environment {
-            env_raw: "kernel=sys/core\nscreen=1024x768".to_string(),
-            screen: (1024, 768),
-            kernel: "sys/core".to_string(),
+            env_raw: format!(
+                "kernel={}\nscreen={}x{}",
+                DEFAULT_KERNEL, screen.0, screen.1
+            ),
+            screen,
+            fb_format: None,
+            kernel: DEFAULT_KERNEL.to_string(),
             no_smp: false,
+            no_acpi: false,
+            no_smbios: false,
+            initstack: INITSTACK_DEFAULT_SIZE,
+            initrd: Vec::new(),
+            initrd_dir: None,
+            boot_drive: None,
+            initrd_crc32: None,
+            dry_run: false,
+            dump_page_tables: false,
+            load_addr: None,
+            protocol: None,
+            mmio_maps: Vec::new(),
+        }
+    }
+}
+
+/// Returns the default screen resolution.
+///
+/// Uses `TOYBB_DEFAULT_SCREEN` if it was set at compile time and is a valid resolution.
+fn default_screen() -> (usize, usize) {
+    parse_resolution(DEFAULT_SCREEN).unwrap_or(SCREEN_DEFAULT_RESOLUTION)
+}
+
+/// Parses a screen resolution in the form `<width>x<height>`, such as `1024x768`.
+///
+/// Resolutions smaller than 640x480 are increased to the minimum.
+///
+/// Returns `None` if the width or height is not a number.
+fn parse_resolution(resolution: &str) -> Option<(usize, usize)> {
+    let (width, height) = resolution.split_once('x')?;
+    let width = width.parse::<usize>().ok()?.max(SCREEN_MIN_WIDTH);
+    let height = height.parse::<usize>().ok()?.max(SCREEN_MIN_HEIGHT);
+
+    Some((width, height))
+}
+
+/// Parses a framebuffer pixel format, which is one of `argb`, `rgba`, `abgr`, or `bgra`.
+///
+/// Returns `None` if the format is not one of these.
+fn parse_fb_format(format: &str) -> Option<FramebufferType> {
+    match format {
+        "argb" => Some(FramebufferType::Argb),
+        "rgba" => Some(FramebufferType::Rgba),
+        "abgr" => Some(FramebufferType::Abgr),
+        "bgra" => Some(FramebufferType::Bgra),
+        _ => None,
+    }
+}
+
+/// Parses a size in bytes with an optional `K` (KiB) or `M` (MiB) suffix, such as `64K` or `1M`.
+///
+/// Returns `None` if the size is not a number followed by a valid suffix.
+fn parse_size(size: &str) -> Option<usize> {
+    let (number, multiplier) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 1024),
+        b'M' | b'm' => (&size[..size.len() - 1], 1024 * 1024),
+        _ => (size, 1),
+    };
+
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+/// Parses an address that is either hexadecimal with a `0x` prefix, such as `0x200000`, or decimal.
+///
+/// Returns `None` if the address is not a valid number.
+fn parse_address(addr: &str) -> Option<u64> {
+    match addr.strip_prefix("0x").or_else(|| addr.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => addr.parse().ok(),
+    }
+}
+
+/// Parses an MMIO range in the form `<addr>:<size>` or `<addr>:<size>@<virt>`, where each number
+/// is an address as parsed by [`parse_address`].
+///
+/// Returns `None` if a number is invalid, the size is 0, the range overflows, or an address or the
+/// size is not page aligned.
+fn parse_mmio_map(map: &str) -> Option<MmioMap> {
+    let (range, virt) = match map.split_once('@') {
+        Some((range, virt)) => (range, Some(parse_address(virt)?)),
+        None => (map, None),
+    };
+    let (phys, size) = range.split_once(':')?;
+    let phys = parse_address(phys)?;
+    let size = parse_address(size)?;
+    let virt = virt.unwrap_or(phys);
+
+    let aligned = |value: u64| value % MMIO_MAP_ALIGN == 0;
+    if size == 0
+        || !aligned(phys)
+        || !aligned(size)
+        || !aligned(virt)
+        || phys.checked_add(size).is_none()
+        || virt.checked_add(size).is_none()
+    {
+        return None;
+    }
+
+    Some(MmioMap { phys, size, virt })
+}
+
+/// Parses a BOOTBOOT protocol level, which is either `static` or `dynamic`.
+///
+/// Returns `None` if the level is neither of these.
+fn parse_protocol(level: &str) -> Option<BootbootProtocolLevel> {
+    match level {
+        "static" => Some(BootbootProtocolLevel::Static),
+        "dynamic" => Some(BootbootProtocolLevel::Dynamic),
+        _ => None,
+    }
+}
+
+/// Parses a CRC-32 checksum of 8 hexadecimal digits, with an optional `0x` prefix.
+///
+/// Returns `None` if the checksum is not 8 hexadecimal digits.
+fn parse_crc32(crc32: &str) -> Option<u32> {
+    let crc32 = crc32
+        .strip_prefix("0x")
+        .or_else(|| crc32.strip_prefix("0X"))
+        .unwrap_or(crc32);
+    if crc32.len() != 8 {
+        return None;
+    }
+
+    u32::from_str_radix(crc32, 16).ok()
+}
+
+/// Parses a GUID in the form `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`, where each `x` is a
+/// hexadecimal digit.
+///
+/// The GUID is returned in the byte order it is stored on disk, where the first three groups are
+/// little endian and the last two groups are big endian.
+///
+/// Returns `None` if the GUID is not in this form.
+fn parse_guid(guid: &str) -> Option<[u8; 16]> {
+    let groups: Vec<&str> = guid.split('-').collect();
+    let group_sizes = [4, 2, 2, 2, 6];
+    if groups.len() != group_sizes.len() {
+        return None;
+    }
+
+    let mut bytes = [0; 16];
+    let mut idx = 0;
+    for (group_idx, (group, size)) in groups.iter().zip(group_sizes).enumerate() {
+        if group.len() != size * 2 || !group.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let group_bytes = &mut bytes[idx..idx + size];
+        for (byte_idx, byte) in group_bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&group[byte_idx * 2..byte_idx * 2 + 2], 16).ok()?;
+        }
+        if group_idx < 3 {
+            group_bytes.reverse();
+        }
+        idx += size;
+    }
+
+    Some(bytes)
+}
+
+/// Parses a config value at the start of `value`.
+///
+/// Unquoted values end at the first whitespace character. Values surrounded by double quotes may
+/// contain whitespace, and use `\"` and `\\` to escape a quote or backslash.
+///
+/// Returns the parsed value along with the number of bytes it takes up in `value`, or `None` if a
+/// quoted value is never terminated.
+fn parse_value(value: &str) -> Option<(String, usize)> {
+    // Unquoted value
+    if !value.starts_with('"') {
+        let len = value.find(char::is_whitespace).unwrap_or(value.len());
+        return Some((String::from(&value[..len]), len));
+    }
+
+    // Quoted value
+    let mut parsed = String::new();
+    let mut escaped = false;
+    for (i, c) in value.char_indices().skip(1) {
+        match c {
+            '"' | '\\' if escaped => {
+                parsed.push(c);
+                escaped = false;
+            }
+            '"' => return Some((parsed, i + 1)),
+            '\\' => escaped = true,
+            _ => {
+                // Keep backslashes that do not escape anything
+                if escaped {
+                    parsed.push('\\');
+                    escaped = false;
+                }
+                parsed.push(c);
+            }
         }
     }
+
+    None
 }
 
 /// Returns the contents of a config file.
@@ -237,8 +799,48 @@ impl Default for Environment {
 /// # Errors
 ///
 /// Returns an error if initrd could not be read from the boot disk or initrd.
-fn get_env_raw(bootdir: &mut Directory, initrd: &Initrd) -> UefiResult<String> {
-    read_env_file(bootdir).or(read_env_initrd(initrd))
+fn get_env_raw(bootdir: &mut Directory, initrd: Option<&Initrd>) -> UefiResult<String> {
+    if MERGE_CONFIG {
+        if let Some(Ok(initrd_env)) = initrd.map(read_env_initrd) {
+            return Ok(match read_env_file(bootdir) {
+                Ok(disk_env) => merge_env(&initrd_env, &disk_env),
+                Err(_) => initrd_env,
+            });
+        }
+    }
+
+    read_env_file(bootdir).or_else(|e| match initrd {
+        Some(initrd) => read_env_initrd(initrd),
+        None => Err(e),
+    })
+}
+
+/// Merges two config files, keeping every line of `base` whose key is not set in `overrides`.
+///
+/// Lines of `base` are kept in order and followed by all of `overrides`, so comments in either
+/// file are kept as well.
+fn merge_env(base: &str, overrides: &str) -> String {
+    let key = |line: &str| {
+        line.trim_start()
+            .split_once('=')
+            .map(|(key, _)| String::from(key))
+    };
+    let override_keys: Vec<String> = overrides.lines().filter_map(key).collect();
+
+    let mut merged = String::new();
+    for line in base.lines() {
+        match key(line) {
+            Some(key) if override_keys.contains(&key) => {}
+            _ => {
+                merged.push_str(line);
+                merged.push('\n');
+            }
+        }
+    }
+    merged.push_str(overrides);
+
+    debug!("Merged environment from boot disk and initrd");
+    merged
 }
 
 /// Returns the contents of `BOOTBOOT/CONFIG` if the file exists on the boot disk.
@@ -248,7 +850,8 @@ fn get_env_raw(bootdir: &mut Directory, initrd: &Initrd) -> UefiResult<String> {
 /// Returns an error if the file could not be read.
 fn read_env_file(bootdir: &mut Directory) -> UefiResult<String> {
     let mut env_file = open_file(bootdir, "CONFIG", FileMode::Read, FileAttribute::empty())?;
-    let env = read_to_string(&mut env_file);
+    let env = decode_text(&read_to_vec(&mut env_file)?)
+        .ok_or_else(|| UefiError::from(Status::COMPROMISED_DATA));
 
     if env.is_ok() {
         debug!("Found environment on boot disk");
@@ -262,12 +865,202 @@ fn read_env_file(bootdir: &mut Directory) -> UefiResult<String> {
 ///
 /// Returns an error if the file could not be read.
 fn read_env_initrd(initrd: &Initrd) -> UefiResult<String> {
-    let env_file = initrd.read_file("sys/config").ok_or(Status::NOT_FOUND)?;
-    let env =
-        String::from_utf8(env_file.to_vec()).map_err(|_| UefiError::from(Status::VOLUME_CORRUPTED));
+    let env_file = initrd
+        .read_file("sys/config")
+        .map_err(|_| UefiError::from(Status::NOT_FOUND))?;
+    let env = decode_text(env_file).ok_or_else(|| UefiError::from(Status::VOLUME_CORRUPTED));
 
     if env.is_ok() {
         debug!("Found environment in initrd file 'sys/config'");
     }
     env
 }
+
+/// Decodes a text file to a `String`.
+///
+/// Files starting with a UTF-16 LE or BE byte order mark are decoded as UTF-16, which is how some
+/// Windows editors save text files. Any other file is decoded as UTF-8. The byte order mark is not
+/// included in the returned string.
+///
+/// Returns `None` if the file is not valid UTF-8 or UTF-16.
+fn decode_text(raw: &[u8]) -> Option<String> {
+    const UTF8_BOM: &[u8] = &[0xef, 0xbb, 0xbf];
+    const UTF16_LE_BOM: &[u8] = &[0xff, 0xfe];
+    const UTF16_BE_BOM: &[u8] = &[0xfe, 0xff];
+
+    let decode_utf16 = |raw: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units = raw.chunks_exact(2);
+        if !units.remainder().is_empty() {
+            return None;
+        }
+        let units = units.map(|unit| from_bytes([unit[0], unit[1]]));
+        char::decode_utf16(units)
+            .collect::<Result<String, _>>()
+            .ok()
+    };
+
+    if let Some(raw) = raw.strip_prefix(UTF16_LE_BOM) {
+        decode_utf16(raw, u16::from_le_bytes)
+    } else if let Some(raw) = raw.strip_prefix(UTF16_BE_BOM) {
+        decode_utf16(raw, u16::from_be_bytes)
+    } else {
+        let raw = raw.strip_prefix(UTF8_BOM).unwrap_or(raw);
+        String::from_utf8(raw.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{select_mode, FbPrefs, ModeDesc};
+
+    #[test]
+    fn removes_control_characters() {
+        let env = Environment::from_string(String::from("kernel=sys/\0kernel\x07\nnosmp=1\n"))
+            .ok()
+            .unwrap();
+        assert_eq!(env.kernel, "sys/kernel");
+        assert!(env.no_smp);
+        assert_eq!(env.env_raw, "kernel=sys/kernel\nnosmp=1\n");
+    }
+
+    #[test]
+    fn removes_only_non_ascii_characters() {
+        let env = Environment::from_string(String::from("// caf\u{e9}\nkernel=sys/core2\n"))
+            .ok()
+            .unwrap();
+        assert_eq!(env.kernel, "sys/core2");
+        assert_eq!(env.env_raw, "// caf\nkernel=sys/core2\n");
+    }
+
+    #[test]
+    fn initrd_crc32() {
+        let env = Environment::from_string(String::from("initrd_crc32=0xCBF43926\n"))
+            .ok()
+            .unwrap();
+        assert_eq!(env.initrd_crc32, Some(0xcbf43926));
+        assert_eq!(parse_crc32("cbf43926"), Some(0xcbf43926));
+        assert_eq!(parse_crc32("cbf4392"), None);
+        assert_eq!(parse_crc32("0xcbf4392g"), None);
+    }
+
+    #[test]
+    fn parse_phase_stores_framebuffer_directives() {
+        let env = Environment::from_string(String::from("screen=800x600\nfbformat=rgba\n"))
+            .ok()
+            .unwrap();
+        assert_eq!(env.screen, (800, 600));
+        assert_eq!(env.fb_format, Some(FramebufferType::Rgba));
+
+        // Invalid directives keep the defaults, and small resolutions are raised to the minimum
+        let env = Environment::from_string(String::from("screen=huge\nfbformat=rgb\n"))
+            .ok()
+            .unwrap();
+        assert_eq!(env.screen, default_screen());
+        assert_eq!(env.fb_format, None);
+        let env = Environment::from_string(String::from("screen=320x200\n"))
+            .ok()
+            .unwrap();
+        assert_eq!(env.screen, (SCREEN_MIN_WIDTH, SCREEN_MIN_HEIGHT));
+    }
+
+    #[test]
+    fn select_mode_uses_parsed_framebuffer_directives() {
+        // `Environment::apply` needs boot services, so the mode selection it does is tested directly
+        let env = Environment::from_string(String::from("screen=800x600\nfbformat=rgba\n"))
+            .ok()
+            .unwrap();
+        let mode = |resolution, format| ModeDesc {
+            resolution,
+            format: Some(format),
+            stride: resolution.0,
+        };
+        let native = mode((1024, 768), FramebufferType::Bgra);
+        let modes = [
+            native,
+            mode((800, 600), FramebufferType::Bgra),
+            mode((640, 480), FramebufferType::Rgba),
+        ];
+        let prefs = FbPrefs {
+            format: env.fb_format,
+        };
+        assert_eq!(select_mode(&native, &modes, env.screen, &prefs), Some(2));
+    }
+
+    #[test]
+    fn quoted_kernel_path() {
+        let env = Environment::from_string(String::from("kernel=\"sys/my kernel\" nosmp=1\n"))
+            .ok()
+            .unwrap();
+        assert_eq!(env.kernel, "sys/my kernel");
+        assert!(env.no_smp);
+
+        let env = Environment::from_string(String::from(r#"kernel="sys/\"core\"\\x""#))
+            .ok()
+            .unwrap();
+        assert_eq!(env.kernel, r#"sys/"core"\x"#);
+        assert_eq!(parse_value(r#""a\"b" c"#), Some((String::from("a\"b"), 6)));
+    }
+
+    #[test]
+    fn unterminated_quoted_kernel_path() {
+        assert_eq!(parse_value("\"sys/core\nnosmp=1\n"), None);
+        let env = Environment::from_string(String::from("kernel=\"sys/core\nnosmp=1\n"))
+            .ok()
+            .unwrap();
+        assert_eq!(env.kernel, DEFAULT_KERNEL);
+        // The rest of the file is part of the unterminated value
+        assert!(!env.no_smp);
+    }
+
+    #[test]
+    fn initstack_size() {
+        let initstack = |env: &str| {
+            Environment::from_string(String::from(env))
+                .ok()
+                .unwrap()
+                .initstack
+        };
+        assert_eq!(initstack("initstack=64K\n"), 64 * 1024);
+        assert_eq!(initstack("initstack=1M\n"), 1024 * 1024);
+        assert_eq!(initstack("initstack=8192\n"), 8192);
+        // Sizes below the minimum are raised to it
+        assert_eq!(initstack("initstack=1K\n"), INITSTACK_MIN_SIZE);
+
+        // Invalid suffixes keep the default
+        assert_eq!(parse_size("64G"), None);
+        assert_eq!(parse_size("64KB"), None);
+        assert_eq!(parse_size("K"), None);
+        assert_eq!(initstack("initstack=64G\n"), INITSTACK_DEFAULT_SIZE);
+    }
+
+    #[test]
+    fn decode_text_encodings() {
+        assert_eq!(
+            decode_text(b"\xef\xbb\xbfnosmp=1\n"),
+            Some(String::from("nosmp=1\n"))
+        );
+        assert_eq!(
+            decode_text(b"\xff\xfen\0o\0s\0m\0p\0=\x001\0\n\0"),
+            Some(String::from("nosmp=1\n"))
+        );
+        assert_eq!(decode_text(b"nosmp=1\n"), Some(String::from("nosmp=1\n")));
+    }
+
+    #[test]
+    fn merge_env_overrides_keys() {
+        let base = "// initrd config\nscreen=800x600\nkernel=sys/core\nnosmp=1\n";
+        let overrides = "kernel=sys/core2\n";
+        assert_eq!(
+            merge_env(base, overrides),
+            "// initrd config\nscreen=800x600\nnosmp=1\nkernel=sys/core2\n"
+        );
+
+        let env = Environment::from_string(merge_env(base, overrides))
+            .ok()
+            .unwrap();
+        assert_eq!(env.kernel, "sys/core2");
+        assert_eq!(env.screen, (800, 600));
+        assert!(env.no_smp);
+    }
+}
@@ -1,14 +1,22 @@
-use log::debug;
+use alloc::vec::Vec;
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use log::{debug, warn};
 use uefi::{
-    prelude::BootServices,
-    proto::console::gop::{GraphicsOutput, ModeInfo},
-    Result as UefiResult,
+    prelude::{BootServices, Status},
+    proto::console::gop::{GraphicsOutput, Mode, ModeInfo, PixelBitmask, PixelFormat},
+    Error as UefiError, Result as UefiResult,
 };
 
 /// Uses UEFI Graphics Output Protocol to find an available graphics mode that closely matches the
 /// `target_resolution`.
 ///
-/// Returns the native mode if it matches the `target_resolution`.
+/// If `target_format` is set, modes with that pixel format are preferred over modes with a closer
+/// resolution. See [`choose_mode`] for how the mode is chosen.
+///
+/// Returns the native mode if it matches the `target_resolution` and `target_format`.
 ///
 /// If the mode that is closest to the `target_resolution` is not the native mode, then the GOP is
 /// set to use the new mode. However, if this action fails then the native mode is returned.
@@ -16,7 +24,11 @@ use uefi::{
 /// # Errors
 ///
 /// Returns an error if GOP cannot be located.
-fn get_gop_info(bt: &BootServices, _target_resolution: (usize, usize)) -> UefiResult<ModeInfo> {
+fn get_gop_info(
+    bt: &BootServices,
+    target_resolution: (usize, usize),
+    target_format: Option<FramebufferType>,
+) -> UefiResult<ModeInfo> {
     // Try to get GOP (graphics output protocol)
     let gop = unsafe { &mut *bt.locate_protocol::<GraphicsOutput>()?.get() };
 
@@ -28,12 +40,227 @@ fn get_gop_info(bt: &BootServices, _target_resolution: (usize, usize)) -> UefiRe
         native_info.stride(),
         native_info.pixel_format()
     );
+    if cfg!(debug_assertions) {
+        debug_modes(gop);
+    }
+
+    // Find the mode that is closest to the target resolution and format
+    // Return native mode if no mode is closer
+    let prefs = FbPrefs {
+        format: target_format,
+    };
+    let modes: Vec<Mode> = gop.modes().collect();
+    let descs: Vec<ModeDesc> = modes
+        .iter()
+        .map(|mode| ModeDesc::from_gop(mode.info()))
+        .collect();
+    let native = ModeDesc::from_gop(&native_info);
+    let closest_mode = match select_mode(&native, &descs, target_resolution, &prefs) {
+        Some(closest) => &modes[closest],
+        None => return Ok(native_info),
+    };
+
+    // Set GOP to use the closest mode
+    // Use native mode if this fails
+    match gop.set_mode(closest_mode) {
+        Ok(()) => {
+            debug!(
+                "Set mode {}: resolution={:?}, stride={}, format={:?}",
+                closest_mode.index(),
+                closest_mode.info().resolution(),
+                closest_mode.info().stride(),
+                closest_mode.info().pixel_format()
+            );
+            Ok(*closest_mode.info())
+        }
+        Err(e) => {
+            debug!(
+                "Could not set mode {} ({:?}), using native mode",
+                closest_mode.index(),
+                e.status()
+            );
+            Ok(native_info)
+        }
+    }
+}
+
+/// The properties of a graphics mode that are used to choose a mode, independent of GOP.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ModeDesc {
+    pub resolution: (usize, usize),
+    /// BOOTBOOT pixel format, or `None` if the mode does not have a usable linear framebuffer
+    pub format: Option<FramebufferType>,
+    /// Size of each row of pixels in pixels
+    pub stride: usize,
+}
+
+impl ModeDesc {
+    /// Returns the description of a GOP mode.
+    fn from_gop(info: &ModeInfo) -> Self {
+        Self {
+            resolution: info.resolution(),
+            format: FramebufferType::from_gop(info.pixel_format(), info.pixel_bitmask()),
+            stride: info.stride(),
+        }
+    }
+}
+
+/// Preferences other than resolution for choosing a graphics mode.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FbPrefs {
+    /// Pixel format that is preferred over a closer resolution, if set
+    pub format: Option<FramebufferType>,
+}
+
+/// Returns the index of the mode in `modes` that is closest to `target_resolution` and `prefs`.
+///
+/// Modes with the preferred pixel format are always chosen over modes without it. Otherwise, the
+/// mode with the smallest [`resolution_distance`] is chosen, and ties go to the first of the tied
+/// modes. Modes without a usable linear framebuffer are never chosen.
+///
+/// Returns `None` if no mode has a usable linear framebuffer.
+pub fn choose_mode(
+    modes: &[ModeDesc],
+    target_resolution: (usize, usize),
+    prefs: &FbPrefs,
+) -> Option<usize> {
+    modes
+        .iter()
+        .enumerate()
+        .filter(|(_, mode)| mode.format.is_some())
+        .min_by_key(|(_, mode)| mode_distance(mode, target_resolution, prefs))
+        .map(|(i, _)| i)
+}
+
+/// Returns the index of the mode in `modes` to switch to from the `native` mode, or `None` if the
+/// native mode should be kept.
+///
+/// The native mode is kept if it matches `target_resolution` and `prefs` exactly, or if the mode
+/// chosen by [`choose_mode`] is not any closer.
+pub fn select_mode(
+    native: &ModeDesc,
+    modes: &[ModeDesc],
+    target_resolution: (usize, usize),
+    prefs: &FbPrefs,
+) -> Option<usize> {
+    let native_distance = mode_distance(native, target_resolution, prefs);
+    if native_distance == (false, 0) {
+        return None;
+    }
+
+    let closest = choose_mode(modes, target_resolution, prefs)?;
+    if mode_distance(&modes[closest], target_resolution, prefs) >= native_distance {
+        return None;
+    }
+    Some(closest)
+}
+
+/// Prints the index, resolution, stride, and pixel format of every mode offered by `gop`.
+fn debug_modes(gop: &GraphicsOutput) {
+    debug!("GOP modes:");
+    for mode in gop.modes() {
+        let info = mode.info();
+        debug!(
+            "Mode {}: resolution={:?}, stride={}, format={:?}",
+            mode.index(),
+            info.resolution(),
+            info.stride(),
+            info.pixel_format()
+        );
+    }
+}
 
-    // Always use native video mode for now
-    Ok(native_info)
+/// Returns how far `mode` is from the target resolution and the preferences in `prefs`.
+///
+/// The first value is true if the mode does not have the preferred format, so a mode with the
+/// preferred format is always closer than one without it. The second value is the resolution
+/// distance.
+fn mode_distance(
+    mode: &ModeDesc,
+    target_resolution: (usize, usize),
+    prefs: &FbPrefs,
+) -> (bool, usize) {
+    let format_mismatch = prefs.format.is_some() && mode.format != prefs.format;
+    (
+        format_mismatch,
+        resolution_distance(mode.resolution, target_resolution),
+    )
+}
 
-    // Return native mode if it matches the target resolution
-    // TODO: Decide on how to choose video mode when native does not match
+/// Returns the sum of the differences in width and height between two resolutions.
+fn resolution_distance(resolution: (usize, usize), target_resolution: (usize, usize)) -> usize {
+    let width_distance = resolution.0.abs_diff(target_resolution.0);
+    let height_distance = resolution.1.abs_diff(target_resolution.1);
+    width_distance + height_distance
+}
+
+/// True while the framebuffer is identity mapped at its physical address.
+///
+/// This is only the case while boot services are active, as the kernel maps the framebuffer at a
+/// different virtual address.
+static IDENTITY_MAPPED: AtomicBool = AtomicBool::new(true);
+
+/// An error resulting from drawing to the framebuffer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FramebufferError {
+    /// The framebuffer is no longer identity mapped
+    Unmapped,
+    /// The pixel is outside of the framebuffer
+    OutOfBounds,
+}
+
+/// Size of a framebuffer pixel in bytes.
+const PIXEL_SIZE: usize = 4;
+
+/// Height in pixels of a row of text, matching the 8x16 font used for text output.
+pub const GLYPH_HEIGHT: u32 = 16;
+
+/// BOOTBOOT framebuffer pixel format.
+///
+/// Formats are named by the order of channels in a 32bit pixel value, from the most to the least
+/// significant byte.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FramebufferType {
+    Argb = 0,
+    Rgba = 1,
+    Abgr = 2,
+    Bgra = 3,
+}
+
+impl FramebufferType {
+    /// Converts a UEFI GOP pixel format to a BOOTBOOT framebuffer type.
+    ///
+    /// Returns `None` if the pixel format does not have a linear framebuffer or uses a bitmask that
+    /// does not match a BOOTBOOT framebuffer type.
+    pub fn from_gop(format: PixelFormat, bitmask: Option<PixelBitmask>) -> Option<Self> {
+        match format {
+            // Blue is the first byte in memory, so it is the least significant byte
+            PixelFormat::Bgr => Some(Self::Argb),
+            PixelFormat::Rgb => Some(Self::Abgr),
+            PixelFormat::Bitmask => match bitmask.map(|mask| (mask.red, mask.blue)) {
+                Some((0x00ff_0000, 0x0000_00ff)) => Some(Self::Argb),
+                Some((0xff00_0000, 0x0000_ff00)) => Some(Self::Rgba),
+                Some((0x0000_00ff, 0x00ff_0000)) => Some(Self::Abgr),
+                Some((0x0000_ff00, 0xff00_0000)) => Some(Self::Bgra),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the 32bit pixel value of a color in this format.
+    ///
+    /// The alpha channel is set to 0.
+    pub fn pixel(&self, r: u8, g: u8, b: u8) -> u32 {
+        let (r, g, b) = (r as u32, g as u32, b as u32);
+        match self {
+            Self::Argb => r << 16 | g << 8 | b,
+            Self::Rgba => r << 24 | g << 16 | b << 8,
+            Self::Abgr => b << 16 | g << 8 | r,
+            Self::Bgra => b << 24 | g << 16 | r << 8,
+        }
+    }
 }
 
 /// BOOTBOOT linear framebuffer information.
@@ -44,24 +271,29 @@ pub struct Framebuffer {
     pub size: u32,
     pub width: u32,
     pub height: u32,
+    /// Size of each row of pixels in bytes
     pub scanline: u32,
+    pub fb_type: FramebufferType,
 }
 
 impl Framebuffer {
     /// Uses UEFI Graphics Output Protocol to create a [`Framebuffer`] that most closely matches
-    /// `target_resolution`.
+    /// `target_resolution`, preferring modes with the pixel format `target_format` if it is set.
     ///
-    /// For now, the native resolution is always used.
+    /// A warning is logged if the resulting resolution or pixel format does not match the target.
+    /// The framebuffer type is always the format that was actually selected.
     ///
     /// # Errors
     ///
-    /// Returns an error if GOP cannot be located.
+    /// Returns an error if GOP cannot be located or if its pixel format is not supported by
+    /// BOOTBOOT.
     pub fn from_boot_services(
         bt: &BootServices,
         target_resolution: (usize, usize),
+        target_format: Option<FramebufferType>,
     ) -> UefiResult<Self> {
         // Get GOP mode
-        let gop_info = get_gop_info(bt, target_resolution)?;
+        let gop_info = get_gop_info(bt, target_resolution, target_format)?;
 
         // Get GOP (graphics output protocol)
         let gop = unsafe { &mut *bt.locate_protocol::<GraphicsOutput>()?.get() };
@@ -69,14 +301,292 @@ impl Framebuffer {
         let ptr = uefi_framebuffer.as_mut_ptr() as usize as u64;
         let (width, height) = gop_info.resolution();
         let size = uefi_framebuffer.size() as u32;
+        let fb_type = FramebufferType::from_gop(gop_info.pixel_format(), gop_info.pixel_bitmask())
+            .ok_or_else(|| UefiError::from(Status::UNSUPPORTED))?;
 
         // Create Framebuffer from GOP info
-        Ok(Self {
+        // GOP stride is in pixels, but BOOTBOOT scanline is in bytes
+        let framebuffer = Self {
             ptr,
             size,
             width: width as u32,
             height: height as u32,
-            scanline: gop_info.stride() as u32,
-        })
+            scanline: (gop_info.stride() * PIXEL_SIZE) as u32,
+            fb_type,
+        };
+
+        // Warn if the requested resolution could not be used
+        if !framebuffer.matches_resolution(target_resolution) {
+            warn!(
+                "Requested resolution {}x{} is unavailable, using {}x{}",
+                target_resolution.0, target_resolution.1, framebuffer.width, framebuffer.height
+            );
+        }
+
+        // Warn if the requested pixel format could not be used
+        if let Some(target_format) = target_format {
+            if target_format != framebuffer.fb_type {
+                warn!(
+                    "Requested pixel format {:?} is unavailable, using {:?}",
+                    target_format, framebuffer.fb_type
+                );
+            }
+        }
+
+        Ok(framebuffer)
+    }
+
+    /// Returns a framebuffer with a null pointer and no pixels, which is passed to the kernel on
+    /// systems without a usable GOP.
+    ///
+    /// Drawing to it does nothing, as every pixel is out of bounds.
+    pub fn null() -> Self {
+        Self {
+            ptr: 0,
+            size: 0,
+            width: 0,
+            height: 0,
+            scanline: 0,
+            fb_type: FramebufferType::Argb,
+        }
+    }
+
+    /// Returns true if this is a [`Framebuffer::null`] framebuffer.
+    pub fn is_null(&self) -> bool {
+        self.ptr == 0
+    }
+
+    /// Marks the framebuffer as no longer identity mapped, so drawing to it fails.
+    ///
+    /// This must be called before exiting boot services.
+    pub fn set_unmapped() {
+        IDENTITY_MAPPED.store(false, Ordering::Relaxed);
+    }
+
+    /// Returns true if the framebuffer can be drawn to at its physical address.
+    pub fn is_mapped() -> bool {
+        IDENTITY_MAPPED.load(Ordering::Relaxed)
+    }
+
+    /// Writes a pixel with the color (`r`, `g`, `b`) at (`x`, `y`), using the channel order of this
+    /// framebuffer's type.
+    ///
+    /// Pixels outside of the framebuffer are not written. Drawing after the framebuffer is unmapped
+    /// is a bug, so it panics in debug builds and does nothing in release builds.
+    pub fn put_pixel(&self, x: u32, y: u32, r: u8, g: u8, b: u8) {
+        let result = self.try_put_pixel(x, y, r, g, b);
+        debug_assert_ne!(result, Err(FramebufferError::Unmapped));
+    }
+
+    /// Writes a pixel like [`Framebuffer::put_pixel`], but returns an error if it is not written.
+    ///
+    /// # Errors
+    ///
+    /// * `FramebufferError::Unmapped`: The framebuffer is no longer identity mapped
+    /// * `FramebufferError::OutOfBounds`: The pixel is outside of the framebuffer
+    pub fn try_put_pixel(
+        &self,
+        x: u32,
+        y: u32,
+        r: u8,
+        g: u8,
+        b: u8,
+    ) -> Result<(), FramebufferError> {
+        if !Self::is_mapped() {
+            return Err(FramebufferError::Unmapped);
+        }
+        if x >= self.width || y >= self.height {
+            return Err(FramebufferError::OutOfBounds);
+        }
+
+        let offset = y as usize * self.scanline as usize + x as usize * PIXEL_SIZE;
+        let pixel = self.fb_type.pixel(r, g, b);
+        // The framebuffer stays mapped while boot services are active and the pixel is in bounds
+        unsafe {
+            ((self.ptr as usize + offset) as *mut u32).write_volatile(pixel);
+        }
+        Ok(())
+    }
+
+    /// Moves the framebuffer contents up by `rows` rows of text and fills the rows exposed at the
+    /// bottom with the pixel value `bg`.
+    ///
+    /// If `rows` covers the whole screen, the screen is only cleared. Like
+    /// [`Framebuffer::put_pixel`], scrolling after the framebuffer is unmapped panics in debug
+    /// builds and does nothing in release builds.
+    // Only used by tests until there is a framebuffer console to scroll
+    #[allow(dead_code)]
+    pub fn scroll_up(&self, rows: u32, bg: u32) {
+        debug_assert!(Self::is_mapped());
+        if !Self::is_mapped() || self.is_null() {
+            return;
+        }
+
+        let shift = rows.saturating_mul(GLYPH_HEIGHT).min(self.height);
+        let scanline = self.scanline as usize;
+        let base = self.ptr as usize as *mut u8;
+        let kept_rows = (self.height - shift) as usize;
+
+        // The framebuffer stays mapped while boot services are active and both regions are inside
+        // it, so this is a memmove within the framebuffer
+        unsafe {
+            ptr::copy(
+                base.add(shift as usize * scanline),
+                base,
+                kept_rows * scanline,
+            );
+        }
+
+        // Clear the exposed rows, skipping the padding at the end of each scanline
+        for y in kept_rows..self.height as usize {
+            let row = unsafe { base.add(y * scanline) } as *mut u32;
+            for x in 0..self.width as usize {
+                unsafe { row.add(x).write_volatile(bg) };
+            }
+        }
+    }
+
+    /// Returns true if this framebuffer's resolution is exactly `resolution`.
+    pub fn matches_resolution(&self, resolution: (usize, usize)) -> bool {
+        (self.width as usize, self.height as usize) == resolution
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(width: usize, height: usize, format: Option<FramebufferType>) -> ModeDesc {
+        ModeDesc {
+            resolution: (width, height),
+            format,
+            stride: width,
+        }
+    }
+
+    #[test]
+    fn select_mode_keeps_exact_native_mode() {
+        let native = mode(1024, 768, Some(FramebufferType::Bgra));
+        let modes = [mode(800, 600, Some(FramebufferType::Bgra)), native];
+        let prefs = FbPrefs::default();
+        assert_eq!(select_mode(&native, &modes, (1024, 768), &prefs), None);
+    }
+
+    #[test]
+    fn select_mode_switches_to_closer_mode() {
+        let native = mode(1024, 768, Some(FramebufferType::Bgra));
+        let modes = [native, mode(800, 600, Some(FramebufferType::Bgra))];
+        let prefs = FbPrefs::default();
+        assert_eq!(select_mode(&native, &modes, (800, 600), &prefs), Some(1));
+        assert_eq!(select_mode(&native, &modes, (820, 600), &prefs), Some(1));
+    }
+
+    #[test]
+    fn select_mode_keeps_native_mode_if_nothing_is_closer() {
+        let native = mode(1024, 768, Some(FramebufferType::Bgra));
+        let modes = [native, mode(1280, 1024, Some(FramebufferType::Bgra))];
+        let prefs = FbPrefs::default();
+        assert_eq!(select_mode(&native, &modes, (800, 600), &prefs), None);
+        assert_eq!(select_mode(&native, &[], (800, 600), &prefs), None);
+    }
+
+    #[test]
+    fn choose_mode_exact_match() {
+        let modes = [
+            mode(640, 480, Some(FramebufferType::Bgra)),
+            mode(1024, 768, Some(FramebufferType::Bgra)),
+            mode(1280, 1024, Some(FramebufferType::Bgra)),
+        ];
+        let prefs = FbPrefs::default();
+        assert_eq!(choose_mode(&modes, (1024, 768), &prefs), Some(1));
+    }
+
+    #[test]
+    fn choose_mode_nearest_match_ties_go_to_first_mode() {
+        let modes = [
+            mode(640, 480, Some(FramebufferType::Bgra)),
+            mode(800, 600, Some(FramebufferType::Bgra)),
+            mode(1000, 600, Some(FramebufferType::Bgra)),
+        ];
+        let prefs = FbPrefs::default();
+        assert_eq!(choose_mode(&modes, (820, 610), &prefs), Some(1));
+        // 800x600 and 1000x600 are both 100 pixels away from 900x600
+        assert_eq!(choose_mode(&modes, (900, 600), &prefs), Some(1));
+        let modes = [modes[2], modes[1]];
+        assert_eq!(choose_mode(&modes, (900, 600), &prefs), Some(0));
+    }
+
+    #[test]
+    fn choose_mode_prefers_format_over_resolution() {
+        let modes = [
+            mode(1024, 768, Some(FramebufferType::Bgra)),
+            mode(640, 480, Some(FramebufferType::Rgba)),
+            mode(800, 600, Some(FramebufferType::Rgba)),
+        ];
+        let prefs = FbPrefs {
+            format: Some(FramebufferType::Rgba),
+        };
+        assert_eq!(choose_mode(&modes, (1024, 768), &prefs), Some(2));
+
+        // Without a mode in the preferred format, the closest resolution is used
+        let prefs = FbPrefs {
+            format: Some(FramebufferType::Argb),
+        };
+        assert_eq!(choose_mode(&modes, (1024, 768), &prefs), Some(0));
+    }
+
+    #[test]
+    fn choose_mode_skips_modes_without_linear_framebuffer() {
+        let modes = [
+            mode(1024, 768, None),
+            mode(800, 600, Some(FramebufferType::Bgra)),
+        ];
+        let prefs = FbPrefs::default();
+        assert_eq!(choose_mode(&modes, (1024, 768), &prefs), Some(1));
+        assert_eq!(choose_mode(&modes[..1], (1024, 768), &prefs), None);
+    }
+
+    #[test]
+    fn pixel_byte_order() {
+        let pixel = |fb_type: FramebufferType| fb_type.pixel(0x11, 0x22, 0x33);
+        assert_eq!(pixel(FramebufferType::Argb), 0x0011_2233);
+        assert_eq!(pixel(FramebufferType::Rgba), 0x1122_3300);
+        assert_eq!(pixel(FramebufferType::Abgr), 0x0033_2211);
+        assert_eq!(pixel(FramebufferType::Bgra), 0x3322_1100);
+    }
+
+    #[test]
+    fn scroll_up() {
+        const WIDTH: usize = 4;
+        // Each scanline has one pixel of padding, which is never written
+        const STRIDE: usize = WIDTH + 1;
+        const HEIGHT: usize = 40;
+        const BG: u32 = 0xffff_ffff;
+        let mut buffer: Vec<u32> = (0..STRIDE * HEIGHT).map(|i| (i / STRIDE) as u32).collect();
+        let framebuffer = Framebuffer {
+            ptr: buffer.as_mut_ptr() as u64,
+            size: (STRIDE * HEIGHT * PIXEL_SIZE) as u32,
+            width: WIDTH as u32,
+            height: HEIGHT as u32,
+            scanline: (STRIDE * PIXEL_SIZE) as u32,
+            fb_type: FramebufferType::Argb,
+        };
+
+        framebuffer.scroll_up(1, BG);
+        let row = |buffer: &[u32], y: usize| buffer[y * STRIDE..y * STRIDE + WIDTH].to_vec();
+        let shift = GLYPH_HEIGHT as usize;
+        for y in 0..HEIGHT - shift {
+            assert_eq!(row(&buffer, y), [(y + shift) as u32; WIDTH]);
+        }
+        for y in HEIGHT - shift..HEIGHT {
+            assert_eq!(row(&buffer, y), [BG; WIDTH]);
+            assert_eq!(buffer[y * STRIDE + WIDTH], y as u32);
+        }
+
+        // Scrolling past the bottom of the screen only clears it
+        framebuffer.scroll_up(HEIGHT as u32, 0);
+        for y in 0..HEIGHT {
+            assert_eq!(row(&buffer, y), [0; WIDTH]);
+        }
     }
 }
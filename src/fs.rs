@@ -1,10 +1,118 @@
 use alloc::{boxed::Box, string::String, vec, vec::Vec};
 use uefi::{
-    prelude::{ResultExt, Status},
-    proto::media::file::{Directory, File, FileAttribute, FileInfo, FileMode, RegularFile},
-    CString16, Error as UefiError, Result as UefiResult,
+    prelude::{BootServices, ResultExt, Status},
+    proto::{
+        device_path::{DevicePath, DevicePathNode, DeviceSubType, DeviceType},
+        media::{
+            file::{Directory, File, FileAttribute, FileInfo, FileMode, RegularFile},
+            fs::SimpleFileSystem,
+        },
+    },
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol},
+    CString16, Error as UefiError, Handle, Result as UefiResult,
 };
 
+/// Offset of the partition signature in the data of a hard drive device path node.
+const PARTITION_SIGNATURE_OFFSET: usize = 20;
+/// Offset of the partition signature type in the data of a hard drive device path node.
+const PARTITION_SIGNATURE_TYPE_OFFSET: usize = 37;
+/// Signature type of GPT partitions, which are identified by a GUID.
+const GUID_SIGNATURE_TYPE: u8 = 0x02;
+
+/// Returns the file system on the GPT partition with the partition `guid`, which is in the byte
+/// order it is stored on disk.
+///
+/// Every handle with a file system is checked for a hard drive node with `guid` in its device path.
+///
+/// # Errors
+///
+/// * `Status::NOT_FOUND`: No file system is on a partition with `guid`
+/// * Any error from opening the file system, such as `Status::ACCESS_DENIED` if it is in use
+pub fn find_partition_file_system<'a>(
+    bt: &'a BootServices,
+    image_handle: Handle,
+    guid: &[u8; 16],
+) -> UefiResult<ScopedProtocol<'a, SimpleFileSystem>> {
+    for handle in bt.find_handles::<SimpleFileSystem>()? {
+        let params = || OpenProtocolParams {
+            handle,
+            agent: image_handle,
+            controller: None,
+        };
+        // The device path is only read, so it does not need to be opened exclusively
+        let device_path = match unsafe {
+            bt.open_protocol::<DevicePath>(params(), OpenProtocolAttributes::GetProtocol)
+        } {
+            Ok(device_path) => device_path,
+            Err(_) => continue,
+        };
+        let device_path = unsafe { &*device_path.interface.get() };
+        if device_path
+            .node_iter()
+            .any(|node| is_partition_node(node, guid))
+        {
+            return unsafe {
+                bt.open_protocol::<SimpleFileSystem>(params(), OpenProtocolAttributes::Exclusive)
+            };
+        }
+    }
+
+    Err(UefiError::from(Status::NOT_FOUND))
+}
+
+/// Returns true if `node` is a hard drive node of the GPT partition with `guid`.
+fn is_partition_node(node: &DevicePathNode, guid: &[u8; 16]) -> bool {
+    let data = node.data();
+    node.device_type() == DeviceType::MEDIA
+        && node.sub_type() == DeviceSubType::MEDIA_HARD_DRIVE
+        && data.get(PARTITION_SIGNATURE_TYPE_OFFSET) == Some(&GUID_SIGNATURE_TYPE)
+        && data.get(PARTITION_SIGNATURE_OFFSET..PARTITION_SIGNATURE_OFFSET + guid.len())
+            == Some(&guid[..])
+}
+
+/// A directory that files can be read from by their path.
+///
+/// Paths separate directories with `/`. This lets code that searches for files be tested without
+/// UEFI.
+pub trait FileSystem {
+    /// Returns the size in bytes of the file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file does not exist or its size could not be read.
+    fn file_size(&mut self, path: &str) -> UefiResult<usize>;
+
+    /// Reads the whole file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file does not exist or could not be read.
+    fn read_file(&mut self, path: &str) -> UefiResult<Vec<u8>>;
+}
+
+impl FileSystem for Directory {
+    fn file_size(&mut self, path: &str) -> UefiResult<usize> {
+        let mut file = open_path(self, path)?;
+        file_size(&mut file)
+    }
+
+    fn read_file(&mut self, path: &str) -> UefiResult<Vec<u8>> {
+        let mut file = open_path(self, path)?;
+        read_to_vec(&mut file)
+    }
+}
+
+/// Opens the file at `path` in `dir` for reading, converting `/` to the backslashes that UEFI
+/// separates directories with.
+fn open_path(dir: &mut Directory, path: &str) -> UefiResult<RegularFile> {
+    open_file(
+        dir,
+        &path.replace('/', "\\"),
+        FileMode::Read,
+        FileAttribute::empty(),
+    )
+}
+
 /// Opens a subdirectory with `dirname` in the `root` directory.
 pub fn open_dir(root: &mut Directory, dirname: &str) -> UefiResult<Directory> {
     let dirname =
@@ -26,17 +134,48 @@ pub fn open_file(
         .map(|file| unsafe { RegularFile::new(file) })
 }
 
+/// Returns the size in bytes of an open `file`.
+pub fn file_size(file: &mut RegularFile) -> UefiResult<usize> {
+    let file_info: Box<FileInfo> = file.get_boxed_info()?;
+    Ok(file_info.file_size() as usize)
+}
+
+/// Size of each read in [`read_to_vec_with_progress`].
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+
 /// Reads an open `file` into a dynamically allocated `Vec<u8>`.
 pub fn read_to_vec(file: &mut RegularFile) -> UefiResult<Vec<u8>> {
+    read_to_vec_with_progress(file, |_, _| {})
+}
+
+/// Reads an open `file` into a dynamically allocated `Vec<u8>` in chunks, calling `progress` with
+/// the number of bytes read so far and the file size after each chunk.
+///
+/// If the file ends before its reported size, the returned `Vec` only contains the bytes read.
+pub fn read_to_vec_with_progress(
+    file: &mut RegularFile,
+    mut progress: impl FnMut(usize, usize),
+) -> UefiResult<Vec<u8>> {
     // Get file size
     // Returns error if file info cannot be read
-    let file_info: Box<FileInfo> = file.get_boxed_info()?;
-    let size = file_info.file_size();
+    let size = file_size(file)?;
     // Allocate buffer
-    let mut buffer = vec![0; size as usize];
-    // Read file to buffer
+    let mut buffer = vec![0; size];
+    // Read file to buffer one chunk at a time
     // Returns error if file cannot be read
-    file.read(&mut buffer[..]).discard_errdata()?;
+    let mut bytes_read = 0;
+    while bytes_read < size {
+        let chunk_end = (bytes_read + READ_CHUNK_SIZE).min(size);
+        let chunk_read = file
+            .read(&mut buffer[bytes_read..chunk_end])
+            .discard_errdata()?;
+        if chunk_read == 0 {
+            break;
+        }
+        bytes_read += chunk_read;
+        progress(bytes_read, size);
+    }
+    buffer.truncate(bytes_read);
 
     Ok(buffer)
 }
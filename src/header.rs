@@ -1,4 +1,34 @@
-use crate::{Framebuffer, Initrd};
+use crate::{BootbootMMap, Environment, Framebuffer, ENVIRONMENT_PAGE_SIZE, MMAP_MAX_ENTRIES};
+
+/// Magic numbers at the start of the BOOTBOOT struct, which is "BOOT" when read as a string.
+pub const BOOTBOOT_MAGIC: [u8; 4] = *b"BOOT";
+
+/// Size in bytes of the BOOTBOOT struct before the memory map entries.
+///
+/// The kernel's `bootboot` symbol must have room for at least this many bytes.
+pub const BOOTBOOT_STRUCT_SIZE: usize = 128;
+
+/// Size in bytes of the page that holds the BOOTBOOT struct followed by the memory map.
+pub const BOOTBOOT_INFO_SIZE: usize = 4096;
+
+/// Offsets of the fields in the BOOTBOOT struct.
+const SIZE_OFFSET: usize = 4;
+const PROTOCOL_OFFSET: usize = 8;
+const FB_TYPE_OFFSET: usize = 9;
+const NUMCORES_OFFSET: usize = 10;
+const BSPID_OFFSET: usize = 12;
+const TIMEZONE_OFFSET: usize = 14;
+const DATETIME_OFFSET: usize = 16;
+const INITRD_PTR_OFFSET: usize = 24;
+const INITRD_SIZE_OFFSET: usize = 32;
+const FB_PTR_OFFSET: usize = 40;
+const FB_SIZE_OFFSET: usize = 48;
+const FB_WIDTH_OFFSET: usize = 52;
+const FB_HEIGHT_OFFSET: usize = 56;
+const FB_SCANLINE_OFFSET: usize = 60;
+const ARCH_OFFSET: usize = 64;
+/// Size in bytes of each memory map entry after the BOOTBOOT struct.
+const MMAP_ENTRY_SIZE: usize = 16;
 
 /// BOOTBOOT loader type
 #[repr(u8)]
@@ -25,6 +55,7 @@ impl TryFrom<u8> for LoaderType {
 
 /// BOOTBOOT protocol level
 #[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum BootbootProtocolLevel {
     Static = 1,
     Dynamic = 2,
@@ -42,52 +73,211 @@ impl TryFrom<u8> for BootbootProtocolLevel {
     }
 }
 
-/// BOOTBOOT protocol
-#[repr(transparent)]
-pub struct _BootbootProtocol(u8);
+/// x86_64 specific pointers in the BOOTBOOT header.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BootbootArchX86_64 {
+    pub acpi_ptr: u64,
+    pub smbi_ptr: u64,
+    /// Pointer to the UEFI system table.
+    ///
+    /// The system table, and the runtime services it points to, are only usable by the kernel if
+    /// it keeps the UEFI runtime memory regions mapped.
+    pub efi_ptr: u64,
+    pub mp_ptr: u64,
+    _unused: [u64; 4],
+}
+
+/// Every value in the BOOTBOOT struct other than the memory map.
+///
+/// These are plain values, so the struct can be assembled without UEFI.
+pub struct BootbootInfo<'a> {
+    /// BOOTBOOT protocol byte, which has the protocol level, loader type, and endianness
+    pub protocol: u8,
+    pub framebuffer: &'a Framebuffer,
+    pub numcores: u16,
+    pub bspid: u16,
+    /// Time zone in minutes
+    pub timezone: i16,
+    /// Date and time of boot in BCD; century, year, month, day, hour, minute, second, and a zero
+    /// byte
+    pub datetime: [u8; 8],
+    pub initrd_ptr: u64,
+    pub initrd_size: u64,
+    pub arch: BootbootArchX86_64,
+}
+
+/// The exact bytes that are passed to the kernel: the BOOTBOOT struct followed by the memory map,
+/// and the environment page.
+pub struct BootbootImage {
+    pub info: [u8; BOOTBOOT_INFO_SIZE],
+    pub env: [u8; ENVIRONMENT_PAGE_SIZE],
+}
+
+impl BootbootImage {
+    /// Assembles the BOOTBOOT struct from `info` and `mmap`, and the environment page from `env`.
+    ///
+    /// Every field is little endian. Memory map entries that do not fit in the page after the
+    /// struct are cut off, which [`BootbootMMap::validate`] checks for.
+    pub fn build(info: &BootbootInfo, mmap: &BootbootMMap, env: &Environment) -> Self {
+        let mut image = Self {
+            info: [0; BOOTBOOT_INFO_SIZE],
+            env: [0; ENVIRONMENT_PAGE_SIZE],
+        };
+        let entries = &mmap.entries()[..mmap.entries().len().min(MMAP_MAX_ENTRIES)];
+        let size = BOOTBOOT_STRUCT_SIZE + entries.len() * MMAP_ENTRY_SIZE;
+
+        let mut put = |offset: usize, bytes: &[u8]| {
+            image.info[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(0, &BOOTBOOT_MAGIC);
+        put(SIZE_OFFSET, &(size as u32).to_le_bytes());
+        put(PROTOCOL_OFFSET, &[info.protocol]);
+        put(FB_TYPE_OFFSET, &[info.framebuffer.fb_type as u8]);
+        put(NUMCORES_OFFSET, &info.numcores.to_le_bytes());
+        put(BSPID_OFFSET, &info.bspid.to_le_bytes());
+        put(TIMEZONE_OFFSET, &info.timezone.to_le_bytes());
+        put(DATETIME_OFFSET, &info.datetime);
+        put(INITRD_PTR_OFFSET, &info.initrd_ptr.to_le_bytes());
+        put(INITRD_SIZE_OFFSET, &info.initrd_size.to_le_bytes());
+        put(FB_PTR_OFFSET, &info.framebuffer.ptr.to_le_bytes());
+        put(FB_SIZE_OFFSET, &info.framebuffer.size.to_le_bytes());
+        put(FB_WIDTH_OFFSET, &info.framebuffer.width.to_le_bytes());
+        put(FB_HEIGHT_OFFSET, &info.framebuffer.height.to_le_bytes());
+        put(FB_SCANLINE_OFFSET, &info.framebuffer.scanline.to_le_bytes());
+        let arch = &info.arch;
+        for (i, ptr) in [arch.acpi_ptr, arch.smbi_ptr, arch.efi_ptr, arch.mp_ptr]
+            .iter()
+            .enumerate()
+        {
+            put(ARCH_OFFSET + i * 8, &ptr.to_le_bytes());
+        }
+        for (i, entry) in entries.iter().enumerate() {
+            put(
+                BOOTBOOT_STRUCT_SIZE + i * MMAP_ENTRY_SIZE,
+                &entry.to_bytes(),
+            );
+        }
 
-impl _BootbootProtocol {
-    /// Returns true if this architecture is big endian
-    pub fn _is_big_endian(&self) -> bool {
-        (self.0 & 0x80) == 0x80
+        env.write_page(&mut image.env);
+        image
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FramebufferType;
+    use alloc::{string::String, vec::Vec};
+    use uefi::table::boot::{MemoryDescriptor, MemoryType};
 
-    /// Returns the BOOTBOOT protocol level
-    pub fn _level(&self) -> BootbootProtocolLevel {
-        (self.0 & 0b0011).try_into().unwrap()
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
     }
 
-    /// Returns the BOOTBOOT loader type
-    pub fn _loader_type(&self) -> LoaderType {
-        ((self.0 & 0b0111_1100) >> 2).try_into().unwrap()
+    fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
     }
 
-    /// Returns a BOOTBOOT protocol
-    pub fn _new(protocol: u8) -> Self {
-        Self(protocol)
+    fn image() -> BootbootImage {
+        let framebuffer = Framebuffer {
+            ptr: 0x8000_0000,
+            size: 800 * 600 * 4,
+            width: 800,
+            height: 600,
+            scanline: 800 * 4,
+            fb_type: FramebufferType::Bgra,
+        };
+        let info = BootbootInfo {
+            protocol: 0x02,
+            framebuffer: &framebuffer,
+            numcores: 4,
+            bspid: 1,
+            timezone: -60,
+            datetime: [0x20, 0x22, 0x08, 0x15, 0x12, 0x34, 0x56, 0x00],
+            initrd_ptr: 0x20_0000,
+            initrd_size: 0x5000,
+            arch: BootbootArchX86_64 {
+                acpi_ptr: 0x1000,
+                smbi_ptr: 0x2000,
+                efi_ptr: 0x3000,
+                mp_ptr: 0,
+                ..Default::default()
+            },
+        };
+        let descriptors: Vec<MemoryDescriptor> = [
+            (0x1000, 2, MemoryType::RESERVED),
+            (0x10_0000, 3, MemoryType::CONVENTIONAL),
+        ]
+        .iter()
+        .map(|&(phys_start, page_count, ty)| {
+            let mut desc = MemoryDescriptor::default();
+            desc.ty = ty;
+            desc.phys_start = phys_start;
+            desc.page_count = page_count;
+            desc
+        })
+        .collect();
+        let mmap = BootbootMMap::from_uefi_mmap(descriptors.iter(), false);
+        let env = Environment::from_string(String::from("screen=800x600\n"))
+            .ok()
+            .unwrap();
+        BootbootImage::build(&info, &mmap, &env)
     }
-}
 
-pub struct _BootbootHeader {
-    fb: Framebuffer,
-    initrd: Initrd,
-    protocol: _BootbootProtocol,
-}
+    #[test]
+    fn struct_fields() {
+        let info = image().info;
+        assert_eq!(info[..4], *b"BOOT");
+        assert_eq!(
+            read_u32(&info, SIZE_OFFSET) as usize,
+            BOOTBOOT_STRUCT_SIZE + 2 * MMAP_ENTRY_SIZE
+        );
+        assert_eq!(info[PROTOCOL_OFFSET], 0x02);
+        assert_eq!(info[FB_TYPE_OFFSET], FramebufferType::Bgra as u8);
+        assert_eq!(
+            info[NUMCORES_OFFSET..NUMCORES_OFFSET + 2],
+            4u16.to_le_bytes()
+        );
+        assert_eq!(info[BSPID_OFFSET..BSPID_OFFSET + 2], 1u16.to_le_bytes());
+        assert_eq!(
+            info[TIMEZONE_OFFSET..TIMEZONE_OFFSET + 2],
+            (-60i16).to_le_bytes()
+        );
+        assert_eq!(
+            info[DATETIME_OFFSET..DATETIME_OFFSET + 8],
+            [0x20, 0x22, 0x08, 0x15, 0x12, 0x34, 0x56, 0x00]
+        );
+        assert_eq!(read_u64(&info, INITRD_PTR_OFFSET), 0x20_0000);
+        assert_eq!(read_u64(&info, INITRD_SIZE_OFFSET), 0x5000);
+        assert_eq!(read_u64(&info, FB_PTR_OFFSET), 0x8000_0000);
+        assert_eq!(read_u32(&info, FB_SIZE_OFFSET), 800 * 600 * 4);
+        assert_eq!(read_u32(&info, FB_WIDTH_OFFSET), 800);
+        assert_eq!(read_u32(&info, FB_HEIGHT_OFFSET), 600);
+        assert_eq!(read_u32(&info, FB_SCANLINE_OFFSET), 800 * 4);
+        assert_eq!(read_u64(&info, ARCH_OFFSET), 0x1000);
+        assert_eq!(read_u64(&info, ARCH_OFFSET + 8), 0x2000);
+        assert_eq!(read_u64(&info, ARCH_OFFSET + 16), 0x3000);
+        assert_eq!(read_u64(&info, ARCH_OFFSET + 24), 0);
+    }
 
-impl _BootbootHeader {
-    /// Initialize a BOOTBOOT header.
-    pub fn _new(fb: Framebuffer, initrd: Initrd, protocol: _BootbootProtocol) -> Self {
-        _BootbootHeader {
-            fb,
-            initrd,
-            protocol,
-        }
+    #[test]
+    fn mmap_entries_follow_struct() {
+        let info = image().info;
+        let entry = |i: usize| BOOTBOOT_STRUCT_SIZE + i * MMAP_ENTRY_SIZE;
+        // Each size is in bytes, with the type in the lowest 4 bits
+        assert_eq!(read_u64(&info, entry(0)), 0x1000);
+        assert_eq!(read_u64(&info, entry(0) + 8), 0x2000);
+        assert_eq!(read_u64(&info, entry(1)), 0x10_0000);
+        assert_eq!(read_u64(&info, entry(1) + 8), 0x3001);
+        assert!(info[entry(2)..].iter().all(|&b| b == 0));
     }
 
-    /// Returns the magic numbers in the BOOTBOOT header.
-    ///
-    /// Should always be [66, 79, 79, 84], or "BOOT" when read as a string.
-    pub fn _magic() -> [u8; 4] {
-        [66, 79, 79, 84]
+    #[test]
+    fn environment_page() {
+        let env = image().env;
+        let text = b"screen=800x600\n";
+        assert_eq!(env[..text.len()], *text);
+        assert!(env[text.len()..].iter().all(|&b| b == 0));
     }
 }
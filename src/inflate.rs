@@ -0,0 +1,509 @@
+//! Decompression of gzip files ([RFC 1952](https://www.rfc-editor.org/rfc/rfc1952)) and the
+//! DEFLATE streams inside them ([RFC 1951](https://www.rfc-editor.org/rfc/rfc1951)).
+//!
+//! Huffman codes are decoded one bit at a time, which is slow compared to table-based decoders,
+//! but small and simple enough to be read alongside the RFC.
+
+use alloc::vec::Vec;
+
+use crate::crc32;
+
+/// An error resulting from decompressing a gzip file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InflateError {
+    /// A Huffman code has too many codes of a length or a code length is repeated out of bounds
+    InvalidCodeLengths,
+    /// A code could not be decoded or was decoded to an invalid symbol
+    InvalidCode,
+    /// A block uses the reserved block type
+    InvalidBlockType,
+    /// The CRC-32 in a gzip trailer does not match the decompressed member
+    ChecksumMismatch,
+    /// A back-reference points to before the start of the output
+    InvalidDistance,
+    /// The gzip header has an invalid magic number, compression method, or flags
+    InvalidHeader,
+    /// The length of a stored block does not match its complement
+    InvalidStoredLength,
+    /// The size in a gzip trailer does not match the size of the decompressed member
+    SizeMismatch,
+    /// The decompressed output is larger than the limit it was decompressed with
+    TooLarge,
+    /// The input ended before the last block or gzip trailer
+    UnexpectedEof,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const GZIP_METHOD_DEFLATE: u8 = 8;
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_TRAILER_SIZE: usize = 8;
+
+// Gzip header flags
+const GZIP_FLAG_HCRC: u8 = 1 << 1;
+const GZIP_FLAG_EXTRA: u8 = 1 << 2;
+const GZIP_FLAG_NAME: u8 = 1 << 3;
+const GZIP_FLAG_COMMENT: u8 = 1 << 4;
+const GZIP_FLAG_RESERVED: u8 = 0xe0;
+
+const MAX_CODE_BITS: usize = 15;
+const MAX_LITERAL_CODES: usize = 286;
+const MAX_DISTANCE_CODES: usize = 30;
+const FIXED_LITERAL_CODES: usize = 288;
+const CODE_LENGTH_CODES: usize = 19;
+const END_OF_BLOCK: u16 = 256;
+
+/// Base lengths for length symbols 257 to 285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+
+/// Extra bits for length symbols 257 to 285.
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances for distance symbols 0 to 29.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+/// Extra bits for distance symbols 0 to 29.
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order that code length code lengths are stored in a dynamic block header.
+const CODE_LENGTH_ORDER: [usize; CODE_LENGTH_CODES] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Returns true if `data` starts with the gzip magic number.
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+/// Decompresses a gzip file.
+///
+/// If the file contains multiple gzip members, their output is concatenated. Like `gzip -d`, any
+/// bytes after the last member that do not start another member are ignored.
+///
+/// Decompression stops as soon as the output would be larger than `limit` bytes, so that a small
+/// file cannot expand to fill memory.
+///
+/// # Errors
+///
+/// Returns `InflateError::TooLarge` if the output is larger than `limit` bytes. Returns another
+/// error if `data` does not start with a gzip member or if any member is corrupt, including when a
+/// member's decompressed output does not match the CRC-32 or size in its trailer.
+pub fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    if !is_gzip(data) {
+        return Err(InflateError::InvalidHeader);
+    }
+
+    let mut output = Vec::new();
+    let mut member = data;
+    while is_gzip(member) {
+        let header_size = gzip_header_size(member)?;
+        let output_start = output.len();
+        let deflate_size = inflate(&member[header_size..], &mut output, limit)?;
+
+        // Check the member's output against the CRC-32 and size (modulo 2^32) in the trailer
+        let trailer_start = header_size + deflate_size;
+        let trailer = member
+            .get(trailer_start..trailer_start + GZIP_TRAILER_SIZE)
+            .ok_or(InflateError::UnexpectedEof)?;
+        let member_output = &output[output_start..];
+        if crc32(member_output) != u32::from_le_bytes(trailer[..4].try_into().unwrap()) {
+            return Err(InflateError::ChecksumMismatch);
+        }
+        if member_output.len() as u32 != u32::from_le_bytes(trailer[4..].try_into().unwrap()) {
+            return Err(InflateError::SizeMismatch);
+        }
+
+        // Skip the trailer to get to the next member
+        member = &member[trailer_start + GZIP_TRAILER_SIZE..];
+    }
+
+    Ok(output)
+}
+
+/// Returns the size of the gzip header at the start of `member`, including optional fields.
+///
+/// # Errors
+///
+/// Returns an error if the header is not a DEFLATE gzip header or is cut off.
+fn gzip_header_size(member: &[u8]) -> Result<usize, InflateError> {
+    if member.len() < GZIP_HEADER_SIZE {
+        return Err(InflateError::UnexpectedEof);
+    }
+    let flags = member[3];
+    if member[2] != GZIP_METHOD_DEFLATE || flags & GZIP_FLAG_RESERVED != 0 {
+        return Err(InflateError::InvalidHeader);
+    }
+
+    let mut size = GZIP_HEADER_SIZE;
+
+    // Skip extra field, which is prefixed with its length
+    if flags & GZIP_FLAG_EXTRA != 0 {
+        let extra_len = member
+            .get(size..size + 2)
+            .ok_or(InflateError::UnexpectedEof)?;
+        size += 2 + u16::from_le_bytes([extra_len[0], extra_len[1]]) as usize;
+    }
+
+    // Skip null-terminated file name and comment
+    for flag in [GZIP_FLAG_NAME, GZIP_FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let len = member
+                .get(size..)
+                .and_then(|field| field.iter().position(|&b| b == 0))
+                .ok_or(InflateError::UnexpectedEof)?;
+            size += len + 1;
+        }
+    }
+
+    // Skip header CRC
+    if flags & GZIP_FLAG_HCRC != 0 {
+        size += 2;
+    }
+
+    if size > member.len() {
+        return Err(InflateError::UnexpectedEof);
+    }
+    Ok(size)
+}
+
+/// Decompresses the DEFLATE stream at the start of `data`, appending the result to `output`.
+///
+/// Back-references can only point to data appended by this stream.
+///
+/// Returns the number of bytes the stream takes up in `data`.
+///
+/// # Errors
+///
+/// Returns `InflateError::TooLarge` if `output` would grow past `limit` bytes. Returns another
+/// error if the stream is corrupt or ends before its last block.
+pub fn inflate(data: &[u8], output: &mut Vec<u8>, limit: usize) -> Result<usize, InflateError> {
+    let mut reader = BitReader::new(data);
+    let start = output.len();
+
+    loop {
+        let last_block = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => inflate_stored(&mut reader, output, limit)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_codes(&mut reader, output, start, limit, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_codes(&mut reader, output, start, limit, &literals, &distances)?;
+            }
+            _ => return Err(InflateError::InvalidBlockType),
+        }
+        if last_block {
+            break;
+        }
+    }
+
+    // The stream ends at the next byte boundary
+    reader.align();
+    Ok(reader.pos / 8)
+}
+
+/// Copies an uncompressed block to `output`, unless `output` would grow past `limit` bytes.
+fn inflate_stored(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), InflateError> {
+    // Length and its complement start at the next byte boundary
+    reader.align();
+    let len = reader.bits(16)?;
+    let len_complement = reader.bits(16)?;
+    if len != !len_complement & 0xffff {
+        return Err(InflateError::InvalidStoredLength);
+    }
+
+    let start = reader.pos / 8;
+    let block = reader
+        .data
+        .get(start..start + len as usize)
+        .ok_or(InflateError::UnexpectedEof)?;
+    if block.len() > limit - output.len() {
+        return Err(InflateError::TooLarge);
+    }
+    output.extend_from_slice(block);
+    reader.pos += block.len() * 8;
+
+    Ok(())
+}
+
+/// Decodes a block of Huffman codes to `output` until the end of block symbol.
+///
+/// `start` is the index of `output` that back-references cannot go past, and `output` cannot grow
+/// past `limit` bytes.
+fn inflate_codes(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    start: usize,
+    limit: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), InflateError> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        match symbol {
+            0..=255 if output.len() >= limit => return Err(InflateError::TooLarge),
+            0..=255 => output.push(symbol as u8),
+            END_OF_BLOCK => return Ok(()),
+            257..=285 => {
+                // Get length of back-reference
+                let index = (symbol - 257) as usize;
+                let len = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index])? as usize;
+
+                // Get distance of back-reference
+                let index = distances.decode(reader)? as usize;
+                if index >= MAX_DISTANCE_CODES {
+                    return Err(InflateError::InvalidDistance);
+                }
+                let distance =
+                    DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index])? as usize;
+                if distance > output.len() - start {
+                    return Err(InflateError::InvalidDistance);
+                }
+                if len > limit - output.len() {
+                    return Err(InflateError::TooLarge);
+                }
+
+                // Copy byte by byte, as the back-reference may overlap with the bytes it creates
+                let from = output.len() - distance;
+                for i in from..from + len {
+                    output.push(output[i]);
+                }
+            }
+            _ => return Err(InflateError::InvalidCode),
+        }
+    }
+}
+
+/// Returns the literal/length and distance codes used by fixed Huffman blocks.
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0; FIXED_LITERAL_CODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let literals = Huffman::new(&lengths).unwrap();
+    let distances = Huffman::new(&[5; MAX_DISTANCE_CODES]).unwrap();
+
+    (literals, distances)
+}
+
+/// Reads the literal/length and distance codes from the header of a dynamic Huffman block.
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    if literal_count > MAX_LITERAL_CODES || distance_count > MAX_DISTANCE_CODES {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    // Read the code that the code lengths are compressed with
+    let mut lengths = [0; MAX_LITERAL_CODES + MAX_DISTANCE_CODES];
+    for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+        lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&lengths[..CODE_LENGTH_CODES])?;
+    lengths[..CODE_LENGTH_CODES].fill(0);
+
+    // Read literal/length and distance code lengths, which are stored together
+    let total_count = literal_count + distance_count;
+    let mut index = 0;
+    while index < total_count {
+        let symbol = code_lengths.decode(reader)?;
+        let (length, repeat) = match symbol {
+            0..=15 => {
+                lengths[index] = symbol as u8;
+                index += 1;
+                continue;
+            }
+            // Repeat previous length
+            16 if index > 0 => (lengths[index - 1], 3 + reader.bits(2)?),
+            16 => return Err(InflateError::InvalidCodeLengths),
+            // Repeat zero
+            17 => (0, 3 + reader.bits(3)?),
+            18 => (0, 11 + reader.bits(7)?),
+            _ => return Err(InflateError::InvalidCode),
+        };
+        let repeat = repeat as usize;
+        if index + repeat > total_count {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+        lengths[index..index + repeat].fill(length);
+        index += repeat;
+    }
+
+    // A block cannot end without an end of block code
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    let literals = Huffman::new(&lengths[..literal_count])?;
+    let distances = Huffman::new(&lengths[literal_count..total_count])?;
+
+    Ok((literals, distances))
+}
+
+/// Reads bits from a DEFLATE stream, starting from the least significant bit of each byte.
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Reads `count` bits as a little-endian number.
+    fn bits(&mut self, count: u8) -> Result<u32, InflateError> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self
+                .data
+                .get(self.pos / 8)
+                .ok_or(InflateError::UnexpectedEof)?;
+            value |= ((*byte as u32 >> (self.pos % 8)) & 1) << i;
+            self.pos += 1;
+        }
+
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary.
+    fn align(&mut self) {
+        self.pos = (self.pos + 7) & !7;
+    }
+}
+
+/// A canonical Huffman code.
+///
+/// Stores the number of codes of each length and the symbols sorted by code, which is enough to
+/// decode a canonical code bit by bit.
+struct Huffman {
+    counts: [u16; MAX_CODE_BITS + 1],
+    symbols: [u16; FIXED_LITERAL_CODES],
+}
+
+impl Huffman {
+    /// Builds a Huffman code from the code length of each symbol, where 0 means unused.
+    ///
+    /// Incomplete codes are allowed, but decoding an unused code returns an error.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are more codes of a length than can fit.
+    fn new(lengths: &[u8]) -> Result<Self, InflateError> {
+        let mut counts = [0; MAX_CODE_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+
+        // Ensure the code is not over-subscribed
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(InflateError::InvalidCodeLengths);
+            }
+        }
+
+        // Sort symbols by code length, then by symbol
+        let mut offsets = [0; MAX_CODE_BITS + 1];
+        for len in 1..MAX_CODE_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = [0; FIXED_LITERAL_CODES];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Ok(Self { counts, symbols })
+    }
+
+    /// Decodes the next symbol from `reader`.
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        // First code of the current length, and the index of its symbol
+        let mut first = 0;
+        let mut index = 0;
+        let mut code = 0;
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err(InflateError::InvalidCode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// `b"a"` repeated 1000 times, compressed with a fixed Huffman block.
+    const REPEATED: [u8; 29] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4b, 0x4c, 0x1c, 0x05, 0xa3,
+        0x60, 0x14, 0x0c, 0x77, 0x00, 0x00, 0x03, 0xda, 0x38, 0x9a, 0xe8, 0x03, 0x00, 0x00,
+    ];
+
+    /// `b"hello stored block"` in a stored block.
+    const STORED: [u8; 41] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x01, 0x12, 0x00, 0xed, 0xff,
+        0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x20, 0x62, 0x6c,
+        0x6f, 0x63, 0x6b, 0x0a, 0xb5, 0xa4, 0xa6, 0x12, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn decompresses_members() {
+        assert_eq!(gunzip(&REPEATED, usize::MAX), Ok(vec![b'a'; 1000]));
+        assert_eq!(
+            gunzip(&STORED, usize::MAX),
+            Ok(b"hello stored block".to_vec())
+        );
+
+        let mut members = STORED.to_vec();
+        members.extend_from_slice(&REPEATED);
+        let output = gunzip(&members, usize::MAX).unwrap();
+        assert_eq!(output.len(), 18 + 1000);
+        assert!(output.starts_with(b"hello stored block"));
+    }
+
+    #[test]
+    fn stops_at_limit() {
+        assert_eq!(gunzip(&REPEATED, 1000).map(|output| output.len()), Ok(1000));
+        assert_eq!(gunzip(&REPEATED, 999), Err(InflateError::TooLarge));
+        assert_eq!(gunzip(&REPEATED, 0), Err(InflateError::TooLarge));
+        assert_eq!(gunzip(&STORED, 18).map(|output| output.len()), Ok(18));
+        assert_eq!(gunzip(&STORED, 17), Err(InflateError::TooLarge));
+
+        // The limit is for the output of every member combined
+        let mut members = REPEATED.to_vec();
+        members.extend_from_slice(&REPEATED);
+        assert_eq!(gunzip(&members, 1999), Err(InflateError::TooLarge));
+    }
+}
@@ -1,75 +1,367 @@
-use alloc::vec::Vec;
+use alloc::{format, string::String, vec::Vec};
 use log::debug;
 use uefi::{
-    proto::media::file::{Directory, FileAttribute, FileMode, RegularFile},
-    Result as UefiResult,
+    prelude::Status,
+    proto::media::file::{Directory, FileAttribute, FileMode},
+    Error as UefiError, Result as UefiResult,
 };
 
+mod cpio;
 mod ustar;
 
-use crate::{open_file, read_to_vec};
-use ustar::read_ustar;
+use crate::{
+    crc32,
+    inflate::{gunzip, is_gzip},
+    open_dir, open_file, read_to_vec_with_progress, BOOT_DIR,
+};
+use cpio::{is_cpio_newc, read_cpio_newc};
+use ustar::{is_ustar, read_ustar};
+
+/// An error resulting from reading a file from initrd.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InitrdError {
+    FileNotFound,
+    FileTruncated,
+    UnknownFormat,
+}
+
+/// The file on the boot partition that an initrd was read from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InitrdSource {
+    /// `BOOTBOOT/INITRD`
+    InitrdFile,
+    /// `BOOTBOOT/X86_64`
+    X86_64File,
+    /// Files listed in the `initrd=` environment key
+    Modules,
+}
+
+/// The file system format of an initrd.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InitrdFormat {
+    Ustar,
+    CpioNewc,
+    Unknown,
+}
+
+impl InitrdFormat {
+    /// Detects the file system format of `initrd_raw`.
+    pub fn detect(initrd_raw: &[u8]) -> Self {
+        if is_ustar(initrd_raw) {
+            Self::Ustar
+        } else if is_cpio_newc(initrd_raw) {
+            Self::CpioNewc
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+/// Files in the `BOOTBOOT` directory that are tried in order when searching for an initrd.
+const INITRD_FILES: [(&str, InitrdSource); 2] = [
+    ("INITRD", InitrdSource::InitrdFile),
+    ("X86_64", InitrdSource::X86_64File),
+];
 
 /// BOOTBOOT initrd.
 #[repr(C)]
 #[derive(Clone)]
 pub struct Initrd {
     initrd_raw: Vec<u8>,
+    source: InitrdSource,
+    format: InitrdFormat,
+    compressed: bool,
 }
 
 impl Initrd {
     /// Reads initrd file from boot partition.
     ///
-    /// The following files are read in order until one is a valid file:
+    /// The following files are read in order until one is a valid initrd:
     ///
     /// * `BOOTBOOT/INITRD`
     /// * `BOOTBOOT/X86_64`
     ///
+    /// If a file is gzip compressed, it is decompressed after being read. A file is only used if
+    /// it is not empty and its file system format is recognized; otherwise the next file is tried.
+    ///
     /// # Errors
     ///
-    /// Returns an error if initrd file could not be read to memory.
+    /// Returns an error if none of the files are a valid initrd.
     pub fn from_disk(bootdir: &mut Directory) -> UefiResult<Self> {
-        // Initrd file
-        let mut initrd_file = get_initrd_file(bootdir)?;
+        Self::search_dir(bootdir, BOOT_DIR)
+    }
+
+    /// Reads initrd file from the subdirectory `dirname` of the `BOOTBOOT` directory, trying the
+    /// same files as [`Initrd::from_disk`].
+    ///
+    /// Nested subdirectories are separated with `/`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the subdirectory could not be opened or none of its files are a valid
+    /// initrd.
+    pub fn from_subdir(bootdir: &mut Directory, dirname: &str) -> UefiResult<Self> {
+        // UEFI paths are separated with backslashes
+        let mut dir = open_dir(bootdir, &dirname.replace('/', "\\"))?;
+        Self::search_dir(&mut dir, &format!("{}/{}", BOOT_DIR, dirname))
+    }
+
+    /// Reads the first valid initrd file in `dir`, which is at `path` on the boot partition.
+    fn search_dir(dir: &mut Directory, path: &str) -> UefiResult<Self> {
+        for (filename, source) in INITRD_FILES {
+            // Try to read initrd
+            let (initrd_raw, compressed) = match open_initrd_file(dir, path, filename) {
+                Ok(initrd) => initrd,
+                Err(e) => {
+                    debug!(
+                        "Could not read initrd '{}/{}': {:?}",
+                        path,
+                        filename,
+                        e.status()
+                    );
+                    continue;
+                }
+            };
+
+            // Detect file system, skipping files that are not a valid initrd
+            let format = InitrdFormat::detect(&initrd_raw);
+            if initrd_raw.is_empty() || format == InitrdFormat::Unknown {
+                debug!(
+                    "Initrd '{}/{}' is empty or has an unknown format",
+                    path, filename
+                );
+                continue;
+            }
+            debug!("Found initrd in '{}/{}'", path, filename);
+
+            return Ok(Self {
+                initrd_raw,
+                source,
+                format,
+                compressed,
+            });
+        }
+
+        Err(UefiError::from(Status::NOT_FOUND))
+    }
+
+    /// Reads and combines initrd modules from the `BOOTBOOT` directory of the boot partition.
+    ///
+    /// Each module is a ustar archive that may be gzip compressed. The archives are concatenated in
+    /// order, so if the same file is in multiple modules, the file in the first module is used.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are no modules, or if a module could not be read, could not be
+    /// decompressed, or is not a ustar archive.
+    pub fn from_modules(bootdir: &mut Directory, modules: &[String]) -> UefiResult<Self> {
+        if modules.is_empty() {
+            return Err(UefiError::from(Status::NOT_FOUND));
+        }
+
+        let mut modules_raw = Vec::new();
+        let mut compressed = false;
+        for module in modules {
+            let (module_raw, module_compressed) = open_initrd_file(bootdir, BOOT_DIR, module)?;
+            debug!("Found initrd module in '{}/{}'", BOOT_DIR, module);
+            modules_raw.push(module_raw);
+            compressed |= module_compressed;
+        }
 
-        // Read initrd
-        let initrd_raw = read_to_vec(&mut initrd_file)?;
+        let initrd_raw = concat_modules(
+            modules
+                .iter()
+                .map(String::as_str)
+                .zip(modules_raw.iter().map(Vec::as_slice)),
+        )
+        .map_err(|module| {
+            debug!("Initrd module '{}' is not a ustar archive", module);
+            UefiError::from(Status::UNSUPPORTED)
+        })?;
+
+        Ok(Self {
+            initrd_raw,
+            source: InitrdSource::Modules,
+            format: InitrdFormat::Ustar,
+            compressed,
+        })
+    }
 
-        Ok(Self { initrd_raw })
+    /// Returns the file system format of this initrd.
+    pub fn format(&self) -> InitrdFormat {
+        self.format
     }
 
-    /// Tries to read `filename` from initrd using various file system types.
+    /// Tries to read `filename` from initrd using its file system format.
     ///
-    /// Returns `None` if `filename` does not exist.
+    /// Currently the supported file systems are ustar and cpio newc.
+    ///
+    /// # Errors
+    ///
+    /// * `InitrdError::FileNotFound`: `filename` does not exist
+    /// * `InitrdError::FileTruncated`: `filename` exists, but goes past the end of initrd
+    /// * `InitrdError::UnknownFormat`: The file system format is unknown
+    pub fn read_file(&self, filename: &str) -> Result<&[u8], InitrdError> {
+        match self.format {
+            InitrdFormat::Ustar => read_ustar(&self.initrd_raw, filename),
+            InitrdFormat::CpioNewc => read_cpio_newc(&self.initrd_raw, filename),
+            InitrdFormat::Unknown => Err(InitrdError::UnknownFormat),
+        }
+    }
+
+    /// Reads `filename` from initrd like [`Initrd::read_file`], but returns a copy of its contents
+    /// that can outlive this initrd.
     ///
-    /// Currently the only supported file system is ustar.
-    pub fn read_file(&self, filename: &str) -> Option<&[u8]> {
-        read_ustar(&self.initrd_raw, filename)
+    /// Returns `None` if [`Initrd::read_file`] would return an error.
+    pub fn read_file_owned(&self, filename: &str) -> Option<Vec<u8>> {
+        self.read_file(filename).ok().map(<[u8]>::to_vec)
+    }
+
+    /// Returns the contents of the initrd file.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.initrd_raw
+    }
+
+    /// Returns a pointer to the start of the initrd file in memory.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.initrd_raw.as_ptr()
     }
 
     /// Returns the initrd file's size in bytes.
     pub fn size(&self) -> usize {
         self.initrd_raw.len()
     }
+
+    /// Returns the file that this initrd was read from.
+    pub fn source(&self) -> InitrdSource {
+        self.source
+    }
+
+    /// Returns the CRC-32 checksum of this initrd.
+    ///
+    /// The checksum is of the decompressed initrd, so for a gzip compressed file it matches the
+    /// checksum stored in the gzip trailer rather than the checksum of the file itself.
+    pub fn crc32(&self) -> u32 {
+        crc32(&self.initrd_raw)
+    }
+
+    /// Returns true if the initrd file was gzip compressed.
+    pub fn compressed(&self) -> bool {
+        self.compressed
+    }
+}
+
+#[cfg(test)]
+impl Initrd {
+    /// Creates a ustar initrd with `files`, for testing code that reads from initrd.
+    pub fn from_files(files: &[(&str, &[u8])]) -> Self {
+        Self {
+            initrd_raw: ustar::archive(files),
+            source: InitrdSource::InitrdFile,
+            format: InitrdFormat::Ustar,
+            compressed: false,
+        }
+    }
+}
+
+/// Concatenates the contents of each module in `modules`, which are pairs of a module's name and
+/// its contents, into one ustar archive.
+///
+/// Files are found by searching the archive from its start, so if the same file is in multiple
+/// modules, the file in the first module is used.
+///
+/// # Errors
+///
+/// Returns the name of the first module that is not a ustar archive, as only ustar archives can be
+/// concatenated.
+fn concat_modules<'a, 'b>(
+    modules: impl IntoIterator<Item = (&'a str, &'b [u8])>,
+) -> Result<Vec<u8>, &'a str> {
+    let mut initrd_raw = Vec::new();
+    for (module, module_raw) in modules {
+        if InitrdFormat::detect(module_raw) != InitrdFormat::Ustar {
+            return Err(module);
+        }
+        initrd_raw.extend_from_slice(module_raw);
+    }
+    Ok(initrd_raw)
 }
 
-/// Searches `BOOTBOOT/INITRD` and `BOOTBOOT/X86_64` for initrd file.
+/// Opens and reads `<filename>` in `dir`, decompressing it if it is gzip compressed.
+///
+/// `path` is the location of `dir` on the boot partition, which is only used for logging.
+///
+/// Returns the contents along with whether the file was compressed.
 ///
 /// # Errors
 ///
-/// Returns error if intird could not be found in either file.
-fn get_initrd_file(bootdir: &mut Directory) -> UefiResult<RegularFile> {
-    // Try to open BOOTBOOT/INITRD
-    let initrd_file = open_file(bootdir, "INITRD", FileMode::Read, FileAttribute::empty());
-    if initrd_file.is_ok() {
-        debug!("Found initrd in 'BOOTBOOT/INITRD'");
-        return initrd_file;
+/// Returns an error if the file could not be opened, read, or decompressed.
+fn open_initrd_file(
+    dir: &mut Directory,
+    path: &str,
+    filename: &str,
+) -> UefiResult<(Vec<u8>, bool)> {
+    let mut initrd_file = open_file(dir, filename, FileMode::Read, FileAttribute::empty())?;
+    // Log progress at every quarter, as large initrds take a while to read
+    let mut quarters_logged = 0;
+    let initrd_raw = read_to_vec_with_progress(&mut initrd_file, |bytes_read, size| {
+        let quarters = bytes_read * 4 / size;
+        if quarters > quarters_logged {
+            quarters_logged = quarters;
+            debug!("Read {}% of initrd '{}/{}'", quarters * 25, path, filename);
+        }
+    })?;
+
+    if !is_gzip(&initrd_raw) {
+        return Ok((initrd_raw, false));
+    }
+    // Unlike the kernel, the initrd has no size limit
+    let initrd_raw = gunzip(&initrd_raw, usize::MAX).map_err(|e| {
+        debug!("Could not decompress initrd: {:?}", e);
+        UefiError::from(Status::VOLUME_CORRUPTED)
+    })?;
+
+    Ok((initrd_raw, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn concatenated_modules_prefer_first_module() {
+        let base = ustar::archive(&[("sys/config", b"nosmp=1\n"), ("sys/core", b"kernel 1")]);
+        let extra = ustar::archive(&[("sys/core", b"kernel 2"), ("etc/motd", b"hello")]);
+        let initrd_raw = concat_modules([("BASE", &base[..]), ("EXTRA", &extra[..])]).unwrap();
+
+        // Files are found in either module, and the first module wins
+        assert_eq!(read_ustar(&initrd_raw, "sys/config"), Ok(&b"nosmp=1\n"[..]));
+        assert_eq!(read_ustar(&initrd_raw, "etc/motd"), Ok(&b"hello"[..]));
+        assert_eq!(read_ustar(&initrd_raw, "sys/core"), Ok(&b"kernel 1"[..]));
+
+        let initrd_raw = concat_modules([("EXTRA", &extra[..]), ("BASE", &base[..])]).unwrap();
+        assert_eq!(read_ustar(&initrd_raw, "sys/core"), Ok(&b"kernel 2"[..]));
+    }
+
+    #[test]
+    fn concatenated_modules_must_be_ustar() {
+        let base = ustar::archive(&[("sys/core", b"kernel")]);
+        let other = vec![0; 1024];
+        assert_eq!(
+            concat_modules([("BASE", &base[..]), ("OTHER", &other[..])]),
+            Err("OTHER")
+        );
     }
 
-    // Try to open BOOTBOOT/X86_64
-    let initrd_file = open_file(bootdir, "X86_64", FileMode::Read, FileAttribute::empty());
-    if initrd_file.is_ok() {
-        debug!("Found initrd in 'BOOTBOOT/X86_64'");
+    #[test]
+    fn read_file_owned() {
+        let initrd = Initrd::from_files(&[("sys/core", b"kernel")]);
+        let kernel = initrd.read_file_owned("sys/core");
+        drop(initrd);
+        assert_eq!(kernel.as_deref(), Some(&b"kernel"[..]));
+
+        let initrd = Initrd::from_files(&[("sys/core", b"kernel")]);
+        assert_eq!(initrd.read_file_owned("sys/config"), None);
     }
-    initrd_file
 }
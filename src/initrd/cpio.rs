@@ -0,0 +1,175 @@
+use core::str;
+
+use crate::InitrdError;
+
+const HEADER_SIZE: usize = 110;
+const FIELD_SIZE: usize = 8;
+const FILE_SIZE_OFFSET: usize = 54;
+const NAME_SIZE_OFFSET: usize = 94;
+/// Magic of newc archives, and of newc archives with checksums
+const MAGICS: [&[u8]; 2] = [b"070701", b"070702"];
+const TRAILER: &str = "TRAILER!!!";
+const ALIGNMENT: usize = 4;
+
+/// Returns true if the first header in `initrd` has the cpio newc magic.
+pub fn is_cpio_newc(initrd: &[u8]) -> bool {
+    MAGICS.contains(&initrd.get(..MAGICS[0].len()).unwrap_or(&[]))
+}
+
+/// Tries to read `filename` from initrd; a cpio newc archive.
+///
+/// Archive paths that start with `./` match `filename` without that prefix, as archives are often
+/// created with `find . | cpio`.
+///
+/// # Errors
+///
+/// * `InitrdError::FileNotFound`: `filename` is not a file in the archive
+/// * `InitrdError::FileTruncated`: `filename` is in the archive, but its size goes past the end of
+///   the archive
+pub fn read_cpio_newc<'a>(initrd: &'a [u8], filename: &str) -> Result<&'a [u8], InitrdError> {
+    let mut idx = 0;
+
+    while idx + HEADER_SIZE <= initrd.len() {
+        // Get header, stopping at anything that is not a newc header
+        let header = &initrd[idx..idx + HEADER_SIZE];
+        if !is_cpio_newc(header) {
+            break;
+        }
+        let (file_size, name_size) = match (
+            read_hex_field(header, FILE_SIZE_OFFSET),
+            read_hex_field(header, NAME_SIZE_OFFSET),
+        ) {
+            (Some(file_size), Some(name_size)) => (file_size, name_size),
+            _ => break,
+        };
+
+        // Get filename, which includes a null terminator
+        // The header and filename are padded to a multiple of 4 bytes
+        let name_start = idx + HEADER_SIZE;
+        let name = match initrd.get(name_start..name_start + name_size) {
+            Some(name) => name,
+            None => break,
+        };
+        let name = str::from_utf8(name).unwrap_or("").trim_end_matches('\0');
+        let data_start = align_up(name_start + name_size);
+
+        // The last entry is always the trailer
+        if name == TRAILER {
+            break;
+        }
+
+        // Return file contents if names match and file has valid size
+        if filename == name.strip_prefix("./").unwrap_or(name) {
+            return initrd
+                .get(data_start..data_start + file_size)
+                .ok_or(InitrdError::FileTruncated);
+        }
+
+        // Move index past file data, which is also padded to a multiple of 4 bytes
+        idx = align_up(data_start + file_size);
+    }
+
+    Err(InitrdError::FileNotFound)
+}
+
+/// Parses the 8 digit hexadecimal field at `offset` in a newc header.
+///
+/// Returns `None` if the field contains a non-hexadecimal character.
+fn read_hex_field(header: &[u8], offset: usize) -> Option<usize> {
+    let hex_str = str::from_utf8(&header[offset..offset + FIELD_SIZE]).ok()?;
+    usize::from_str_radix(hex_str, 16).ok()
+}
+
+/// Rounds `idx` up to the next multiple of 4.
+fn align_up(idx: usize) -> usize {
+    (idx + ALIGNMENT - 1) & !(ALIGNMENT - 1)
+}
+
+/// Creates a cpio newc archive with `files`, for testing code that reads from initrd.
+#[cfg(test)]
+pub fn archive(files: &[(&str, &[u8])]) -> alloc::vec::Vec<u8> {
+    let mut archive = alloc::vec::Vec::new();
+    let trailer: (&str, &[u8]) = (TRAILER, &[]);
+    for (ino, (name, data)) in files.iter().chain([&trailer]).enumerate() {
+        // Fields are inode, mode, uid, gid, links, mtime, file size, major, minor, rmajor, rminor,
+        // name size, and checksum
+        let header = alloc::format!(
+            "{}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            "070701",
+            ino,
+            0o100644,
+            0,
+            0,
+            1,
+            0,
+            data.len(),
+            0,
+            0,
+            0,
+            0,
+            name.len() + 1,
+            0
+        );
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align_up(archive.len()), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align_up(archive.len()), 0);
+    }
+    archive
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_files() {
+        let archive = archive(&[
+            ("sys", b""),
+            ("sys/config", b"screen=800x600\n"),
+            ("./sys/core", b"\x7fELF kernel"),
+            ("usr/share/fonts/consolefonts/default.psf", b"font"),
+            ("a", b"abc"),
+        ]);
+        assert!(is_cpio_newc(&archive));
+        assert_eq!(
+            read_cpio_newc(&archive, "sys/config"),
+            Ok(&b"screen=800x600\n"[..])
+        );
+        assert_eq!(
+            read_cpio_newc(&archive, "sys/core"),
+            Ok(&b"\x7fELF kernel"[..])
+        );
+        assert_eq!(
+            read_cpio_newc(&archive, "usr/share/fonts/consolefonts/default.psf"),
+            Ok(&b"font"[..])
+        );
+        assert_eq!(read_cpio_newc(&archive, "a"), Ok(&b"abc"[..]));
+        assert_eq!(
+            read_cpio_newc(&archive, "usr/share/fonts"),
+            Err(InitrdError::FileNotFound)
+        );
+    }
+
+    #[test]
+    fn stops_at_trailer() {
+        let mut archive = archive(&[("sys/config", b"")]);
+        archive.extend_from_slice(&super::archive(&[("sys/core", b"kernel")]));
+        assert_eq!(
+            read_cpio_newc(&archive, "sys/core"),
+            Err(InitrdError::FileNotFound)
+        );
+    }
+
+    #[test]
+    fn truncated_file() {
+        let mut archive = archive(&[("sys/core", &[0; 64])]);
+        archive.truncate(HEADER_SIZE + 12 + 32);
+        assert_eq!(
+            read_cpio_newc(&archive, "sys/core"),
+            Err(InitrdError::FileTruncated)
+        );
+    }
+}
@@ -1,15 +1,27 @@
 use core::str;
 
+use crate::InitrdError;
+
 const BLOCK_SIZE: usize = 512;
 const NAME_SIZE: usize = 100;
 const SIZE_OFFSET: usize = 124;
 const SIZE_SIZE: usize = 12;
+const MAGIC_OFFSET: usize = 257;
+const MAGIC: &[u8] = b"ustar";
+
+/// Returns true if the first header in `initrd` has the ustar magic.
+pub fn is_ustar(initrd: &[u8]) -> bool {
+    initrd.get(MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()) == Some(MAGIC)
+}
 
 /// Tries to read `filename` from initrd; a tar archive.
 ///
-/// Returns `None` if initrd is not a valid tar archive or if `filename` is not a valid file in the
-/// archive.
-pub fn read_ustar<'a>(initrd: &'a [u8], filename: &str) -> Option<&'a [u8]> {
+/// # Errors
+///
+/// * `InitrdError::FileNotFound`: `filename` is not a file in the archive
+/// * `InitrdError::FileTruncated`: `filename` is in the archive, but its size goes past the end of
+///   the archive
+pub fn read_ustar<'a>(initrd: &'a [u8], filename: &str) -> Result<&'a [u8], InitrdError> {
     let mut idx = 0;
 
     while idx < initrd.len() && idx + BLOCK_SIZE <= initrd.len() {
@@ -18,7 +30,7 @@ pub fn read_ustar<'a>(initrd: &'a [u8], filename: &str) -> Option<&'a [u8]> {
         idx += BLOCK_SIZE;
         // Get file size octal string
         let file_size = read_octal_size(
-            header[SIZE_OFFSET..SIZE_OFFSET + SIZE_SIZE - 1]
+            header[SIZE_OFFSET..SIZE_OFFSET + SIZE_SIZE]
                 .try_into()
                 .unwrap(),
         );
@@ -36,8 +48,11 @@ pub fn read_ustar<'a>(initrd: &'a [u8], filename: &str) -> Option<&'a [u8]> {
         let name = name.unwrap().trim_end_matches('\0');
 
         // Return file contents if names match and file has valid size
-        if filename == name && idx + file_size <= initrd.len() {
-            return Some(&initrd[idx..idx + file_size]);
+        if filename == name {
+            if idx + file_size > initrd.len() {
+                return Err(InitrdError::FileTruncated);
+            }
+            return Ok(&initrd[idx..idx + file_size]);
         }
 
         // Move index past file data
@@ -50,29 +65,96 @@ pub fn read_ustar<'a>(initrd: &'a [u8], filename: &str) -> Option<&'a [u8]> {
         idx += file_size + block_padding;
     }
 
-    None
+    Err(InitrdError::FileNotFound)
 }
 
-fn pow(x: usize, n: usize) -> usize {
-    if n == 0 {
-        return 1;
+/// Parses the octal size field of a tar header.
+///
+/// Leading and trailing whitespace and null characters are ignored, as tar implementations differ
+/// in how the field is padded and terminated (e.g. `"00000004000\0"` or `"00000004000 "`).
+///
+/// Returns `None` if the field is empty or contains a non-octal character.
+fn read_octal_size(octal_str: [u8; SIZE_SIZE]) -> Option<usize> {
+    let octal_str = str::from_utf8(&octal_str[..]).ok()?;
+    let octal_str = octal_str.trim_matches(|c: char| c.is_ascii_whitespace() || c == '\0');
+    if octal_str.is_empty() {
+        return None;
     }
 
-    let mut result = x;
-    for _ in 2..=n {
-        result *= x;
+    let mut size: usize = 0;
+    for c in octal_str.chars() {
+        let digit = c.to_digit(8)? as usize;
+        size = size.checked_mul(8)?.checked_add(digit)?;
     }
-    result
+
+    Some(size)
 }
 
-fn read_octal_size(octal_str: [u8; SIZE_SIZE - 1]) -> Option<usize> {
-    let mut size = 0;
-    let octal_str = str::from_utf8(&octal_str[..]).ok()?;
+/// Creates a ustar archive with `files`, for testing code that reads from initrd.
+#[cfg(test)]
+pub fn archive(files: &[(&str, &[u8])]) -> alloc::vec::Vec<u8> {
+    let mut archive = alloc::vec::Vec::new();
+    for (name, data) in files {
+        let mut header = [0; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = alloc::format!("{:011o}\0", data.len());
+        header[SIZE_OFFSET..SIZE_OFFSET + SIZE_SIZE].copy_from_slice(size.as_bytes());
+        header[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()].copy_from_slice(MAGIC);
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(
+            archive.len() + (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE,
+            0,
+        );
+    }
+    // Archives end with two empty blocks
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+    archive
+}
 
-    for (i, c) in octal_str.chars().enumerate() {
-        let digit = c.to_digit(8)? as usize;
-        size += digit * pow(8, 10 - i);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octal_size_terminators() {
+        assert_eq!(read_octal_size(*b"00000004000\0"), Some(0o4000));
+        assert_eq!(read_octal_size(*b"00000004000 "), Some(0o4000));
+        assert_eq!(read_octal_size(*b"0000004000\0\0"), Some(0o4000));
+        assert_eq!(read_octal_size(*b"0000004000 \0"), Some(0o4000));
     }
 
-    Some(size)
+    #[test]
+    fn octal_size_trimming() {
+        assert_eq!(read_octal_size(*b"  4000\0\0\0\0\0\0"), Some(0o4000));
+        assert_eq!(read_octal_size(*b"\0\0\0\0\0\0\0\0 17\0"), Some(0o17));
+        assert_eq!(read_octal_size(*b"           \0"), None);
+        assert_eq!(read_octal_size([0; SIZE_SIZE]), None);
+        assert_eq!(read_octal_size(*b"0000000400 8"), None);
+        assert_eq!(read_octal_size(*b"00000004009\0"), None);
+    }
+
+    #[test]
+    fn reads_files() {
+        let archive = archive(&[("sys/config", b"screen=800x600\n"), ("sys/core", b"kernel")]);
+        assert!(is_ustar(&archive));
+        assert_eq!(
+            read_ustar(&archive, "sys/config"),
+            Ok(&b"screen=800x600\n"[..])
+        );
+        assert_eq!(read_ustar(&archive, "sys/core"), Ok(&b"kernel"[..]));
+        assert_eq!(read_ustar(&archive, "sys"), Err(InitrdError::FileNotFound));
+    }
+
+    #[test]
+    fn truncated_file() {
+        let archive = archive(&[("sys/config", b"nosmp=1\n"), ("sys/core", &[0x90; 1024])]);
+        // Cut off the end of the last file and the two empty blocks after it
+        let truncated = &archive[..archive.len() - 2 * BLOCK_SIZE - 8];
+        assert_eq!(
+            read_ustar(truncated, "sys/core"),
+            Err(InitrdError::FileTruncated)
+        );
+        assert_eq!(read_ustar(truncated, "sys/config"), Ok(&b"nosmp=1\n"[..]));
+    }
 }
@@ -0,0 +1,365 @@
+use alloc::{string::String, vec::Vec};
+use log::debug;
+use uefi::prelude::Status;
+
+use crate::{
+    environment::DEFAULT_KERNEL, fs::FileSystem, gunzip, Environment, InflateError, Initrd,
+    InitrdError, BOOT_DIR,
+};
+
+/// Maximum kernel file size in bytes, unless it is overridden at compile time.
+const DEFAULT_MAX_KERNEL_SIZE: usize = 64 * 1024 * 1024;
+/// Size of the pages that the kernel is mapped with.
+const PAGE_SIZE: u64 = 4096;
+
+/// An error resulting from searching for the kernel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KernelError {
+    /// The kernel was not found in initrd or on the boot partition
+    NotFound,
+    /// The kernel was found in initrd, but could not be read
+    Initrd(InitrdError),
+    /// The kernel was found on the boot partition, but could not be read
+    BootPartition(Status),
+    /// The kernel file is `size` bytes, which is larger than the maximum of `limit` bytes
+    ///
+    /// Decompression stops once the limit is passed, so for a compressed kernel `size` is only
+    /// `limit + 1`.
+    TooLarge { size: usize, limit: usize },
+    /// The kernel is gzip compressed, but could not be decompressed
+    Decompress(InflateError),
+}
+
+/// The physical pages that the loaded kernel was copied to, and the virtual address that it is
+/// linked at.
+///
+/// The kernel only depends on its virtual addresses, so the physical pages can be anywhere. They
+/// are at the preferred load address if it was free, and anywhere else otherwise.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KernelMapping {
+    pub virt: u64,
+    pub phys: u64,
+    pub size: u64,
+}
+
+impl KernelMapping {
+    /// Returns the physical address that backs the kernel's virtual address `virt`.
+    ///
+    /// Returns `None` if `virt` is not in the kernel.
+    pub fn translate(&self, virt: u64) -> Option<u64> {
+        let offset = virt.checked_sub(self.virt)?;
+        if offset >= self.size {
+            return None;
+        }
+        Some(self.phys + offset)
+    }
+
+    /// Returns the virtual and physical addresses of each page of the kernel, which is what has to
+    /// be mapped for the kernel to run.
+    ///
+    /// Both addresses are expected to be page aligned, as BOOTBOOT kernels are linked at page
+    /// aligned addresses and the physical pages are allocated by page.
+    pub fn pages(&self) -> impl Iterator<Item = (u64, u64)> {
+        let pages = (self.size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let (virt, phys) = (self.virt, self.phys);
+        (0..pages).map(move |page| (virt + page * PAGE_SIZE, phys + page * PAGE_SIZE))
+    }
+}
+
+/// Where [`resolve_kernel`] found the kernel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KernelLocation {
+    Initrd,
+    BootPartition,
+}
+
+/// A kernel file found by [`resolve_kernel`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KernelFile {
+    /// Path that the kernel was found at, which may be the default kernel path instead of the path
+    /// from the environment
+    pub path: String,
+    pub location: KernelLocation,
+    pub data: Vec<u8>,
+}
+
+/// Returns the maximum kernel file size in bytes.
+///
+/// Uses `TOYBB_MAX_KERNEL_SIZE` if it was set at compile time and is a valid number of bytes.
+pub fn max_kernel_size() -> usize {
+    option_env!("TOYBB_MAX_KERNEL_SIZE")
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_MAX_KERNEL_SIZE)
+}
+
+/// Searches for the kernel and returns its contents along with where it was found.
+///
+/// The following locations are searched in order until the kernel is found:
+///
+/// 1. The `kernel=` path from `env` in `initrd`
+/// 2. The default kernel path in `initrd`, if `env` uses a different path
+/// 3. The `kernel=` path from `env` in `bootdir`, which is the `BOOTBOOT` directory of the boot
+///    partition, for setups that do not keep the kernel in initrd
+///
+/// Each location that does not have the kernel is logged. The kernel's size is checked against
+/// [`max_kernel_size`] before it is copied into memory.
+///
+/// # Errors
+///
+/// * `KernelError::NotFound`: The kernel is not in any of the locations
+/// * `KernelError::Initrd`: The kernel is in initrd, but could not be read (e.g. it is truncated)
+/// * `KernelError::BootPartition`: The kernel is on the boot partition, but could not be read
+/// * `KernelError::TooLarge`: The kernel is larger than [`max_kernel_size`]
+pub fn resolve_kernel(
+    bootdir: &mut impl FileSystem,
+    initrd: &Initrd,
+    env: &Environment,
+) -> Result<KernelFile, KernelError> {
+    // Search initrd
+    let mut initrd_paths = [env.kernel.as_str(), DEFAULT_KERNEL].into_iter();
+    if env.kernel == DEFAULT_KERNEL {
+        initrd_paths.next_back();
+    }
+    let limit = max_kernel_size();
+    for path in initrd_paths {
+        match initrd.read_file(path).map(<[u8]>::len) {
+            Ok(size) => {
+                debug!("Found kernel in initrd at '{}'", path);
+                check_kernel_size(size, limit)?;
+                // The kernel is copied out of initrd, so it does not borrow from it
+                let data = initrd.read_file_owned(path).ok_or(KernelError::NotFound)?;
+                return Ok(KernelFile {
+                    path: String::from(path),
+                    location: KernelLocation::Initrd,
+                    data,
+                });
+            }
+            Err(InitrdError::FileNotFound) | Err(InitrdError::UnknownFormat) => {
+                debug!("Kernel not found in initrd at '{}'", path);
+            }
+            Err(e) => return Err(KernelError::Initrd(e)),
+        }
+    }
+
+    // Search boot partition
+    let path = env.kernel.as_str();
+    let size = match bootdir.file_size(path) {
+        Ok(size) => size,
+        Err(_) => {
+            debug!(
+                "Kernel not found on boot partition at '{}/{}'",
+                BOOT_DIR, path
+            );
+            return Err(KernelError::NotFound);
+        }
+    };
+    check_kernel_size(size, limit)?;
+    let kernel = bootdir
+        .read_file(path)
+        .map_err(|e| KernelError::BootPartition(e.status()))?;
+    debug!("Found kernel on boot partition at '{}/{}'", BOOT_DIR, path);
+
+    Ok(KernelFile {
+        path: String::from(path),
+        location: KernelLocation::BootPartition,
+        data: kernel,
+    })
+}
+
+/// Decompresses a gzip compressed kernel.
+///
+/// The decompressed kernel is limited to [`max_kernel_size`] like an uncompressed kernel, and
+/// decompression stops as soon as the limit is passed.
+///
+/// # Errors
+///
+/// * `KernelError::TooLarge`: The decompressed kernel is larger than [`max_kernel_size`]
+/// * `KernelError::Decompress`: The kernel is not a valid gzip file
+pub fn gunzip_kernel(kernel: &[u8]) -> Result<Vec<u8>, KernelError> {
+    gunzip_with_limit(kernel, max_kernel_size())
+}
+
+/// Decompresses a gzip compressed kernel that must not be larger than `limit` bytes.
+fn gunzip_with_limit(kernel: &[u8], limit: usize) -> Result<Vec<u8>, KernelError> {
+    gunzip(kernel, limit).map_err(|e| match e {
+        InflateError::TooLarge => KernelError::TooLarge {
+            size: limit + 1,
+            limit,
+        },
+        e => KernelError::Decompress(e),
+    })
+}
+
+/// Returns an error if a kernel of `size` bytes is larger than `limit`.
+fn check_kernel_size(size: usize, limit: usize) -> Result<(), KernelError> {
+    if size > limit {
+        return Err(KernelError::TooLarge { size, limit });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{string::ToString, vec};
+    use uefi::Error as UefiError;
+
+    /// A boot partition directory with `files`.
+    struct TestDir<'a> {
+        files: &'a [(&'a str, &'a [u8])],
+    }
+
+    impl TestDir<'_> {
+        fn find(&self, path: &str) -> uefi::Result<&[u8]> {
+            self.files
+                .iter()
+                .find(|(name, _)| *name == path)
+                .map(|(_, data)| *data)
+                .ok_or_else(|| UefiError::from(Status::NOT_FOUND))
+        }
+    }
+
+    impl FileSystem for TestDir<'_> {
+        fn file_size(&mut self, path: &str) -> uefi::Result<usize> {
+            self.find(path).map(<[u8]>::len)
+        }
+
+        fn read_file(&mut self, path: &str) -> uefi::Result<Vec<u8>> {
+            self.find(path).map(<[u8]>::to_vec)
+        }
+    }
+
+    fn env_with_kernel(kernel: &str) -> Environment {
+        Environment {
+            kernel: kernel.to_string(),
+            ..Environment::default()
+        }
+    }
+
+    #[test]
+    fn prefers_configured_kernel_in_initrd() {
+        let initrd =
+            Initrd::from_files(&[(DEFAULT_KERNEL, b"default"), ("boot/kernel", b"kernel")]);
+        let mut bootdir = TestDir {
+            files: &[("boot/kernel", b"esp")],
+        };
+        let kernel = resolve_kernel(&mut bootdir, &initrd, &env_with_kernel("boot/kernel"));
+        assert_eq!(
+            kernel,
+            Ok(KernelFile {
+                path: "boot/kernel".to_string(),
+                location: KernelLocation::Initrd,
+                data: b"kernel".to_vec(),
+            })
+        );
+    }
+
+    #[test]
+    fn falls_back_to_default_kernel_in_initrd() {
+        let initrd = Initrd::from_files(&[(DEFAULT_KERNEL, b"default")]);
+        let mut bootdir = TestDir {
+            files: &[("boot/kernel", b"esp")],
+        };
+        let kernel =
+            resolve_kernel(&mut bootdir, &initrd, &env_with_kernel("boot/kernel")).unwrap();
+        // The hash file is looked up next to this path, not the configured one
+        assert_eq!(kernel.path, DEFAULT_KERNEL);
+        assert_eq!(kernel.data, b"default");
+    }
+
+    #[test]
+    fn falls_back_to_boot_partition() {
+        let initrd = Initrd::from_files(&[("sys/config", b"screen=800x600")]);
+        let mut bootdir = TestDir {
+            files: &[("boot/kernel", b"esp")],
+        };
+        let kernel = resolve_kernel(&mut bootdir, &initrd, &env_with_kernel("boot/kernel"));
+        assert_eq!(
+            kernel,
+            Ok(KernelFile {
+                path: "boot/kernel".to_string(),
+                location: KernelLocation::BootPartition,
+                data: b"esp".to_vec(),
+            })
+        );
+    }
+
+    #[test]
+    fn missing_kernel() {
+        let initrd = Initrd::from_files(&[]);
+        let mut bootdir = TestDir { files: &[] };
+        let kernel = resolve_kernel(&mut bootdir, &initrd, &env_with_kernel("boot/kernel"));
+        assert_eq!(kernel, Err(KernelError::NotFound));
+    }
+
+    #[test]
+    fn kernel_larger_than_limit() {
+        let data = vec![0; max_kernel_size() + 1];
+        let initrd = Initrd::from_files(&[]);
+        let mut bootdir = TestDir {
+            files: &[(DEFAULT_KERNEL, &data)],
+        };
+        let kernel = resolve_kernel(&mut bootdir, &initrd, &Environment::default());
+        assert_eq!(
+            kernel,
+            Err(KernelError::TooLarge {
+                size: data.len(),
+                limit: max_kernel_size(),
+            })
+        );
+    }
+
+    /// Returns `data` as a gzip file with a single stored block.
+    fn gzip_stored(data: &[u8]) -> Vec<u8> {
+        let len = data.len() as u16;
+        let mut gzip = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3, 1];
+        gzip.extend_from_slice(&len.to_le_bytes());
+        gzip.extend_from_slice(&(!len).to_le_bytes());
+        gzip.extend_from_slice(data);
+        gzip.extend_from_slice(&crate::crc32(data).to_le_bytes());
+        gzip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        gzip
+    }
+
+    #[test]
+    fn decompressed_kernel_larger_than_limit() {
+        let gzip = gzip_stored(&[0x7f; 4096]);
+        assert_eq!(gunzip_with_limit(&gzip, 4096), Ok(vec![0x7f; 4096]));
+        assert_eq!(
+            gunzip_with_limit(&gzip, 4095),
+            Err(KernelError::TooLarge {
+                size: 4096,
+                limit: 4095,
+            })
+        );
+        assert_eq!(
+            gunzip_with_limit(&gzip[..gzip.len() - 1], 4096),
+            Err(KernelError::Decompress(InflateError::UnexpectedEof))
+        );
+    }
+
+    #[test]
+    fn kernel_mapping_at_fallback_address() {
+        // Pages that were allocated away from the preferred load address, which is not known here
+        let mapping = KernelMapping {
+            virt: 0xffff_ffff_ffe0_0000,
+            phys: 0x0345_6000,
+            size: 0x2800,
+        };
+        assert_eq!(mapping.translate(0xffff_ffff_ffe0_0000), Some(0x0345_6000));
+        assert_eq!(mapping.translate(0xffff_ffff_ffe0_1234), Some(0x0345_7234));
+        assert_eq!(mapping.translate(0xffff_ffff_ffe0_27ff), Some(0x0345_87ff));
+        assert_eq!(mapping.translate(0xffff_ffff_ffe0_2800), None);
+        assert_eq!(mapping.translate(0xffff_ffff_ffdf_ffff), None);
+
+        // The partial last page is mapped too
+        assert_eq!(
+            mapping.pages().collect::<Vec<_>>(),
+            vec![
+                (0xffff_ffff_ffe0_0000, 0x0345_6000),
+                (0xffff_ffff_ffe0_1000, 0x0345_7000),
+                (0xffff_ffff_ffe0_2000, 0x0345_8000),
+            ]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Logger that prints to the UEFI console or serial port, and keeps the most recent messages in
+//! memory.
+//!
+//! The logger is made of layers that each receive every record. The recent messages are kept in a
+//! fixed-size ring, so recording them never allocates and keeps working after boot services are
+//! exited. They are written to the crash log if the bootloader panics.
+
+use core::{
+    cmp,
+    fmt::{self, Write},
+    ptr, str,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use log::{Log, Metadata, Record};
+use uefi::Handle;
+
+use crate::arch;
+
+/// Number of messages kept in the log ring.
+const LOG_CAPACITY: usize = 64;
+
+/// Maximum size in bytes of a message in the log ring; longer messages are truncated.
+const LOG_MESSAGE_SIZE: usize = 128;
+
+/// True while boot services are active, so the UEFI console and file system can be used.
+static BOOT_SERVICES_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Handle of the bootloader image, used to find the boot partition.
+///
+/// The bootloader only runs on one core, so the statics below are never accessed concurrently.
+static mut IMAGE_HANDLE: Option<Handle> = None;
+
+/// Ring of the most recent log messages.
+static mut LOG_RING: LogRing = LogRing {
+    messages: [LogMessage::EMPTY; LOG_CAPACITY],
+    recorded: 0,
+};
+
+static LOGGER: BootLogger = BootLogger {
+    layers: [&ConsoleLogger, &RingLogger],
+};
+
+/// A formatted log message, truncated to [`LOG_MESSAGE_SIZE`] bytes.
+#[derive(Clone, Copy)]
+struct LogMessage {
+    data: [u8; LOG_MESSAGE_SIZE],
+    len: usize,
+}
+
+impl LogMessage {
+    const EMPTY: Self = Self {
+        data: [0; LOG_MESSAGE_SIZE],
+        len: 0,
+    };
+
+    fn as_str(&self) -> &str {
+        // Only whole characters are written, so this is always valid UTF-8
+        str::from_utf8(&self.data[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for LogMessage {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Truncate at a character boundary if the message is full
+        let mut count = cmp::min(s.len(), LOG_MESSAGE_SIZE - self.len);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.data[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// A ring that keeps the last [`LOG_CAPACITY`] messages recorded in it.
+struct LogRing {
+    messages: [LogMessage; LOG_CAPACITY],
+    /// Total number of messages ever recorded
+    recorded: usize,
+}
+
+impl LogRing {
+    /// Clears and returns the slot of the oldest message, which becomes the newest message.
+    fn next_message(&mut self) -> &mut LogMessage {
+        let message = &mut self.messages[self.recorded % LOG_CAPACITY];
+        message.len = 0;
+        self.recorded += 1;
+        message
+    }
+
+    /// Returns an iterator over the kept messages, from oldest to newest.
+    fn messages(&self) -> impl Iterator<Item = &str> {
+        let count = cmp::min(self.recorded, LOG_CAPACITY);
+        (self.recorded - count..self.recorded)
+            .map(move |idx| self.messages[idx % LOG_CAPACITY].as_str())
+    }
+}
+
+/// Writes `record` as a single line without a trailing newline.
+fn write_record(writer: &mut dyn Write, record: &Record) -> fmt::Result {
+    write!(
+        writer,
+        "[{:>5}]: {:>12}@{:03}: {}",
+        record.level(),
+        record.file().unwrap_or(""),
+        record.line().unwrap_or(0),
+        record.args()
+    )
+}
+
+/// Prints every record to the UEFI console while boot services are active, and to the serial port
+/// after they are exited.
+struct ConsoleLogger;
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        // Write errors are ignored, as there is nowhere to report them
+        if boot_services_active() {
+            let st = unsafe { uefi_services::system_table().as_mut() };
+            let stdout = st.stdout();
+            let _ = write_record(stdout, record);
+            let _ = stdout.write_char('\n');
+        } else {
+            let _ = write_record(&mut arch::serial::SerialWriter, record);
+            arch::serial::write_str("\n");
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Keeps every record in [`LOG_RING`].
+struct RingLogger;
+
+impl Log for RingLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let ring = unsafe { &mut *ptr::addr_of_mut!(LOG_RING) };
+        // Writing to a log message cannot fail
+        let _ = write_record(ring.next_message(), record);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Passes every record to each of its layers.
+struct BootLogger {
+    layers: [&'static dyn Log; 2],
+}
+
+impl Log for BootLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.layers.iter().any(|layer| layer.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        for layer in self.layers {
+            if layer.enabled(record.metadata()) {
+                layer.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for layer in self.layers {
+            layer.flush();
+        }
+    }
+}
+
+/// Installs the logger.
+///
+/// This must be called once, after UEFI services are initialized. `image_handle` is kept so that
+/// the crash log can be written to the boot partition.
+pub fn init(image_handle: Handle) {
+    unsafe { IMAGE_HANDLE = Some(image_handle) };
+    BOOT_SERVICES_ACTIVE.store(true, Ordering::Relaxed);
+    // The logger is only set once, so this cannot fail
+    let _ = log::set_logger(&LOGGER);
+}
+
+/// Switches console output from the UEFI console to the serial port.
+///
+/// This must be called before exiting boot services. Log messages are still kept in memory.
+pub fn exit_boot_services() {
+    BOOT_SERVICES_ACTIVE.store(false, Ordering::Relaxed);
+}
+
+/// Returns true if the logger was installed and boot services have not been exited.
+pub fn boot_services_active() -> bool {
+    BOOT_SERVICES_ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Returns the handle of the bootloader image, if the logger was installed.
+pub fn image_handle() -> Option<Handle> {
+    unsafe { IMAGE_HANDLE }
+}
+
+/// Returns an iterator over the most recent log messages, from oldest to newest.
+///
+/// Messages do not end with a newline, and messages longer than 128 bytes are truncated.
+pub fn recent_messages() -> impl Iterator<Item = &'static str> {
+    unsafe { (*ptr::addr_of!(LOG_RING)).messages() }
+}
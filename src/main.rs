@@ -1,5 +1,6 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 #![feature(abi_efiapi)]
 #![feature(iter_advance_by)]
 #![feature(ptr_metadata)]
@@ -18,6 +19,9 @@
 //! This bootloader is structured so that the [`main`] function either loads a kernel and never
 //! returns or it panics if an unrecoverable error is encountered.
 //!
+//! If `dryrun=1` is set in the environment, [`main`] instead returns successfully after everything
+//! that would be passed to the kernel has been prepared, without exiting boot services.
+//!
 //! # Panics
 //!
 //! Unrecoverable errors cause the bootloader to panic, as the processor is forced to halt.
@@ -30,43 +34,113 @@
 //!
 //! Panics are different for release and debug modes.
 //!
-//! A panic in the release mode prints out an error code to the console and halt (not implemented
-//! yet).
+//! A panic in the release mode prints out an error code to the console and halts. The error code
+//! is the [`BootPhase`] that the bootloader was in when it panicked.
 //!
 //! A panic in the debug mode provides extra information about the error. Right now, the only
-//! extra information is the source file and line of panic, but a stack trace might be helpful,
-//! too.
+//! extra information is the boot phase, panic message, and the source file and line of panic, but
+//! a stack trace might be helpful, too.
 
 extern crate alloc;
 
 mod acpi;
+mod arch;
+mod crypto;
 mod elf;
 mod environment;
 mod framebuffer;
 mod fs;
 mod header;
+mod inflate;
 mod initrd;
+mod kernel;
+mod logger;
 mod mmap;
+mod panic;
 mod smbios;
+mod summary;
 mod time;
 mod utils;
 
-pub use acpi::AcpiSystemDescriptionTable;
+pub use acpi::{AcpiSystemDescriptionTable, AcpiTables, PmTimer, TableLookup};
+pub use crypto::{crc32, sha256, sha256_from_hex};
 pub use elf::{
-    ElfHeader64, ElfParseError, ElfProgramHeader64, ElfSectionHeader64, ElfSymbol64,
-    ELF_PH_TYPE_LOAD, ELF_SH_TYPE_STRTAB, ELF_SH_TYPE_SYMTAB,
+    read_build_id, ElfHeader64, ElfParseError, ElfProgramHeader64, ElfSectionHeader64, ElfSymbol64,
+    ELF_NOTE_TYPE_GNU_BUILD_ID, ELF_PF_R, ELF_PF_W, ELF_PF_X, ELF_PH_TYPE_LOAD, ELF_PH_TYPE_NOTE,
+    ELF_PH_TYPE_TLS, ELF_SH_TYPE_DYNSYM, ELF_SH_TYPE_NOBITS, ELF_SH_TYPE_STRTAB,
+    ELF_SH_TYPE_SYMTAB, ELF_SYM_BIND_GLOBAL, ELF_SYM_BIND_LOCAL, ELF_SYM_BIND_WEAK,
+    ELF_SYM_TYPE_FILE, ELF_SYM_TYPE_FUNC, ELF_SYM_TYPE_NOTYPE, ELF_SYM_TYPE_OBJECT,
+    ELF_SYM_TYPE_SECTION, MAX_LOAD_SIZE,
+};
+pub use environment::{Environment, MmioMap, ENVIRONMENT_PAGE_SIZE};
+pub use framebuffer::{
+    choose_mode, select_mode, FbPrefs, Framebuffer, FramebufferError, FramebufferType, ModeDesc,
+    GLYPH_HEIGHT,
+};
+pub use fs::{
+    file_size, find_partition_file_system, open_dir, open_file, read_to_string, read_to_vec,
+    read_to_vec_with_progress, FileSystem,
+};
+pub use header::{
+    BootbootArchX86_64, BootbootImage, BootbootInfo, BootbootProtocolLevel, LoaderType,
+    BOOTBOOT_INFO_SIZE, BOOTBOOT_MAGIC, BOOTBOOT_STRUCT_SIZE,
+};
+pub use inflate::{gunzip, inflate, is_gzip, InflateError};
+pub use initrd::{Initrd, InitrdError, InitrdFormat, InitrdSource};
+pub use kernel::{
+    gunzip_kernel, max_kernel_size, resolve_kernel, KernelError, KernelFile, KernelLocation,
+    KernelMapping,
+};
+pub use mmap::{BootbootMMap, MMapError, MMAP_MAX_ENTRIES};
+pub use panic::BootPhase;
+pub use smbios::{BiosInfo, SmbiosEntryPoint};
+pub use summary::BootSummary;
+pub use utils::{Hex, HexBytes};
+
+use alloc::{format, vec, vec::Vec};
+use core::{
+    mem, slice, str,
+    sync::atomic::{AtomicU64, Ordering},
 };
-pub use environment::Environment;
-pub use framebuffer::Framebuffer;
-pub use fs::{open_dir, open_file, read_to_string, read_to_vec};
-pub use initrd::Initrd;
-pub use mmap::BootbootMMap;
-pub use smbios::SmbiosEntryPoint;
-
-use alloc::{vec, vec::Vec};
-use core::{mem, slice, str};
-use log::debug;
-use uefi::{prelude::*, table::boot::MemoryType};
+use log::{debug, info, warn, LevelFilter};
+use uefi::{
+    prelude::*,
+    table::{
+        boot::{AllocateType, MemoryType},
+        cfg,
+    },
+    Guid,
+};
+
+const PAGE_SIZE: usize = 4096;
+/// Number of extra entries to make room for in memory maps.
+///
+/// Allocations made after fetching the memory map may split UEFI memory map entries, and marking
+/// regions as used may split BOOTBOOT memory map entries.
+const MMAP_EXTRA_ENTRIES: usize = 8;
+/// Directory on the boot partition with the initrd and config, which can be renamed at compile time
+/// with the `TOYBB_BOOT_DIR` environment variable.
+pub const BOOT_DIR: &str = match option_env!("TOYBB_BOOT_DIR") {
+    Some(boot_dir) => boot_dir,
+    None => "BOOTBOOT",
+};
+
+/// Returns the maximum level of log statements to print.
+///
+/// The level can be set at compile time with the `TOYBB_LOG` environment variable (`off`, `error`,
+/// `warn`, `info`, `debug`, or `trace`). Otherwise, debug builds print debug statements and release
+/// builds print info statements.
+fn log_level() -> LevelFilter {
+    let default = if cfg!(debug_assertions) {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+
+    option_env!("TOYBB_LOG")
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(default)
+}
 
 fn debug_info(st: &SystemTable<Boot>) {
     // Print firmware info
@@ -85,105 +159,375 @@ fn debug_info(st: &SystemTable<Boot>) {
         uefi_revision.major(),
         uefi_revision.minor()
     );
+    if let Some(bios) = SmbiosEntryPoint::from_uefi_config_table(st.config_table())
+        .ok()
+        .and_then(SmbiosEntryPoint::bios_info)
+    {
+        debug!("BIOS Vendor = {}", bios.vendor);
+        debug!("BIOS Version = {}", bios.version);
+    }
+
+    debug_config_table(st);
+}
+
+/// Total size in bytes of the pages allocated with [`alloc_phys`] and [`alloc_phys_aligned`].
+static LOADER_MEMORY: AtomicU64 = AtomicU64::new(0);
+
+/// Allocates enough zeroed pages of loader data to fit `size` bytes, at `addr` if it is set.
+///
+/// Every allocation for memory that is passed to the kernel should use this, so that it is counted
+/// by [`total_loader_memory`].
+///
+/// # Errors
+///
+/// Returns an error if the pages could not be allocated.
+fn alloc_phys(
+    bt: &BootServices,
+    size: usize,
+    addr: Option<u64>,
+) -> uefi::Result<&'static mut [u8]> {
+    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let allocate_type = match addr {
+        Some(addr) => AllocateType::Address(addr as _),
+        None => AllocateType::AnyPages,
+    };
+    let addr = bt.allocate_pages(allocate_type, MemoryType::LOADER_DATA, pages)?;
+
+    // The pages were just allocated, so nothing else refers to them
+    Ok(unsafe { claim_pages(addr, pages, size) })
+}
+
+/// Allocates enough zeroed pages of loader data to fit `size` bytes, starting at a multiple of
+/// `align`.
+///
+/// `align` must be a power of two. Extra pages are allocated to find an aligned start, and the
+/// pages around the aligned part are freed again.
+///
+/// # Errors
+///
+/// Returns an error if the pages could not be allocated or the extra pages could not be freed.
+fn alloc_phys_aligned(
+    bt: &BootServices,
+    size: usize,
+    align: usize,
+) -> uefi::Result<&'static mut [u8]> {
+    // Pages are always aligned to the page size
+    if align <= PAGE_SIZE {
+        return alloc_phys(bt, size, None);
+    }
+
+    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let extra_pages = align / PAGE_SIZE - 1;
+    let addr = bt.allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        pages + extra_pages,
+    )?;
+    let aligned_addr = (addr + align as u64 - 1) & !(align as u64 - 1);
+    let pages_before = (aligned_addr - addr) as usize / PAGE_SIZE;
+    let pages_after = extra_pages - pages_before;
+    if pages_before > 0 {
+        bt.free_pages(addr, pages_before)?;
+    }
+    if pages_after > 0 {
+        bt.free_pages(aligned_addr + (pages * PAGE_SIZE) as u64, pages_after)?;
+    }
+
+    // The pages were just allocated, so nothing else refers to them
+    Ok(unsafe { claim_pages(aligned_addr, pages, size) })
+}
+
+/// Counts `pages` pages at `addr` as loader memory, and returns the first `size` bytes of them
+/// zeroed.
+///
+/// # Safety
+///
+/// The pages must have just been allocated, so that nothing else refers to them.
+unsafe fn claim_pages(addr: u64, pages: usize, size: usize) -> &'static mut [u8] {
+    LOADER_MEMORY.fetch_add((pages * PAGE_SIZE) as u64, Ordering::Relaxed);
+    let memory = slice::from_raw_parts_mut(addr as usize as *mut u8, size);
+    memory.fill(0);
+    memory
+}
+
+/// Returns the total size in bytes of the pages allocated with [`alloc_phys`] and
+/// [`alloc_phys_aligned`].
+fn total_loader_memory() -> u64 {
+    LOADER_MEMORY.load(Ordering::Relaxed)
+}
+
+/// GUID of the flattened device tree (DTB) config table entry.
+const DTB_GUID: Guid = Guid::from_values(0xb1b621d5, 0xf19c, 0x41a5, 0x830b, 0xd9152c69aae0);
+
+/// Prints the GUID and address of every entry in the UEFI config table.
+///
+/// Known GUIDs are printed along with their names.
+fn debug_config_table(st: &SystemTable<Boot>) {
+    debug!("UEFI config table:");
+    for entry in st.config_table() {
+        let name = match entry.guid {
+            cfg::ACPI_GUID => "ACPI",
+            cfg::ACPI2_GUID => "ACPI2",
+            cfg::SMBIOS_GUID => "SMBIOS",
+            cfg::SMBIOS3_GUID => "SMBIOS3",
+            DTB_GUID => "DTB",
+            cfg::DEBUG_IMAGE_INFO_GUID => "Debug Image Info",
+            cfg::DXE_SERVICES_GUID => "DXE Services",
+            cfg::HAND_OFF_BLOCK_LIST_GUID => "Hand-off Block List",
+            cfg::LZMA_COMPRESS_GUID => "LZMA Compress",
+            cfg::MEMORY_TYPE_INFORMATION_GUID => "Memory Type Information",
+            cfg::PROPERTIES_TABLE_GUID => "Properties Table",
+            _ => "Unknown",
+        };
+        debug!("{} at {} ({})", entry.guid, Hex(entry.address as u64), name);
+    }
+}
+
+/// The symbols used by the dynamic protocol, in the order `bootboot`, `environment`, `fb`, and
+/// `initstack`.
+type BootbootSymbols<'a> = [Option<&'a ElfSymbol64>; 4];
+
+/// A kernel executable that was loaded from its ELF file by [`load_elf`].
+struct LoadedKernel<'a> {
+    /// Every LOAD segment copied to its offset from `base`, with everything else zeroed
+    image: Vec<u8>,
+    /// The symbols used by the dynamic protocol, which are `None` if the kernel does not have a
+    /// symbol table or section headers
+    symbols: Option<BootbootSymbols<'a>>,
+    /// Virtual address of the start of `image`
+    base: usize,
+    /// Alignment that the physical pages of `image` should have
+    align: usize,
+    /// Program header of each LOAD segment, which have the segments' permissions
+    segments: Vec<ElfProgramHeader64>,
+    /// Program header of the thread-local storage template, which is `None` if the kernel does
+    /// not use thread-local storage or its template is not in the file
+    tls: Option<ElfProgramHeader64>,
 }
 
-/// Parses `kernel` to load executable, symbol information, and the program header for the loaded
+/// Parses `kernel` to load executable, symbol information, and the virtual address of the loaded
 /// region.
 ///
-/// Returns a tuple that includes the loaded executable and all symbols found.
+/// Every LOAD segment is copied into one buffer that spans from the lowest segment start to the
+/// highest segment end in memory. Gaps between segments and the parts of segments that are not in
+/// the file, such as `.bss`, are zeroed.
+///
+/// The start of the buffer is rounded down to the largest segment alignment, so that each segment
+/// has the same offset from an aligned address in the buffer as it has in virtual memory.
+///
+/// Returns the loaded executable along with its symbols, virtual address, alignment, and the
+/// program headers that describe it.
 ///
 /// # Panic
 ///
 /// Panics if `kernel` is invalid AND also gets in the way of loading an executable. If there are
 /// invalid parts of the file that do not contribute to loading the executable, no panic will
 /// occur.
-fn load_elf<'a>(
-    elf_header: &'a ElfHeader64,
-    kernel: &[u8],
-) -> (
-    Vec<u8>,
-    [Option<&'a ElfSymbol64>; 4],
-    &'a ElfProgramHeader64,
-) {
+fn load_elf<'a>(elf_header: &'a ElfHeader64, kernel: &'a [u8]) -> LoadedKernel<'a> {
     // Get section and program headers
     let (section_headers, program_headers) = elf_header
         .get_headers(kernel)
         .unwrap_or_else(|e| panic!("Kernel: Error while parsing ELF file headers: {:?}", e));
 
-    // Get first program header with LOAD type
-    let ph_load = program_headers
-        .iter()
-        .find(|ph| ph.program_type == ELF_PH_TYPE_LOAD)
-        .expect("Kernel: No program header of LOAD type");
-    // Ensure program header is valid
-    if ph_load.offset + ph_load.file_size > kernel.len() {
+    // Get virtual address range covered by all LOAD segments
+    let load_span = ElfProgramHeader64::load_span(&program_headers).unwrap_or_else(|e| match e {
+        ElfParseError::MissingLoadSegment => panic!(
+            "Kernel: No LOAD segment in {} program headers, so there is nothing to load",
+            program_headers.len()
+        ),
+        e => panic!("Kernel: LOAD segments have an invalid size: {:?}", e),
+    });
+    // Ensure every LOAD segment is valid before any are loaded
+    for (i, ph) in ElfProgramHeader64::load_segments(&program_headers).enumerate() {
+        ph.validate(kernel)
+            .unwrap_or_else(|e| panic!("Kernel: LOAD segment {} is invalid: {:?}", i, e));
+    }
+    // Ensure no LOAD segment overwrites another while they are copied
+    if let Err(e) = ElfProgramHeader64::validate_no_overlap(&program_headers) {
+        panic!("Kernel: LOAD segments overlap in memory: {:?}", e);
+    }
+    // Ensure the entry point runs loaded code
+    if let Err(e) = elf_header.validate_entry(&program_headers) {
         panic!(
-            "Kernel: File size {} bytes with offset 0x{:x} is too small to load executable of size {} bytes",
-            kernel.len(),
-            ph_load.offset,
-            ph_load.file_size
+            "Kernel: Entry point {} is not in a LOAD segment: {:?}",
+            Hex(elf_header.entry as u64),
+            e
         );
     }
-    if ph_load.file_size > ph_load.mem_size {
-        panic!("Kernel: Size of executable file should not be larger than size in memory");
-    }
-    if elf_header.sh_string_index as usize >= section_headers.len() {
-        panic!("Kernel: String table has an invalid section index");
+
+    // Find special symbols, which are only needed for the dynamic protocol
+    // Sections are not needed to load the kernel, so a kernel without a valid section name string
+    // table is treated as having no symbols
+    let all_symbols = match section_headers.get(elf_header.sh_string_index as usize) {
+        Some(str_table_header) => {
+            let str_table = str_table_header
+                .bytes(kernel)
+                .expect("Kernel: String table has invalid size or offset");
+            find_bootboot_symbols(&section_headers, str_table, kernel, elf_header.entry)
+        }
+        None if section_headers.is_empty() => {
+            debug!("Kernel: No section headers, so there are no symbols");
+            None
+        }
+        None => {
+            warn!(
+                "Kernel: Section name string table index {} is invalid, ignoring symbols",
+                elf_header.sh_string_index
+            );
+            None
+        }
+    };
+    match read_build_id(kernel, &program_headers) {
+        Some(build_id) => debug!("Kernel build-id: {}", HexBytes(build_id)),
+        None => debug!("Kernel: No build-id"),
     }
 
-    // Get info from program header
-    let kernel_load = &kernel[ph_load.offset..ph_load.offset + ph_load.file_size];
+    debug!(
+        "Found ELF executable of size {} KiB in {} LOAD segments at {}",
+        load_span.len() / 1024,
+        ElfProgramHeader64::load_segments(&program_headers).count(),
+        Hex(load_span.start as u64)
+    );
 
-    // Get string table for section names
-    let str_table_header = section_headers[elf_header.sh_string_index as usize];
-    if str_table_header.offset + str_table_header.size > kernel.len() {
-        panic!("Kernel: String table has invalid size or offset");
+    // Align the start of the span to the largest segment alignment, which is at least a page
+    let load_align = ElfProgramHeader64::load_segments(&program_headers)
+        .map(ElfProgramHeader64::align)
+        .fold(PAGE_SIZE, usize::max);
+    let load_base = load_span.start & !(load_align - 1);
+    if load_span.end - load_base > MAX_LOAD_SIZE {
+        panic!(
+            "Kernel: LOAD segments are larger than {} MiB after alignment to {}",
+            MAX_LOAD_SIZE / (1024 * 1024),
+            Hex(load_align as u64)
+        );
     }
-    let str_table =
-        &kernel[str_table_header.offset..str_table_header.offset + str_table_header.size];
+    debug!("Kernel segment alignment: {} KiB", load_align / 1024);
 
-    // Get symbol table by checking for ".symtab" in string table
-    let symbol_name = b".symtab";
-    let symbol_header = ElfSectionHeader64::find_section(
-        section_headers,
-        &symbol_name[..],
-        ELF_SH_TYPE_SYMTAB,
-        &str_table,
-    )
-    .expect("Kernel: Could not find valid symbol table header");
-    if symbol_header.entry_size != mem::size_of::<ElfSymbol64>() {
-        panic!("Kernel: Symbol table has invalid entry size");
+    // Allocate space for kernel, which is zeroed, and copy each segment to its offset in the span
+    let mut loaded_kernel = vec![0; load_span.end - load_base];
+    let segments: Vec<ElfProgramHeader64> = ElfProgramHeader64::load_segments(&program_headers)
+        .copied()
+        .collect();
+    for ph in &segments {
+        let offset = ph.virt_addr() - load_base;
+        loaded_kernel[offset..offset + ph.file_size]
+            .copy_from_slice(&kernel[ph.offset..ph.offset + ph.file_size]);
+        let flag = |set, c| if set { c } else { '-' };
+        debug!(
+            "LOAD segment at {} of size {} KiB ({}{}{})",
+            Hex(ph.virt_addr() as u64),
+            ph.mem_size / 1024,
+            flag(ph.is_readable(), 'r'),
+            flag(ph.is_writable(), 'w'),
+            flag(ph.is_executable(), 'x')
+        );
     }
-    if symbol_header.offset + symbol_header.size > kernel.len() {
-        panic!("Kernel: Symbol table does not fit");
+
+    // Find the thread-local storage template
+    let tls = ElfProgramHeader64::tls_segment(&program_headers).and_then(|ph| {
+        if let Err(e) = ph.validate(kernel) {
+            warn!("Kernel: Ignoring invalid TLS segment: {:?}", e);
+            return None;
+        }
+        Some(*ph)
+    });
+
+    LoadedKernel {
+        image: loaded_kernel,
+        symbols: all_symbols,
+        base: load_base,
+        align: load_align,
+        segments,
+        tls,
     }
-    if symbol_header.size % symbol_header.entry_size != 0
-        || symbol_header.size < symbol_header.entry_size
-    {
-        panic!("Kernel: Symbol table has invalid size");
+}
+
+/// Returns the protocol level forced by `env`, which is the level set in the environment, or
+/// detects it from the kernel's symbols if the environment does not set one.
+///
+/// Kernels using the dynamic protocol say where the loader should map everything with the
+/// `bootboot`, `environment`, and `fb` symbols, so the dynamic protocol is detected if
+/// `has_dynamic_symbols` is true.
+///
+/// # Panic
+///
+/// Panics if the dynamic protocol is forced but the kernel does not have a symbol table or the
+/// symbols that the protocol needs.
+fn select_protocol(
+    env: Option<BootbootProtocolLevel>,
+    has_symbol_table: bool,
+    has_dynamic_symbols: bool,
+) -> BootbootProtocolLevel {
+    match env {
+        Some(BootbootProtocolLevel::Dynamic) if !has_symbol_table => {
+            panic!(
+                "Kernel: Dynamic protocol requires a symbol table, so the kernel must keep its \
+                 symbols or be linked for the static protocol"
+            )
+        }
+        Some(BootbootProtocolLevel::Dynamic) if !has_dynamic_symbols => {
+            panic!("Kernel: Dynamic protocol requires the bootboot, environment, and fb symbols")
+        }
+        Some(level) => level,
+        None if has_dynamic_symbols => BootbootProtocolLevel::Dynamic,
+        None => BootbootProtocolLevel::Static,
     }
-    let symbol_entries = symbol_header.size / symbol_header.entry_size;
-    let symbol_table = unsafe {
-        slice::from_raw_parts(
-            &kernel[symbol_header.offset] as *const u8 as *const ElfSymbol64,
-            symbol_entries,
+}
+
+/// Finds the symbols used by the dynamic protocol: `bootboot`, `environment`, `fb`, and
+/// `initstack`.
+///
+/// The symbols are searched for in `.symtab` with `.strtab`, or in `.dynsym` with `.dynstr` if the
+/// kernel does not have both of the former, as stripped kernels may still have dynamic symbols.
+///
+/// The function that the kernel's `entry` point is in is also logged, as a check that the right
+/// kernel was loaded.
+///
+/// Returns `None` if the kernel does not have either symbol table, as stripped kernels can still
+/// use the static protocol. Panics if the symbol table exists but is invalid.
+fn find_bootboot_symbols<'a>(
+    section_headers: &[ElfSectionHeader64],
+    str_table: &[u8],
+    kernel: &'a [u8],
+    entry: usize,
+) -> Option<BootbootSymbols<'a>> {
+    // Prefer the full symbol table, falling back to the dynamic symbol table
+    let symtab = find_symbol_table(
+        section_headers,
+        str_table,
+        kernel,
+        b".symtab",
+        ELF_SH_TYPE_SYMTAB,
+        b".strtab",
+    );
+    let symbol_tables = symtab.or_else(|| {
+        debug!("Kernel: No .symtab and .strtab, trying .dynsym and .dynstr");
+        find_symbol_table(
+            section_headers,
+            str_table,
+            kernel,
+            b".dynsym",
+            ELF_SH_TYPE_DYNSYM,
+            b".dynstr",
         )
+    });
+    let (symbol_table, symbol_str_table) = match symbol_tables {
+        Some(tables) => tables,
+        None => {
+            debug!("Kernel: No symbol table");
+            return None;
+        }
     };
 
-    // Get symbol string table by checking for ".strtab" in string table
-    let symbol_str_name = b".strtab";
-    let symbol_str_header = ElfSectionHeader64::find_section(
-        section_headers,
-        &symbol_str_name[..],
-        ELF_SH_TYPE_STRTAB,
-        &str_table,
-    )
-    .expect("Kernel: Could not find valid symbol string table header");
-    if symbol_str_header.offset + symbol_str_header.size > kernel.len() {
-        panic!("Kernel: Symbol string table has invalid size or offset");
+    match ElfSymbol64::resolve_address(symbol_table, symbol_str_table, entry) {
+        Some((name, offset)) => debug!(
+            "Kernel entry point: {} = {}+{:#x}",
+            Hex(entry as u64),
+            name,
+            offset
+        ),
+        None => debug!("Kernel: No function symbol at the entry point"),
     }
-    let symbol_str_table =
-        &kernel[symbol_str_header.offset..symbol_str_header.offset + symbol_str_header.size];
 
     // Find special symbols
     let bootboot_symbol_name = b"bootboot";
@@ -197,41 +541,81 @@ fn load_elf<'a>(
     let initstack_symbol =
         ElfSymbol64::find_symbol(symbol_table, &initstack_symbol_name[..], symbol_str_table);
 
-    debug!(
-        "Found ELF executable of size: {} KiB",
-        kernel_load.len() / 1024
-    );
     if let Some(bootboot) = bootboot_symbol {
-        debug!("Symbol BOOTBOOT: 0x{:x}", bootboot.value);
+        debug!("Symbol BOOTBOOT: {}", Hex(bootboot.value as u64));
     }
     if let Some(env) = env_symbol {
-        debug!("Symbol ENVIRONMENT: 0x{:x}", env.value);
+        debug!("Symbol ENVIRONMENT: {}", Hex(env.value as u64));
     }
     if let Some(fb) = fb_symbol {
-        debug!("Symbol FRAMEBUFFER: 0x{:x}", fb.value);
+        debug!("Symbol FRAMEBUFFER: {}", Hex(fb.value as u64));
     }
     if let Some(initstack) = initstack_symbol {
-        debug!("Symbol INITSTACK: 0x{:x}", initstack.value);
+        debug!("Symbol INITSTACK: {}", Hex(initstack.value as u64));
     }
 
-    // Ensure kernel is valid executable
+    Some([bootboot_symbol, env_symbol, fb_symbol, initstack_symbol])
+}
+
+/// Returns the symbol table with `symbol_name` and `symbol_type`, along with the string table with
+/// `symbol_str_name` that holds its symbol names.
+///
+/// Returns `None` if either section is missing. Panics if the sections exist but are invalid.
+fn find_symbol_table<'a>(
+    section_headers: &[ElfSectionHeader64],
+    str_table: &[u8],
+    kernel: &'a [u8],
+    symbol_name: &[u8],
+    symbol_type: u32,
+    symbol_str_name: &[u8],
+) -> Option<(&'a [ElfSymbol64], &'a [u8])> {
+    // Get symbol table by checking for its name in string table
+    let symbol_header =
+        ElfSectionHeader64::find_section(section_headers, symbol_name, symbol_type, str_table)?;
+    if symbol_header.entry_size != mem::size_of::<ElfSymbol64>() {
+        panic!("Kernel: Symbol table has invalid entry size");
+    }
+    if symbol_header.offset + symbol_header.size > kernel.len() {
+        panic!("Kernel: Symbol table does not fit");
+    }
+    if symbol_header.size % symbol_header.entry_size != 0
+        || symbol_header.size < symbol_header.entry_size
+    {
+        panic!("Kernel: Symbol table has invalid size");
+    }
+    let symbol_entries = symbol_header.size / symbol_header.entry_size;
 
-    // Allocate space for kernel
-    let mut loaded_kernel = vec![0; ph_load.mem_size];
-    loaded_kernel[..ph_load.file_size].copy_from_slice(kernel_load);
+    // Get symbol string table by checking for its name in string table
+    let symbol_str_header = ElfSectionHeader64::find_section(
+        section_headers,
+        symbol_str_name,
+        ELF_SH_TYPE_STRTAB,
+        str_table,
+    )?;
+    let symbol_str_table = symbol_str_header
+        .bytes(kernel)
+        .expect("Kernel: Symbol string table has invalid size or offset");
 
-    let all_symbols = [bootboot_symbol, env_symbol, fb_symbol, initstack_symbol];
-    (loaded_kernel, all_symbols, ph_load)
+    let symbol_table = unsafe {
+        slice::from_raw_parts(
+            &kernel[symbol_header.offset] as *const u8 as *const ElfSymbol64,
+            symbol_entries,
+        )
+    };
+    Some((symbol_table, symbol_str_table))
 }
 
 #[entry]
 pub fn main(image_handle: Handle, mut st: SystemTable<Boot>) -> Status {
+    // Print a banner without UEFI, so there is output even if initializing UEFI services fails
+    arch::serial::write_str("toy-bootboot starting\n");
     uefi_services::init(&mut st).unwrap();
+    logger::init(image_handle);
 
-    // Log debug statements if built in debug mode
-    if cfg!(debug_assertions) {
-        log::set_max_level(log::LevelFilter::Debug);
-
+    // Log statements up to the level chosen at compile time
+    let level = log_level();
+    log::set_max_level(level);
+    if level >= LevelFilter::Debug {
         debug_info(&st);
     }
 
@@ -244,70 +628,693 @@ pub fn main(image_handle: Handle, mut st: SystemTable<Boot>) -> Status {
     let mut root = fs.open_volume().expect(ESP_ERR);
 
     // Check for BOOTBOOT directory
-    let mut bootdir = open_dir(&mut root, "BOOTBOOT").expect(ESP_ERR);
+    let mut bootdir = open_dir(&mut root, BOOT_DIR).expect(ESP_ERR);
 
     // Read initrd file into memory
-    let initrd = Initrd::from_disk(&mut bootdir).expect("Could not read initrd from disk");
-    debug!("Found initrd of size: {} KiB", initrd.size() / 1024);
+    BootPhase::ReadInitrd.enter();
+    // This may fail if initrd modules are listed in the environment instead
+    let initrd = Initrd::from_disk(&mut bootdir);
+
+    BootPhase::ParseEnv.enter();
+    let env = Environment::get_env(&mut bootdir, initrd.as_ref().ok());
+
+    // Switch to the BOOTBOOT directory on another partition if one is set in the environment
+    // The environment is still the one read from the boot partition
+    // Fall back to the boot partition if the other partition cannot be used
+    let boot_drive_fs = env.boot_drive.as_ref().and_then(|guid| {
+        find_partition_file_system(bt, image_handle, guid)
+            .map_err(|e| {
+                warn!(
+                    "Could not open boot drive ({:?}), using boot partition",
+                    e.status()
+                )
+            })
+            .ok()
+    });
+    let boot_drive_dir = boot_drive_fs.as_ref().and_then(|boot_drive_fs| {
+        let boot_drive_fs = unsafe { &mut *boot_drive_fs.interface.get() };
+        boot_drive_fs
+            .open_volume()
+            .and_then(|mut root| open_dir(&mut root, BOOT_DIR))
+            .map_err(|e| {
+                warn!(
+                    "Could not open '{}' on boot drive ({:?}), using boot partition",
+                    BOOT_DIR,
+                    e.status()
+                )
+            })
+            .ok()
+    });
+    let (mut bootdir, initrd) = match boot_drive_dir {
+        Some(mut boot_drive_dir) => {
+            debug!("Using '{}' on boot drive", BOOT_DIR);
+            let initrd = Initrd::from_disk(&mut boot_drive_dir);
+            (boot_drive_dir, initrd)
+        }
+        None => (bootdir, initrd),
+    };
+
+    // Use initrd modules if they are listed in the environment
+    // Otherwise, prefer an initrd in the subdirectory set in the environment
+    let initrd = if env.initrd.is_empty() {
+        let initrd = match &env.initrd_dir {
+            Some(dirname) => Initrd::from_subdir(&mut bootdir, dirname).or_else(|e| {
+                warn!(
+                    "Could not read initrd from '{}/{}' ({:?}), using '{}'",
+                    BOOT_DIR,
+                    dirname,
+                    e.status(),
+                    BOOT_DIR
+                );
+                initrd
+            }),
+            None => initrd,
+        };
+        initrd.expect("Could not read initrd from disk")
+    } else {
+        Initrd::from_modules(&mut bootdir, &env.initrd)
+            .expect("Could not read initrd modules from disk")
+    };
+
+    // Verify initrd if a CRC-32 checksum is set in the environment
+    // Panic if the checksum does not match
+    if let Some(expected_crc32) = env.initrd_crc32 {
+        let initrd_crc32 = initrd.crc32();
+        if initrd_crc32 != expected_crc32 {
+            panic!(
+                "Initrd CRC-32 {:08x} does not match {:08x} from environment",
+                initrd_crc32, expected_crc32
+            );
+        }
+        debug!("Initrd matches CRC-32 from environment");
+    }
+    debug!(
+        "Found initrd of size: {} KiB (source: {:?}, format: {:?}, compressed: {})",
+        initrd.size() / 1024,
+        initrd.source(),
+        initrd.format(),
+        initrd.compressed()
+    );
 
-    let env = Environment::get_env(&mut bootdir, &initrd);
     debug!("Kernel name: {}", env.kernel);
     debug!("SMP: {}", !env.no_smp);
     debug!("Target resolution: {:?}", env.screen);
+    debug!("Target framebuffer format: {:?}", env.fb_format);
+    debug!("Initial stack size: {} bytes", env.initstack);
+    debug!("Initrd modules: {:?}", env.initrd);
+    debug!("Initrd directory: {:?}", env.initrd_dir);
+    debug!("Dry run: {}", env.dry_run);
+    debug!("Dump page tables: {}", env.dump_page_tables);
+    debug!("Extra MMIO ranges: {:?}", env.mmio_maps);
 
-    // Get linear framebuffer
-    let framebuffer =
-        Framebuffer::from_boot_services(bt, env.screen).expect("Could not get framebuffer");
+    // Get linear framebuffer by applying the graphics directives from the environment
+    BootPhase::Framebuffer.enter();
+    // Continue without a framebuffer on headless systems, relying on serial output instead
+    let framebuffer = env.apply(bt).unwrap_or_else(|e| {
+        warn!(
+            "Could not get framebuffer ({:?}), booting without one",
+            e.status()
+        );
+        Framebuffer::null()
+    });
     debug!("Framebuffer: {:?}", framebuffer);
 
-    // Get ACPI table
-    let _acpi_table = AcpiSystemDescriptionTable::from_uefi_config_table(st.config_table());
+    // Get ACPI table, unless it is disabled for firmware that faults when it is read
+    let acpi_table = if env.no_acpi {
+        info!("ACPI disabled by environment, skipping ACPI tables");
+        None
+    } else {
+        AcpiSystemDescriptionTable::from_uefi_config_table(st.config_table()).ok()
+    };
+
+    // Measure TSC frequency using the ACPI PM timer
+    #[cfg(target_arch = "x86_64")]
+    {
+        let tsc_frequency = acpi_table
+            .and_then(|acpi| PmTimer::from_acpi(&mut TableLookup::new(acpi.sdt)))
+            .and_then(|pm_timer| time::calibrate_tsc(&pm_timer));
+        match tsc_frequency {
+            Some(frequency) => debug!("TSC frequency: {} MHz", frequency / 1_000_000),
+            None => debug!("Could not calibrate TSC"),
+        }
+
+        // These are only logged, as paging is not implemented and no paging feature is enabled
+        debug!(
+            "CPU features: nx={}, pat={}, 1g_pages={}",
+            arch::cpu::has_nx(),
+            arch::cpu::has_pat(),
+            arch::cpu::has_1g_pages()
+        );
+    }
 
-    // Get SMBIOS
-    let _smbios_table = SmbiosEntryPoint::from_uefi_config_table(st.config_table());
+    // Get SMBIOS, unless it is disabled for firmware that faults when it is read
+    let smbios_table = if env.no_smbios {
+        info!("SMBIOS disabled by environment, skipping SMBIOS table");
+        None
+    } else {
+        SmbiosEntryPoint::from_uefi_config_table(st.config_table()).ok()
+    };
 
     // Get time
-    if let Ok(time) = time::get_time(&st) {
+    let boot_time = time::get_time(&st).ok();
+    if let Some(time) = boot_time {
         debug!("Got time: {:?}", time);
     }
 
     // Get kernel ELF file
+    BootPhase::LoadElf.enter();
     // Panic if not found
-    let kernel = initrd
-        .read_file(&env.kernel)
-        .unwrap_or_else(|| panic!("Could not read kernel at file: {}", env.kernel));
-    // Panic if too small
-    if kernel.len() < mem::size_of::<ElfHeader64>() {
-        panic!("Kernel of size {} bytes is too small", kernel.len());
-    }
+    let kernel_file = resolve_kernel(&mut bootdir, &initrd, &env)
+        .unwrap_or_else(|e| panic!("Could not read kernel at file {}: {:?}", env.kernel, e));
+    let kernel = &kernel_file.data[..];
     debug!(
-        "Found kernel at file {} of size {} KiB",
-        env.kernel,
-        kernel.len() / 1024
+        "Found kernel at file {} of size {} KiB ({:?})",
+        kernel_file.path,
+        kernel.len() / 1024,
+        kernel_file.location
     );
 
+    // Verify kernel file if a SHA-256 hash file exists next to it in initrd
+    // The hash file is next to the path the kernel was found at, which may be the default path
+    // Panic if hash is invalid or does not match
+    let hash_filename = format!("{}.sha256", kernel_file.path);
+    match initrd.read_file(&hash_filename) {
+        Ok(hash_file) => {
+            let hash = str::from_utf8(hash_file)
+                .ok()
+                .and_then(|hash| sha256_from_hex(hash.trim_start()))
+                .unwrap_or_else(|| panic!("Invalid SHA-256 hash in file: {}", hash_filename));
+            if sha256(kernel) != hash {
+                panic!(
+                    "Kernel does not match SHA-256 hash in file: {}",
+                    hash_filename
+                );
+            }
+            debug!("Kernel matches SHA-256 hash in file {}", hash_filename);
+        }
+        Err(InitrdError::FileNotFound) => {}
+        Err(e) => panic!(
+            "Could not read SHA-256 hash file {}: {:?}",
+            hash_filename, e
+        ),
+    }
+
+    // Decompress kernel if it is gzip compressed
+    // Panic if decompression fails
+    let kernel_inflated;
+    let kernel = if is_gzip(kernel) {
+        kernel_inflated = gunzip_kernel(kernel).unwrap_or_else(|e| {
+            panic!("Could not decompress kernel {}: {:?}", kernel_file.path, e)
+        });
+        debug!(
+            "Decompressed kernel to size {} KiB",
+            kernel_inflated.len() / 1024
+        );
+        &kernel_inflated[..]
+    } else {
+        kernel
+    };
+
     // Get ELF64 header
-    let elf_header = ElfHeader64::new(kernel[..mem::size_of::<ElfHeader64>()].try_into().unwrap())
+    // Panic if the kernel is too small or the header is invalid
+    let elf_header = ElfHeader64::from_slice(kernel)
         .unwrap_or_else(|e| panic!("Error while parsing Elf header: {:?}", e));
+    // Relocations are not applied yet, so position independent kernels are loaded at the
+    // addresses they are linked at
+    // Panic if a position independent kernel is not linked in the higher half, as it would have
+    // to be relocated there
+    if elf_header.is_pie() {
+        if (elf_header.entry as i64) >= 0 {
+            panic!(
+                "Kernel is position independent and linked at {}, but relocation is not supported",
+                Hex(elf_header.entry as u64)
+            );
+        }
+        warn!("Kernel is position independent, but it is loaded without relocation");
+    }
 
     // Load kernel executable
-    let (_loaded_kernel, _all_symbols, _ph_load) = load_elf(&elf_header, kernel);
+    // The segment permissions are kept for when the kernel is mapped
+    let LoadedKernel {
+        image: loaded_kernel,
+        symbols: all_symbols,
+        base: load_base,
+        align: load_align,
+        segments,
+        tls,
+    } = load_elf(&elf_header, kernel);
+    // Stripped kernels do not have any symbols
+    let has_symbol_table = all_symbols.is_some();
+    let all_symbols = all_symbols.unwrap_or([None; 4]);
+
+    // Panic if the BOOTBOOT struct does not fit in the kernel's bootboot symbol
+    // Symbols defined in linker scripts have no size, so they are not checked
+    if let Some(bootboot) = all_symbols[0] {
+        if bootboot.size != 0 && (bootboot.size as usize) < BOOTBOOT_STRUCT_SIZE {
+            panic!(
+                "Kernel: Symbol BOOTBOOT of size {} bytes is smaller than {} bytes",
+                bootboot.size, BOOTBOOT_STRUCT_SIZE
+            );
+        }
+    }
+    let entry_point = elf_header.entry_point().unwrap_or_else(|e| {
+        panic!(
+            "Kernel: Invalid entry point {}: {:?}",
+            Hex(elf_header.entry as u64),
+            e
+        )
+    });
+    debug!("Kernel entry point: {}", Hex(entry_point as u64));
+    let load_segments = segments.len();
+    // The thread-local storage template is only reported for now
+    if let Some(tls) = tls {
+        debug!(
+            "TLS segment at {} of size {} bytes ({} bytes initialized)",
+            Hex(tls.virt_addr() as u64),
+            tls.mem_size,
+            tls.file_size
+        );
+    }
+
+    // Use the protocol level from the environment, or detect it from the kernel's symbols
+    let has_dynamic_symbols = all_symbols[..3].iter().all(Option::is_some);
+    let protocol_level = select_protocol(env.protocol, has_symbol_table, has_dynamic_symbols);
+    debug!("Protocol level: {:?}", protocol_level);
+
+    // Copy kernel to its own pages, at the physical address from the environment if there is one
+    // The kernel is mapped at its virtual address, so any other pages work if that address is used
+    // Other pages are aligned like the kernel's segments, so large pages can map them
+    // Panic if the pages cannot be allocated
+    let kernel_pages = match env.load_addr {
+        Some(load_addr) => {
+            if load_addr % load_align as u64 != 0 {
+                warn!(
+                    "Load address {} is not aligned to the kernel's segment alignment of {} KiB",
+                    Hex(load_addr),
+                    load_align / 1024
+                );
+            }
+            alloc_phys(bt, loaded_kernel.len(), Some(load_addr)).or_else(|e| {
+                warn!(
+                    "Could not allocate kernel at load address {} ({:?}), loading it anywhere",
+                    Hex(load_addr),
+                    e.status()
+                );
+                alloc_phys_aligned(bt, loaded_kernel.len(), load_align)
+            })
+        }
+        None => alloc_phys_aligned(bt, loaded_kernel.len(), load_align),
+    }
+    .unwrap_or_else(|e| {
+        panic!(
+            "Could not allocate {} KiB for kernel: {:?}",
+            loaded_kernel.len() / 1024,
+            e.status()
+        )
+    });
+    kernel_pages.copy_from_slice(&loaded_kernel);
+    let kernel_mapping = KernelMapping {
+        virt: load_base as u64,
+        phys: kernel_pages.as_ptr() as u64,
+        size: kernel_pages.len() as u64,
+    };
+    match env.load_addr {
+        Some(load_addr) if load_addr != kernel_mapping.phys => info!(
+            "Loaded kernel at fallback physical address {} instead of {}",
+            Hex(kernel_mapping.phys),
+            Hex(load_addr)
+        ),
+        _ => debug!("Loaded kernel at address {}", Hex(kernel_mapping.phys)),
+    }
+    debug!(
+        "Kernel is at virtual address {} ({} pages)",
+        Hex(kernel_mapping.virt),
+        kernel_mapping.pages().count()
+    );
+    let loaded_kernel = kernel_pages;
+
+    // Allocate pages for the BOOTBOOT struct and environment, which are written before handoff
+    // Panic if the pages cannot be allocated
+    let bootboot_page: &mut [u8; BOOTBOOT_INFO_SIZE] = alloc_phys(bt, BOOTBOOT_INFO_SIZE, None)
+        .expect("Could not allocate page for BOOTBOOT struct")
+        .try_into()
+        .unwrap();
+    let env_page: &mut [u8; ENVIRONMENT_PAGE_SIZE] = alloc_phys(bt, ENVIRONMENT_PAGE_SIZE, None)
+        .expect("Could not allocate page for environment")
+        .try_into()
+        .unwrap();
+    debug!(
+        "BOOTBOOT struct at {}, environment at {}",
+        Hex(bootboot_page.as_ptr() as u64),
+        Hex(env_page.as_ptr() as u64)
+    );
+    // Copy the initrd to its own pages, as it was read into pool memory
+    // At least one page is allocated, as the initrd may be empty
+    // Panic if the pages cannot be allocated
+    let initrd_pages =
+        alloc_phys(bt, initrd.size().max(1), None).expect("Could not allocate pages for initrd");
+    initrd_pages[..initrd.size()].copy_from_slice(initrd.as_bytes());
+    debug!("Initrd copied to {}", Hex(initrd_pages.as_ptr() as u64));
 
     // Get memory map from UEFI
+    // The buffer is allocated in pages, as it is still used after exiting boot services
+    BootPhase::Mmap.enter();
     let mmap_size = bt.memory_map_size();
     let entry_size = mmap_size.entry_size;
-    let mmap_size = mmap_size.map_size + 2 * entry_size;
-    let buffer = bt
-        .allocate_pool(MemoryType::LOADER_DATA, mmap_size)
-        .expect("Could not allocate pool for memory map");
-    let buffer = unsafe { slice::from_raw_parts_mut(buffer, mmap_size) };
+    let mmap_size = mmap_size.map_size + MMAP_EXTRA_ENTRIES * entry_size;
+    let buffer = alloc_phys(bt, mmap_size, None).expect("Could not allocate pages for memory map");
     let (_key, desc_iter) = bt
         .memory_map(buffer)
         .expect("Failed to get UEFI memory map");
 
     // Convert UEFI memory map to BOOTBOOT memory map
-    let mmap = BootbootMMap::from_uefi_mmap(desc_iter);
+    // ACPI reclaim memory is kept, as the kernel gets a pointer to the ACPI tables stored in it
+    let mut mmap = BootbootMMap::from_uefi_mmap(desc_iter, false);
+    // Make room to convert the final memory map after exiting boot services without allocating
+    mmap.reserve(mmap_size / entry_size + MMAP_EXTRA_ENTRIES);
+
+    // Reserve every region allocated with `alloc_phys`, which includes everything passed to the
+    // kernel
+    // No page tables are allocated yet, and the TLS template is part of the loaded kernel
+    let loader_regions = [
+        (initrd_pages.as_ptr() as u64, initrd_pages.len() as u64),
+        (loaded_kernel.as_ptr() as u64, loaded_kernel.len() as u64),
+        (bootboot_page.as_ptr() as u64, BOOTBOOT_INFO_SIZE as u64),
+        (env_page.as_ptr() as u64, ENVIRONMENT_PAGE_SIZE as u64),
+        (buffer.as_ptr() as u64, buffer.len() as u64),
+    ];
+    for (base, size) in loader_regions {
+        mmap.mark_used(base, size);
+    }
     debug!("{}", mmap);
+    // Check for bugs in the memory map conversion
+    if cfg!(debug_assertions) {
+        if let Err(e) = mmap.validate() {
+            panic!("Invalid memory map: {:?}", e);
+        }
+    }
+    // Every allocation made with `alloc_phys` should be marked as used
+    let loader_memory = total_loader_memory();
+    if mmap.marked_size() != loader_memory {
+        warn!(
+            "Marked {} KiB of memory as used, but the loader allocated {} KiB",
+            mmap.marked_size() / 1024,
+            loader_memory / 1024
+        );
+    }
+    // Extra MMIO ranges must not cover memory that the kernel may use
+    // Paging is not implemented yet, so valid ranges are only logged
+    if !env.mmio_maps.is_empty() {
+        warn!("Extra MMIO ranges are not mapped, as paging is not implemented yet");
+    }
+    let mmio_maps: Vec<MmioMap> = env
+        .mmio_maps
+        .iter()
+        .copied()
+        .filter(|map| {
+            let valid = mmap.is_device_region(map.phys, map.size);
+            if !valid {
+                warn!(
+                    "Ignoring MMIO range {} of {} KiB, as it overlaps usable memory",
+                    Hex(map.phys),
+                    map.size / 1024
+                );
+            }
+            valid
+        })
+        .collect();
+    for map in &mmio_maps {
+        debug!(
+            "MMIO range {} of {} KiB at virtual address {}",
+            Hex(map.phys),
+            map.size / 1024,
+            Hex(map.virt)
+        );
+    }
+    let total_size = |regions: &mut dyn Iterator<Item = &mmap::MMapEntry>| {
+        regions.map(|entry| entry.size()).sum::<u64>() / 1024
+    };
+    debug!(
+        "Memory: free={} KiB, used={} KiB, acpi={} KiB, mmio={} KiB",
+        total_size(&mut mmap.free_regions()),
+        total_size(&mut mmap.used_regions()),
+        total_size(&mut mmap.acpi_regions()),
+        total_size(&mut mmap.mmio_regions())
+    );
+
+    // Create BOOTBOOT header
+    BootPhase::Handoff.enter();
+    let mut arch = BootbootArchX86_64::default();
+    arch.efi_ptr = st.as_ptr() as u64;
+    debug!("EFI system table: {}", Hex(arch.efi_ptr));
+    // Tables that were not found or are disabled are passed as null pointers
+    arch.acpi_ptr = acpi_table.map_or(0, |acpi| acpi.rsdp_addr);
+    arch.smbi_ptr = smbios_table.map_or(0, |table| table as *const _ as u64);
+    let protocol = protocol_level as u8 | (LoaderType::Uefi as u8) << 2;
+    let summary = BootSummary {
+        kernel: &kernel_file.path,
+        kernel_size: kernel_file.data.len(),
+        entry_point: entry_point as u64,
+        load_segments,
+        initrd_size: initrd.size(),
+        initrd_format: initrd.format(),
+        framebuffer: &framebuffer,
+        total_memory: mmap
+            .entries()
+            .iter()
+            .filter(|entry| entry.memory_type() != mmap::MMapEntryType::Mmio)
+            .map(|entry| entry.size())
+            .sum(),
+        free_memory: mmap.free_regions().map(|entry| entry.size()).sum(),
+        loader_memory,
+        acpi: acpi_table.is_some(),
+        smbios: smbios_table.is_some(),
+        // SMP is not supported yet, so only the bootstrap processor is started
+        num_cores: 1,
+        time: boot_time,
+    };
+    // Print every detail in debug builds and only the most important ones in release builds
+    if level >= LevelFilter::Debug {
+        info!("{:#}", summary);
+    } else {
+        info!("{}", summary);
+    }
+    let (datetime, timezone) = boot_time
+        .as_ref()
+        .map_or(([0; 8], 0), time::bootboot_datetime);
+    let info = BootbootInfo {
+        protocol,
+        framebuffer: &framebuffer,
+        // SMP is not supported yet, so only the bootstrap processor is started
+        numcores: 1,
+        // BOOTBOOT only has room for 16bit IDs
+        bspid: arch::core_id() as u16,
+        timezone,
+        datetime,
+        initrd_ptr: initrd_pages.as_ptr() as u64,
+        initrd_size: initrd.size() as u64,
+        arch,
+    };
+    // Write the BOOTBOOT struct now so that a dry run can be inspected, and again after exiting
+    // boot services with the final memory map
+    let image = BootbootImage::build(&info, &mmap, &env);
+    bootboot_page.copy_from_slice(&image.info);
+    env_page.copy_from_slice(&image.env);
+
+    // Log the page tables in debug builds if the environment asks for it
+    // These are the firmware's page tables, as paging is not implemented and the loader does not
+    // build any of its own
+    #[cfg(target_arch = "x86_64")]
+    if cfg!(debug_assertions) && env.dump_page_tables {
+        debug!("Dumping firmware page tables, as the loader does not build page tables yet");
+        // Memory is identity mapped while boot services are active
+        unsafe { arch::paging::dump(arch::paging::current_pml4()) };
+    }
+
+    // Stop before exiting boot services if this is a dry run
+    if env.dry_run {
+        info!(
+            "Dry run complete: kernel {} ({} KiB loaded), {} memory map entries",
+            kernel_file.path,
+            loaded_kernel.len() / 1024,
+            mmap.entries().len()
+        );
+        return Status::SUCCESS;
+    }
+
+    // Exit boot services
+    // Nothing may allocate between getting the final memory map and exiting boot services, so
+    // everything passed to the kernel is allocated before this
+    // If the memory map changed before exiting, the memory map is fetched again and exiting is
+    // retried
+    info!("Exiting boot services");
+    Framebuffer::set_unmapped();
+    logger::exit_boot_services();
+    let (_st, desc_iter) = st
+        .exit_boot_services(image_handle, buffer)
+        .expect("Could not exit boot services");
+
+    // Convert the final memory map, which does not allocate as room was reserved for it
+    // ACPI reclaim memory is still kept for the kernel's ACPI table pointer
+    mmap.refill_from_uefi_mmap(desc_iter, false);
+    for (base, size) in loader_regions {
+        mmap.mark_used(base, size);
+    }
+    let image = BootbootImage::build(&info, &mmap, &env);
+    bootboot_page.copy_from_slice(&image.info);
 
     panic!("Bootloader done (this will be removed when os loading is implemented)");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{test_symbols, TestElf};
+
+    /// Address that test kernels are linked at.
+    const KERNEL_BASE: usize = 0xffff_ffff_ffe0_0000;
+    /// Code of test kernels, which is a few `nop` instructions.
+    const CODE: [u8; 16] = [0x90; 16];
+
+    /// Returns a test kernel with one LOAD segment at [`KERNEL_BASE`] and `sections`.
+    fn kernel_with_sections(sections: Vec<(&str, u32, &[u8])>) -> Vec<u8> {
+        TestElf {
+            entry: KERNEL_BASE,
+            segments: vec![(ELF_PH_TYPE_LOAD, KERNEL_BASE, 0x2000, &CODE)],
+            sections,
+            ..Default::default()
+        }
+        .build()
+    }
+
+    #[test]
+    fn load_elf_without_section_headers() {
+        let kernel = kernel_with_sections(vec![]);
+        let elf_header = ElfHeader64::from_slice(&kernel).unwrap();
+        assert_eq!(elf_header.sh_num, 0);
+
+        let loaded = load_elf(&elf_header, &kernel);
+        assert!(loaded.symbols.is_none());
+        assert_eq!(loaded.base, KERNEL_BASE);
+        assert_eq!(loaded.segments.len(), 1);
+        assert!(loaded.tls.is_none());
+        assert_eq!(loaded.image.len(), 0x2000);
+        assert_eq!(loaded.image[..CODE.len()], CODE);
+        assert!(loaded.image[CODE.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "LOAD segment 1 is invalid: InvalidSize")]
+    fn load_elf_with_segment_larger_in_file_than_in_memory() {
+        let data = [0; 0x20];
+        let kernel = TestElf {
+            entry: KERNEL_BASE,
+            segments: vec![
+                (ELF_PH_TYPE_LOAD, KERNEL_BASE, 0x1000, &CODE),
+                (ELF_PH_TYPE_LOAD, KERNEL_BASE + 0x1000, 0x10, &data),
+            ],
+            ..Default::default()
+        }
+        .build();
+        let elf_header = ElfHeader64::from_slice(&kernel).unwrap();
+        let (_, program_headers) = elf_header.get_headers(&kernel).unwrap();
+        assert!(program_headers[0].validate(&kernel).is_ok());
+        assert!(matches!(
+            program_headers[1].validate(&kernel),
+            Err(ElfParseError::InvalidSize)
+        ));
+
+        load_elf(&elf_header, &kernel);
+    }
+
+    #[test]
+    fn load_elf_with_invalid_section_name_index() {
+        let (symtab, strtab) = test_symbols(&[("bootboot", KERNEL_BASE, ELF_SYM_BIND_GLOBAL)]);
+        let mut kernel = kernel_with_sections(vec![
+            (".symtab", ELF_SH_TYPE_SYMTAB, &symtab),
+            (".strtab", ELF_SH_TYPE_STRTAB, &strtab),
+        ]);
+        let elf_header = ElfHeader64::from_slice(&kernel).unwrap();
+        assert!(load_elf(&elf_header, &kernel).symbols.is_some());
+
+        // Point the section name string table index past the last section
+        kernel[62..64].copy_from_slice(&u16::MAX.to_le_bytes());
+        let elf_header = ElfHeader64::from_slice(&kernel).unwrap();
+        assert!(load_elf(&elf_header, &kernel).symbols.is_none());
+    }
+
+    #[test]
+    fn load_elf_with_tls_segment() {
+        let tdata = [0xaa; 8];
+        let kernel = TestElf {
+            entry: KERNEL_BASE,
+            segments: vec![
+                (ELF_PH_TYPE_LOAD, KERNEL_BASE, 0x2000, &CODE),
+                (ELF_PH_TYPE_TLS, KERNEL_BASE + 0x1000, 0x20, &tdata),
+            ],
+            ..Default::default()
+        }
+        .build();
+        let elf_header = ElfHeader64::from_slice(&kernel).unwrap();
+
+        let loaded = load_elf(&elf_header, &kernel);
+        assert_eq!(loaded.segments.len(), 1);
+        let tls = loaded.tls.unwrap();
+        assert_eq!(tls.virt_addr(), KERNEL_BASE + 0x1000);
+        assert_eq!(tls.file_size, tdata.len());
+        assert_eq!(tls.mem_size, 0x20);
+    }
+
+    #[test]
+    fn select_protocol_forced_static() {
+        for (has_symbol_table, has_dynamic_symbols) in [(false, false), (true, false), (true, true)]
+        {
+            assert_eq!(
+                select_protocol(
+                    Some(BootbootProtocolLevel::Static),
+                    has_symbol_table,
+                    has_dynamic_symbols
+                ),
+                BootbootProtocolLevel::Static
+            );
+        }
+    }
+
+    #[test]
+    fn select_protocol_forced_dynamic_with_symbols() {
+        assert_eq!(
+            select_protocol(Some(BootbootProtocolLevel::Dynamic), true, true),
+            BootbootProtocolLevel::Dynamic
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Dynamic protocol requires a symbol table")]
+    fn select_protocol_forced_dynamic_without_symbol_table() {
+        select_protocol(Some(BootbootProtocolLevel::Dynamic), false, false);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Dynamic protocol requires the bootboot, environment, and fb symbols"
+    )]
+    fn select_protocol_forced_dynamic_without_symbols() {
+        select_protocol(Some(BootbootProtocolLevel::Dynamic), true, false);
+    }
+
+    #[test]
+    fn select_protocol_detected() {
+        assert_eq!(
+            select_protocol(None, true, true),
+            BootbootProtocolLevel::Dynamic
+        );
+        assert_eq!(
+            select_protocol(None, true, false),
+            BootbootProtocolLevel::Static
+        );
+        assert_eq!(
+            select_protocol(None, false, false),
+            BootbootProtocolLevel::Static
+        );
+    }
+}
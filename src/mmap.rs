@@ -2,53 +2,329 @@ use alloc::vec::Vec;
 use core::{
     cmp::Ordering,
     fmt::{self, Display, Formatter},
+    mem,
 };
+use log::debug;
 use uefi::table::boot::{MemoryDescriptor, MemoryType};
 
+use crate::{utils::Hex, BOOTBOOT_STRUCT_SIZE};
+
+const PAGE_SIZE: u64 = 4096;
+/// Maximum number of entries, as the memory map fills the rest of the BOOTBOOT struct's page.
+pub const MMAP_MAX_ENTRIES: usize =
+    (PAGE_SIZE as usize - BOOTBOOT_STRUCT_SIZE) / mem::size_of::<MMapEntry>();
+
+/// An invalid BOOTBOOT memory map.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MMapError {
+    /// The entry at this index has a size of 0
+    EmptyEntry(usize),
+    /// The entry at this index overlaps the entry before it
+    Overlapping(usize),
+    /// The entry at this index starts before the entry before it
+    NotSorted(usize),
+    /// There are more entries than fit in the BOOTBOOT struct
+    TooManyEntries,
+}
+
 /// A BOOTBOOT memory map.
 pub struct BootbootMMap {
     mmap: Vec<MMapEntry>,
+    /// Total size of the regions passed to [`BootbootMMap::mark_used`], expanded to pages
+    marked_size: u64,
 }
 
 impl BootbootMMap {
     /// Converts a UEFI memory map to a BOOTBOOT memory map.
     ///
-    /// The memory map entries are also sorted and merged.
-    pub fn from_uefi_mmap<'b, MMap>(uefi_mmap: MMap) -> Self
+    /// If `reclaim_acpi` is true, ACPI reclaim memory is marked as [`MMapEntryType::Free`] instead
+    /// of [`MMapEntryType::Acpi`]. This should only be set if nothing after the bootloader needs to
+    /// read the ACPI tables, as they are stored in this memory. ACPI NVS memory is never reclaimed.
+    /// The loader itself always passes false, as the kernel is given a pointer to the ACPI tables.
+    ///
+    /// The memory map entries are also sorted and merged. If any UEFI memory descriptors overlap,
+    /// the overlapping part is given to the entry with the higher priority type (see
+    /// [`MMapEntryType::priority`]), so that no entries overlap.
+    pub fn from_uefi_mmap<'b, MMap>(uefi_mmap: MMap, reclaim_acpi: bool) -> Self
     where
         MMap: ExactSizeIterator<Item = &'b MemoryDescriptor> + Clone,
     {
-        // Allocate and convert UEFI memory map
-        let mut mmap = Vec::with_capacity(248);
+        let mut mmap = Self {
+            mmap: Vec::with_capacity(248),
+            marked_size: 0,
+        };
+        mmap.refill_from_uefi_mmap(uefi_mmap, reclaim_acpi);
+        mmap
+    }
+
+    /// Replaces every entry in this memory map with a newly converted UEFI memory map.
+    ///
+    /// This reuses the memory already allocated for entries, so the memory map can be converted
+    /// again right before exiting boot services without allocating in most cases. Only the map
+    /// converted last is accurate, so regions marked with [`BootbootMMap::mark_used`] have to be
+    /// marked again after this.
+    ///
+    /// See [`BootbootMMap::from_uefi_mmap`] for how the UEFI memory map is converted.
+    pub fn refill_from_uefi_mmap<'b, MMap>(&mut self, uefi_mmap: MMap, reclaim_acpi: bool)
+    where
+        MMap: ExactSizeIterator<Item = &'b MemoryDescriptor> + Clone,
+    {
+        // Convert UEFI memory map
+        self.marked_size = 0;
+        let mmap = &mut self.mmap;
+        mmap.clear();
+        mmap.reserve(uefi_mmap.len());
         for desc in uefi_mmap {
+            // Drop empty entries first, so the entries around them can be merged
+            if desc.page_count == 0 {
+                continue;
+            }
+            let ty = match desc.ty {
+                MemoryType::ACPI_RECLAIM if reclaim_acpi => MMapEntryType::Free,
+                ty => MMapEntryType::from_uefi(ty),
+            };
             // TODO: Return error if entry fails to be created
-            let entry = MMapEntry::new(
-                desc.phys_start,
-                desc.page_count * 4096,
-                MMapEntryType::from_uefi(desc.ty),
-            )
-            .unwrap();
+            let entry = MMapEntry::new(desc.phys_start, desc.page_count * PAGE_SIZE, ty).unwrap();
             mmap.push(entry);
         }
 
-        // Sort entries
-        mmap.sort();
+        // Sort entries without allocating
+        mmap.sort_unstable();
+
+        // Clip overlapping entries
+        // Only the overlapping parts are dropped, so every other byte has to be kept
+        let total = total_size(mmap);
+        let dropped = clip_overlaps(mmap);
+        debug_assert_eq!(total_size(mmap), total.wrapping_sub(dropped));
 
         // Merge entries
-        let mut merge_mmap = Vec::with_capacity(mmap.len());
-        merge_mmap.push(mmap[0]);
-        for entry in mmap[1..].iter() {
-            if let Some(merge_entry) = merge_mmap.last().unwrap().merge(entry) {
-                *merge_mmap.last_mut().unwrap() = merge_entry;
+        let total = total_size(mmap);
+        merge_entries(mmap);
+        debug_assert_eq!(total_size(mmap), total);
+    }
+
+    /// Reserves room for at least `additional` more entries.
+    ///
+    /// Refilling the memory map and marking regions as used do not allocate if there is enough
+    /// room, which is required after exiting boot services.
+    pub fn reserve(&mut self, additional: usize) {
+        self.mmap.reserve(additional);
+    }
+
+    /// Ensures that every entry is sorted, does not overlap other entries, and is not empty, and
+    /// that every entry fits in the BOOTBOOT struct.
+    ///
+    /// # Errors
+    ///
+    /// * `MMapError::EmptyEntry`: An entry has a size of 0
+    /// * `MMapError::Overlapping`: An entry overlaps the entry before it
+    /// * `MMapError::NotSorted`: An entry starts before the entry before it
+    /// * `MMapError::TooManyEntries`: There are more than [`MMAP_MAX_ENTRIES`] entries
+    pub fn validate(&self) -> Result<(), MMapError> {
+        if let Some(i) = self.mmap.iter().position(|entry| entry.size() == 0) {
+            return Err(MMapError::EmptyEntry(i));
+        }
+        for (i, entries) in self.mmap.windows(2).enumerate() {
+            let (prev, entry) = (entries[0], entries[1]);
+            if entry.ptr < prev.ptr {
+                return Err(MMapError::NotSorted(i + 1));
+            }
+            if entry.ptr < prev.ptr + prev.size() {
+                return Err(MMapError::Overlapping(i + 1));
+            }
+        }
+        if self.mmap.len() > MMAP_MAX_ENTRIES {
+            return Err(MMapError::TooManyEntries);
+        }
+        Ok(())
+    }
+
+    /// Returns the total size in bytes of every region marked as used since this memory map was
+    /// converted, with each region expanded to page boundaries.
+    pub fn marked_size(&self) -> u64 {
+        self.marked_size
+    }
+
+    /// Returns true if no part of the region from `base` to `base + size` is free or ACPI memory.
+    ///
+    /// Device memory is often left out of the memory map, so parts of the region that are not in
+    /// any entry are allowed.
+    pub fn is_device_region(&self, base: u64, size: u64) -> bool {
+        let end = base.saturating_add(size);
+        !self.mmap.iter().any(|entry| {
+            entry.ptr < end
+                && base < entry.ptr + entry.size()
+                && matches!(
+                    entry.memory_type(),
+                    MMapEntryType::Free | MMapEntryType::Acpi
+                )
+        })
+    }
+
+    /// Returns every entry in this memory map, sorted by address.
+    pub fn entries(&self) -> &[MMapEntry] {
+        &self.mmap
+    }
+
+    /// Returns an iterator over every entry of type `ty`.
+    pub fn regions(&self, ty: MMapEntryType) -> impl Iterator<Item = &MMapEntry> {
+        self.mmap
+            .iter()
+            .filter(move |entry| entry.memory_type() == ty)
+    }
+
+    /// Returns an iterator over every [`MMapEntryType::Free`] entry.
+    pub fn free_regions(&self) -> impl Iterator<Item = &MMapEntry> {
+        self.regions(MMapEntryType::Free)
+    }
+
+    /// Returns an iterator over every [`MMapEntryType::Used`] entry.
+    pub fn used_regions(&self) -> impl Iterator<Item = &MMapEntry> {
+        self.regions(MMapEntryType::Used)
+    }
+
+    /// Returns an iterator over every [`MMapEntryType::Acpi`] entry.
+    pub fn acpi_regions(&self) -> impl Iterator<Item = &MMapEntry> {
+        self.regions(MMapEntryType::Acpi)
+    }
+
+    /// Returns an iterator over every [`MMapEntryType::Mmio`] entry.
+    pub fn mmio_regions(&self) -> impl Iterator<Item = &MMapEntry> {
+        self.regions(MMapEntryType::Mmio)
+    }
+
+    /// Marks the memory region starting at `base` with a size of `size` bytes as
+    /// [`MMapEntryType::Used`].
+    ///
+    /// The region is expanded to page boundaries. Entries that partially overlap the region are
+    /// split so that only the overlapping part is relabeled.
+    ///
+    /// This should be called for every region allocated by the bootloader that is still needed
+    /// after passing control to the kernel; otherwise the kernel may see the region as free memory.
+    pub fn mark_used(&mut self, base: u64, size: u64) {
+        if size == 0 {
+            return;
+        }
+
+        // Expand region to page boundaries
+        let end = base.saturating_add(size).saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let base = base & !(PAGE_SIZE - 1);
+        self.marked_size += end - base;
+
+        let mut i = 0;
+        while i < self.mmap.len() {
+            let entry = self.mmap[i];
+            let entry_end = entry.ptr + entry.size();
+            let ty = entry.memory_type();
+
+            // Skip entries that do not overlap the region or are already used
+            if entry_end <= base || entry.ptr >= end || ty == MMapEntryType::Used {
+                i += 1;
+                continue;
+            }
+
+            // Split entry into the parts before, inside, and after the region
+            let used_start = entry.ptr.max(base);
+            let used_end = entry_end.min(end);
+            let parts = [
+                (entry.ptr, used_start - entry.ptr, ty),
+                (used_start, used_end - used_start, MMapEntryType::Used),
+                (used_end, entry_end - used_end, ty),
+            ];
+
+            // Replace entry with the non-empty parts
+            self.mmap.remove(i);
+            for (ptr, size, ty) in parts.into_iter().filter(|(_, size, _)| *size > 0) {
+                // Parts are never larger than the original entry, so this cannot fail
+                self.mmap.insert(i, MMapEntry::new(ptr, size, ty).unwrap());
+                i += 1;
+            }
+        }
+
+        // Merge the used region with any adjacent used entries
+        merge_entries(&mut self.mmap);
+    }
+}
+
+/// Removes the overlapping parts of entries in the sorted `mmap`, keeping the entries sorted.
+///
+/// When two entries overlap, the overlapping part is kept in the entry with the higher priority
+/// type, and the other entry is shrunk, split, or removed.
+///
+/// Returns the number of bytes dropped, which is the total size of the overlapping parts.
+fn clip_overlaps(mmap: &mut Vec<MMapEntry>) -> u64 {
+    let mut dropped: u64 = 0;
+
+    // Empty entries are never needed and would otherwise split entries that they overlap
+    mmap.retain(|entry| entry.size() > 0);
+
+    // Every entry before `i` is sorted and does not overlap any other entry before `i`
+    let mut i = 1;
+    while i < mmap.len() {
+        let prev = mmap[i - 1];
+        let entry = mmap[i];
+        let prev_end = prev.ptr + prev.size();
+        let entry_end = entry.ptr + entry.size();
+        if entry.ptr >= prev_end {
+            i += 1;
+            continue;
+        }
+        debug!(
+            "Memory map entries overlap: {} ({}) and {} ({})",
+            Hex(prev.ptr),
+            prev.memory_type(),
+            Hex(entry.ptr),
+            entry.memory_type()
+        );
+        dropped = dropped.wrapping_add(prev_end.min(entry_end) - entry.ptr);
+
+        // Parts are never larger than the original entries, so this cannot fail
+        let part = |ptr, end, ty| MMapEntry::new(ptr, end - ptr, ty).unwrap();
+        if prev.memory_type().priority() >= entry.memory_type().priority() {
+            // Keep the part of the entry after the previous entry
+            if entry_end > prev_end {
+                mmap[i] = part(prev_end, entry_end, entry.memory_type());
             } else {
-                merge_mmap.push(*entry)
+                mmap.remove(i);
+            }
+        } else {
+            // Keep the parts of the previous entry before and after the entry
+            mmap[i - 1] = part(prev.ptr, entry.ptr, prev.memory_type());
+            if prev_end > entry_end {
+                mmap.push(part(entry_end, prev_end, prev.memory_type()));
+            }
+            if mmap[i - 1].size() == 0 {
+                mmap.remove(i - 1);
             }
         }
-        mmap.clear();
-        mmap.extend_from_slice(&merge_mmap);
 
-        Self { mmap }
+        // Clipped entries may have moved, so sort again and recheck from the previous entry
+        mmap.sort_unstable();
+        i = i.saturating_sub(1).max(1);
     }
+
+    dropped
+}
+
+/// Returns the total size in bytes of the entries in `mmap`.
+///
+/// The total wraps on overflow, as it is only used to check that no bytes are gained or lost.
+fn total_size(mmap: &[MMapEntry]) -> u64 {
+    mmap.iter()
+        .fold(0, |total: u64, entry| total.wrapping_add(entry.size()))
+}
+
+/// Merges every pair of sequential entries with the same type in the sorted `mmap`.
+///
+/// Entries are merged in place, so this never allocates.
+fn merge_entries(mmap: &mut Vec<MMapEntry>) {
+    mmap.dedup_by(|entry, prev| match prev.merge(entry) {
+        Some(merged) => {
+            *prev = merged;
+            true
+        }
+        None => false,
+    });
 }
 
 impl Display for BootbootMMap {
@@ -57,9 +333,9 @@ impl Display for BootbootMMap {
         for entry in self.mmap.iter() {
             write!(
                 f,
-                "\nAddr: {:08x?} Size: {:08x?} Type: {}",
-                entry.ptr,
-                entry.size(),
+                "\nAddr: {} Size: {} Type: {}",
+                Hex(entry.ptr),
+                Hex(entry.size()),
                 entry.memory_type()
             )?;
         }
@@ -80,6 +356,10 @@ pub enum MMapEntryType {
 
 impl MMapEntryType {
     /// Converts UEFI memory type to BOOTBOOT memory type.
+    ///
+    /// This mapping is lossy, as BOOTBOOT only has four memory types. Notably, ACPI reclaim memory
+    /// (which can be freed after the ACPI tables are parsed) and ACPI NVS memory (which must never
+    /// be touched) are both converted to [`MMapEntryType::Acpi`].
     pub fn from_uefi(ty: MemoryType) -> Self {
         match ty {
             MemoryType::RESERVED
@@ -99,6 +379,21 @@ impl MMapEntryType {
         }
     }
 
+    /// Returns the priority of this memory type, used to decide which entry keeps an overlapping
+    /// region.
+    ///
+    /// Free memory has the lowest priority, so memory is never handed to the kernel as free if any
+    /// descriptor says otherwise.
+    pub fn priority(&self) -> u8 {
+        match self {
+            Self::Free => 0,
+            Self::Mmio => 1,
+            Self::Acpi => 2,
+            Self::Unknown => 3,
+            Self::Used => 4,
+        }
+    }
+
     /// Creates a memory type from u8 value.
     pub fn new(ty: u8) -> Self {
         match ty {
@@ -129,17 +424,29 @@ impl Display for MMapEntryType {
 }
 
 /// BOOTBOOT memory map entry.
+///
+/// Entries are ordered by address, then by size, then by type, so that sorting is deterministic
+/// even if buggy firmware reports multiple entries at the same address.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, Eq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct MMapEntry {
     ptr: u64,
     size: u64,
 }
 
 impl MMapEntry {
-    /// Adds `other`'s size to this entry's size;
-    pub fn add_size(&mut self, other: &Self) {
-        self.size += other.size() << 4;
+    /// Adds `other`'s size to this entry's size.
+    ///
+    /// Returns an error and leaves this entry unchanged if the combined size does not fit in the
+    /// 60bit size field.
+    pub fn add_size(&mut self, other: &Self) -> Result<(), ()> {
+        let size = self.size().checked_add(other.size()).ok_or(())?;
+        if size > u64::MAX >> 4 {
+            return Err(());
+        }
+
+        self.size = (size << 4) | (self.size & 0xf);
+        Ok(())
     }
 
     /// Returns true if `other` is the entry directly after this one.
@@ -147,6 +454,11 @@ impl MMapEntry {
         self.ptr + self.size() == other.ptr && self.memory_type() == other.memory_type()
     }
 
+    /// Returns the physical address of the entry.
+    pub fn ptr(&self) -> u64 {
+        self.ptr
+    }
+
     /// Returns the type of memory map entry.
     pub fn memory_type(&self) -> MMapEntryType {
         MMapEntryType::new((self.size & 0xf) as u8)
@@ -154,14 +466,15 @@ impl MMapEntry {
 
     /// Returns this entry merged with `other` if they are sequential entries.
     ///
-    /// Returns `None` if they are `other` is not an entry directly after this one.
+    /// Returns `None` if `other` is not an entry directly after this one, or if the merged entry
+    /// would be too large to store.
     pub fn merge(&self, other: &Self) -> Option<Self> {
         if !self.is_next(other) {
             return None;
         }
 
         let mut merged = *self;
-        merged.add_size(other);
+        merged.add_size(other).ok()?;
         Some(merged)
     }
 
@@ -178,6 +491,20 @@ impl MMapEntry {
         Ok(Self { ptr, size })
     }
 
+    /// Returns the entry as it is stored in the BOOTBOOT memory map; the address followed by the
+    /// size and type, both little endian.
+    ///
+    /// BOOTBOOT stores the size in bytes with the type in its lowest 4 bits, which are always zero
+    /// for a real size. This is not the same as the `size` field here, which is shifted left to
+    /// keep the lowest 4 bits of any size.
+    pub fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.ptr.to_le_bytes());
+        let size = (self.size() & !0xf) | self.memory_type() as u64;
+        bytes[8..].copy_from_slice(&size.to_le_bytes());
+        bytes
+    }
+
     /// Returns the size of the entry in bytes.
     pub fn size(&self) -> u64 {
         self.size >> 4
@@ -186,7 +513,8 @@ impl MMapEntry {
 
 impl Ord for MMapEntry {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.ptr.cmp(&other.ptr)
+        // The size field stores the size above the type, so this ties on size and then on type
+        self.ptr.cmp(&other.ptr).then(self.size.cmp(&other.size))
     }
 }
 
@@ -196,8 +524,264 @@ impl PartialOrd for MMapEntry {
     }
 }
 
-impl PartialEq for MMapEntry {
-    fn eq(&self, other: &Self) -> bool {
-        self.ptr == other.ptr
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Creates a memory map with `entries` as they are, without converting them.
+    fn mmap_of(entries: &[(u64, u64, MMapEntryType)]) -> BootbootMMap {
+        BootbootMMap {
+            mmap: entries
+                .iter()
+                .map(|&(ptr, size, ty)| MMapEntry::new(ptr, size, ty).unwrap())
+                .collect(),
+            marked_size: 0,
+        }
+    }
+
+    /// Converts a UEFI memory map made of the address, page count, and type of each descriptor.
+    fn convert(descriptors: &[(u64, u64, MemoryType)]) -> BootbootMMap {
+        convert_reclaiming(descriptors, false)
+    }
+
+    /// Converts a UEFI memory map like [`convert`], reclaiming ACPI memory if `reclaim_acpi` is
+    /// true.
+    fn convert_reclaiming(
+        descriptors: &[(u64, u64, MemoryType)],
+        reclaim_acpi: bool,
+    ) -> BootbootMMap {
+        let descriptors: Vec<MemoryDescriptor> = descriptors
+            .iter()
+            .map(|&(phys_start, page_count, ty)| {
+                let mut desc = MemoryDescriptor::default();
+                desc.ty = ty;
+                desc.phys_start = phys_start;
+                desc.page_count = page_count;
+                desc
+            })
+            .collect();
+        BootbootMMap::from_uefi_mmap(descriptors.iter(), reclaim_acpi)
+    }
+
+    /// Returns the address, size, and type of every entry in `mmap`.
+    fn entries_of(mmap: &BootbootMMap) -> Vec<(u64, u64, MMapEntryType)> {
+        mmap.entries()
+            .iter()
+            .map(|entry| (entry.ptr(), entry.size(), entry.memory_type()))
+            .collect()
+    }
+
+    #[test]
+    fn mark_used_splits_free_entry() {
+        let mut mmap = mmap_of(&[(0x0, 0x10000, MMapEntryType::Free)]);
+        mmap.mark_used(0x4000, 0x2000);
+        assert_eq!(
+            entries_of(&mmap),
+            vec![
+                (0x0, 0x4000, MMapEntryType::Free),
+                (0x4000, 0x2000, MMapEntryType::Used),
+                (0x6000, 0xa000, MMapEntryType::Free),
+            ]
+        );
+        assert_eq!(mmap.marked_size(), 0x2000);
+        assert_eq!(mmap.validate(), Ok(()));
+    }
+
+    #[test]
+    fn mark_used_spans_multiple_entries() {
+        let mut mmap = mmap_of(&[
+            (0x0, 0x4000, MMapEntryType::Free),
+            (0x4000, 0x4000, MMapEntryType::Acpi),
+            (0x8000, 0x4000, MMapEntryType::Free),
+        ]);
+        mmap.mark_used(0x2000, 0x8000);
+        assert_eq!(
+            entries_of(&mmap),
+            vec![
+                (0x0, 0x2000, MMapEntryType::Free),
+                (0x2000, 0x8000, MMapEntryType::Used),
+                (0xa000, 0x2000, MMapEntryType::Free),
+            ]
+        );
+    }
+
+    #[test]
+    fn mark_used_relabels_whole_entry_and_merges() {
+        let mut mmap = mmap_of(&[
+            (0x0, 0x1000, MMapEntryType::Used),
+            (0x1000, 0x1000, MMapEntryType::Free),
+            (0x2000, 0x1000, MMapEntryType::Used),
+        ]);
+        mmap.mark_used(0x1000, 0x1000);
+        assert_eq!(entries_of(&mmap), vec![(0x0, 0x3000, MMapEntryType::Used)]);
+    }
+
+    #[test]
+    fn mark_used_expands_to_pages() {
+        let mut mmap = mmap_of(&[(0x0, 0x10000, MMapEntryType::Free)]);
+        mmap.mark_used(0x1ff0, 0x20);
+        assert_eq!(
+            entries_of(&mmap),
+            vec![
+                (0x0, 0x1000, MMapEntryType::Free),
+                (0x1000, 0x2000, MMapEntryType::Used),
+                (0x3000, 0xd000, MMapEntryType::Free),
+            ]
+        );
+        assert_eq!(mmap.marked_size(), 0x2000);
+    }
+
+    #[test]
+    fn mark_used_ignores_empty_and_unmapped_regions() {
+        let mut mmap = mmap_of(&[(0x0, 0x1000, MMapEntryType::Free)]);
+        mmap.mark_used(0x0, 0);
+        mmap.mark_used(0x10000, 0x1000);
+        assert_eq!(entries_of(&mmap), vec![(0x0, 0x1000, MMapEntryType::Free)]);
+        assert_eq!(mmap.marked_size(), 0x1000);
+    }
+
+    #[test]
+    fn merges_free_entries_around_empty_entry() {
+        let mmap = convert(&[
+            (0x0, 1, MemoryType::CONVENTIONAL),
+            (0x1000, 0, MemoryType::RESERVED),
+            (0x1000, 1, MemoryType::BOOT_SERVICES_DATA),
+        ]);
+        assert_eq!(entries_of(&mmap), vec![(0x0, 0x2000, MMapEntryType::Free)]);
+    }
+
+    #[test]
+    fn same_base_entries_convert_the_same_in_any_order() {
+        let used = (0x1000, 2, MemoryType::RESERVED);
+        let free = (0x1000, 1, MemoryType::CONVENTIONAL);
+        let expected = vec![(0x1000, 0x2000, MMapEntryType::Used)];
+        assert_eq!(entries_of(&convert(&[used, free])), expected);
+        assert_eq!(entries_of(&convert(&[free, used])), expected);
+
+        // Entries with the same address and size are ordered by type
+        let mut entries = [
+            MMapEntry::new(0x1000, 0x1000, MMapEntryType::Acpi).unwrap(),
+            MMapEntry::new(0x1000, 0x1000, MMapEntryType::Free).unwrap(),
+        ];
+        entries.sort_unstable();
+        assert_eq!(entries[0].memory_type(), MMapEntryType::Free);
+        assert_eq!(entries[1].memory_type(), MMapEntryType::Acpi);
+        assert_ne!(entries[0], entries[1]);
+    }
+
+    #[test]
+    fn conversion_conserves_size() {
+        let descriptors = [
+            (0x0, 16, MemoryType::CONVENTIONAL),
+            (0x4000, 4, MemoryType::RUNTIME_SERVICES_DATA),
+            (0x10000, 4, MemoryType::ACPI_RECLAIM),
+            (0x14000, 4, MemoryType::LOADER_DATA),
+            (0x18000, 8, MemoryType::BOOT_SERVICES_CODE),
+        ];
+        let mmap = convert(&descriptors);
+        assert_eq!(
+            entries_of(&mmap),
+            vec![
+                (0x0, 0x4000, MMapEntryType::Free),
+                (0x4000, 0x4000, MMapEntryType::Used),
+                (0x8000, 0x8000, MMapEntryType::Free),
+                (0x10000, 0x4000, MMapEntryType::Acpi),
+                (0x14000, 0xc000, MMapEntryType::Free),
+            ]
+        );
+
+        // Only the 4 pages where the first two descriptors overlap are dropped
+        let pages: u64 = descriptors.iter().map(|&(_, pages, _)| pages).sum();
+        assert_eq!(total_size(mmap.entries()), (pages - 4) * PAGE_SIZE);
+        assert_eq!(mmap.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_finds_empty_entry() {
+        let mmap = mmap_of(&[
+            (0x0, 0x1000, MMapEntryType::Free),
+            (0x1000, 0, MMapEntryType::Used),
+        ]);
+        assert_eq!(mmap.validate(), Err(MMapError::EmptyEntry(1)));
+    }
+
+    #[test]
+    fn validate_finds_unsorted_entries() {
+        let mmap = mmap_of(&[
+            (0x2000, 0x1000, MMapEntryType::Free),
+            (0x0, 0x1000, MMapEntryType::Used),
+        ]);
+        assert_eq!(mmap.validate(), Err(MMapError::NotSorted(1)));
+    }
+
+    #[test]
+    fn validate_finds_overlapping_entries() {
+        let mmap = mmap_of(&[
+            (0x0, 0x1000, MMapEntryType::Free),
+            (0x1000, 0x2000, MMapEntryType::Used),
+            (0x2000, 0x1000, MMapEntryType::Acpi),
+        ]);
+        assert_eq!(mmap.validate(), Err(MMapError::Overlapping(2)));
+    }
+
+    #[test]
+    fn validate_finds_too_many_entries() {
+        let entries: Vec<_> = (0..=MMAP_MAX_ENTRIES as u64)
+            .map(|i| (i * 0x2000, 0x1000, MMapEntryType::Free))
+            .collect();
+        assert_eq!(mmap_of(&entries[1..]).validate(), Ok(()));
+        assert_eq!(mmap_of(&entries).validate(), Err(MMapError::TooManyEntries));
+    }
+
+    #[test]
+    fn to_bytes_stores_size_in_bytes_with_type() {
+        let entry = MMapEntry::new(0x10_0000, 0x3000, MMapEntryType::Mmio).unwrap();
+        let bytes = entry.to_bytes();
+        assert_eq!(bytes[..8], 0x10_0000u64.to_le_bytes());
+        assert_eq!(bytes[8..], 0x3003u64.to_le_bytes());
+    }
+
+    #[test]
+    fn acpi_memory_types() {
+        let descriptors = [
+            (0x1000, 1, MemoryType::ACPI_RECLAIM),
+            (0x3000, 1, MemoryType::ACPI_NON_VOLATILE),
+        ];
+        let types = |reclaim_acpi| -> Vec<MMapEntryType> {
+            convert_reclaiming(&descriptors, reclaim_acpi)
+                .entries()
+                .iter()
+                .map(MMapEntry::memory_type)
+                .collect()
+        };
+        assert_eq!(types(false), [MMapEntryType::Acpi, MMapEntryType::Acpi]);
+        // NVS memory is never reclaimed
+        assert_eq!(types(true), [MMapEntryType::Free, MMapEntryType::Acpi]);
+    }
+
+    #[test]
+    fn add_size_limit() {
+        const HALF: u64 = 1 << 59;
+        let entry = |ptr, size| MMapEntry::new(ptr, size, MMapEntryType::Free).unwrap();
+
+        // The largest size that fits is 2^60 - 1
+        let mut largest = entry(0, HALF);
+        assert_eq!(largest.add_size(&entry(HALF, HALF - 1)), Ok(()));
+        assert_eq!(largest.size(), (1 << 60) - 1);
+        assert_eq!(largest.memory_type(), MMapEntryType::Free);
+
+        // Reaching exactly 2^60 or going past it fails and leaves the entry unchanged
+        let first = entry(0, HALF);
+        for size in [HALF, HALF + 0x1000] {
+            let mut merged = first;
+            assert_eq!(merged.add_size(&entry(HALF, size)), Err(()));
+            assert_eq!(merged, first);
+            assert_eq!(first.merge(&entry(HALF, size)), None);
+        }
+
+        let mut mmap = vec![first, entry(HALF, HALF)];
+        merge_entries(&mut mmap);
+        assert_eq!(mmap, [first, entry(HALF, HALF)]);
     }
 }
@@ -0,0 +1,136 @@
+use alloc::format;
+use core::{
+    fmt::Write,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+use log::error;
+use uefi::{
+    prelude::{ResultExt, Status},
+    proto::media::file::{File, FileAttribute, FileMode},
+    Error as UefiError, Result as UefiResult,
+};
+
+use crate::{arch, logger, open_dir, open_file, BOOT_DIR};
+
+/// Name of the file in the `BOOTBOOT` directory that panics are written to.
+const CRASH_LOG: &str = "CRASHLOG";
+
+/// Set once the crash log is being written, so a panic while writing it does not write it again.
+static WRITING_CRASH_LOG: AtomicBool = AtomicBool::new(false);
+
+/// The phase of the boot process that the bootloader is currently in.
+///
+/// The current phase is included in panic messages. In release mode, it is printed as a numeric
+/// error code.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BootPhase {
+    ReadInitrd = 1,
+    ParseEnv = 2,
+    Framebuffer = 3,
+    LoadElf = 4,
+    Mmap = 5,
+    Paging = 6,
+    Smp = 7,
+    Handoff = 8,
+}
+
+/// The current boot phase, stored as a `u8` so it can be read from the panic handler.
+static BOOT_PHASE: AtomicU8 = AtomicU8::new(BootPhase::ReadInitrd as u8);
+
+impl BootPhase {
+    /// Returns the current boot phase.
+    pub fn current() -> Self {
+        match BOOT_PHASE.load(Ordering::Relaxed) {
+            1 => Self::ReadInitrd,
+            2 => Self::ParseEnv,
+            3 => Self::Framebuffer,
+            4 => Self::LoadElf,
+            5 => Self::Mmap,
+            6 => Self::Paging,
+            7 => Self::Smp,
+            _ => Self::Handoff,
+        }
+    }
+
+    /// Sets this as the current boot phase.
+    pub fn enter(self) {
+        BOOT_PHASE.store(self as u8, Ordering::Relaxed);
+    }
+}
+
+/// Prints the panic along with the current boot phase to the logger and the serial port, and halts
+/// the processor.
+///
+/// In debug mode, the panic message and location are printed. In release mode, only the boot phase
+/// is printed as an error code.
+///
+/// If boot services are still active, the full panic message and the most recent log output are
+/// also written to `BOOTBOOT/CRASHLOG` on the boot partition.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    let phase = BootPhase::current();
+    // Also write to the serial port, as the logger may not be initialized yet
+    let mut serial = arch::serial::SerialWriter;
+    if cfg!(debug_assertions) {
+        error!("Panicked during phase {:?}: {}", phase, info);
+        let _ = writeln!(serial, "Panicked during phase {:?}: {}", phase, info);
+    } else {
+        error!("Boot failed with error code {}", phase as u8);
+        let _ = writeln!(serial, "Boot failed with error code {}", phase as u8);
+    }
+
+    // Writing the crash log is best effort, as the panic may have left the firmware in a bad state
+    if logger::boot_services_active() && !WRITING_CRASH_LOG.swap(true, Ordering::Relaxed) {
+        let _ = write_crash_log(info, phase);
+    }
+
+    loop {
+        arch::halt();
+    }
+}
+
+/// Writes the panic message, boot phase, and most recent log output to `BOOTBOOT/CRASHLOG`,
+/// replacing any previous crash log.
+///
+/// # Errors
+///
+/// Returns an error if the boot partition cannot be opened or the file cannot be written.
+fn write_crash_log(info: &PanicInfo, phase: BootPhase) -> UefiResult {
+    let image_handle = logger::image_handle().ok_or_else(|| UefiError::from(Status::NOT_READY))?;
+    let st = unsafe { uefi_services::system_table().as_ref() };
+    let bt = st.boot_services();
+    let fs = bt.get_image_file_system(image_handle)?;
+    let fs = unsafe { &mut *fs.interface.get() };
+    let mut root = fs.open_volume()?;
+    let mut bootdir = open_dir(&mut root, BOOT_DIR)?;
+
+    // Delete the previous crash log, as creating a file does not truncate it
+    if let Ok(old_log) = open_file(
+        &mut bootdir,
+        CRASH_LOG,
+        FileMode::ReadWrite,
+        FileAttribute::empty(),
+    ) {
+        let _ = old_log.delete();
+    }
+    let mut crash_log = open_file(
+        &mut bootdir,
+        CRASH_LOG,
+        FileMode::CreateReadWrite,
+        FileAttribute::empty(),
+    )?;
+
+    let message = format!(
+        "Panicked during phase {:?}: {}\n\nRecent log output:\n",
+        phase, info
+    );
+    crash_log.write(message.as_bytes()).discard_errdata()?;
+    for line in logger::recent_messages() {
+        crash_log.write(line.as_bytes()).discard_errdata()?;
+        crash_log.write(b"\n").discard_errdata()?;
+    }
+    crash_log.flush()
+}
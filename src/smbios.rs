@@ -1,7 +1,17 @@
+use core::{iter, slice, str};
 use log::debug;
 use uefi::table::cfg::{self, ConfigTableEntry};
 
-use crate::utils::{Checksum, Magic, ParseError};
+use crate::utils::{self, Checksum, Hex, Magic, ParseError};
+
+/// Type of the SMBIOS BIOS Information structure.
+const BIOS_INFO_TYPE: u8 = 0;
+
+/// Type of the SMBIOS structure that marks the end of the structure table.
+const END_OF_TABLE_TYPE: u8 = 127;
+
+/// Size of the type, length, and handle fields at the start of every SMBIOS structure.
+const STRUCT_HEADER_SIZE: usize = 4;
 
 /// SMBIOS entry point struct.
 #[repr(C)]
@@ -17,9 +27,9 @@ pub struct SmbiosEntryPoint {
     _formatted_area: [u8; 5],
     _intermediate_anchor: [u8; 5],
     _intermediate_checksum: u8,
-    _table_length: u16,
-    _table_address: u32,
-    _num_structs: u16,
+    table_length: u16,
+    table_address: u32,
+    num_structs: u16,
     _bcd_revision: u8,
 }
 
@@ -39,7 +49,7 @@ impl SmbiosEntryPoint {
     /// * `ParseError::NoTable`: SMBIOS table cannot be found
     /// * `ParseError::FailedChecksum`: SMBIOS checksum failed
     /// * `ParseError::InvalidSignature`: SMBIOS signature is invalid
-    /// * `ParseError::InvalidPointer`: A null pointer was found during parse
+    /// * `ParseError::InvalidPointer`: A null or non-canonical pointer was found during parse
     pub fn from_uefi_config_table(
         config_table: &[ConfigTableEntry],
     ) -> Result<&SmbiosEntryPoint, ParseError> {
@@ -50,6 +60,11 @@ impl SmbiosEntryPoint {
             .ok_or(ParseError::NoTable)?;
         let smbios_addr = smbios_entry.address;
 
+        // Return error if address cannot be dereferenced
+        if !utils::is_valid_pointer(smbios_addr as u64) {
+            return Err(ParseError::InvalidPointer);
+        }
+
         // Convert to SMBIOS struct
         // May not be valid
         let smbios = unsafe {
@@ -73,8 +88,9 @@ impl SmbiosEntryPoint {
         //--------------------------------
 
         debug!(
-            "Found SMBIOS of size 0x{:x} at 0x{:x}",
-            smbios.entry_point_length, smbios_entry.address as usize
+            "Found SMBIOS of size 0x{:x} at {}",
+            smbios.entry_point_length,
+            Hex(smbios_entry.address as u64)
         );
         Ok(smbios)
     }
@@ -82,4 +98,100 @@ impl SmbiosEntryPoint {
     pub fn valid_magic() -> &'static [u8; 4] {
         b"_SM_"
     }
+
+    /// Returns the firmware vendor and version from the BIOS Information (Type 0) structure.
+    ///
+    /// Returns `None` if the structure table cannot be read or does not have a BIOS Information
+    /// structure.
+    pub fn bios_info(&self) -> Option<BiosInfo<'_>> {
+        let bios = self
+            .structures()
+            .find(|structure| structure.struct_type() == BIOS_INFO_TYPE)?;
+
+        // Vendor and version are string numbers at offsets 4 and 5
+        Some(BiosInfo {
+            vendor: bios.string(*bios.formatted.get(4)?).unwrap_or(""),
+            version: bios.string(*bios.formatted.get(5)?).unwrap_or(""),
+        })
+    }
+
+    /// Returns an iterator over the structures in the SMBIOS structure table.
+    ///
+    /// Iteration stops at the end-of-table structure, after the number of structures in the entry
+    /// point, or at the first structure that does not fit in the table.
+    fn structures(&self) -> impl Iterator<Item = SmbiosStructure<'_>> {
+        let table: &[u8] = if utils::is_valid_pointer(self.table_address as u64) {
+            unsafe {
+                slice::from_raw_parts(
+                    self.table_address as usize as *const u8,
+                    self.table_length as usize,
+                )
+            }
+        } else {
+            &[]
+        };
+
+        let mut offset = 0;
+        iter::from_fn(move || {
+            let structure = SmbiosStructure::from_bytes(table.get(offset..)?)?;
+            offset += structure.formatted.len() + structure.strings.len();
+            Some(structure)
+        })
+        .take(self.num_structs as usize)
+        .take_while(|structure| structure.struct_type() != END_OF_TABLE_TYPE)
+    }
+}
+
+/// Firmware identification from the SMBIOS BIOS Information structure.
+#[derive(Clone, Copy, Debug)]
+pub struct BiosInfo<'a> {
+    pub vendor: &'a str,
+    pub version: &'a str,
+}
+
+/// A structure in the SMBIOS structure table.
+struct SmbiosStructure<'a> {
+    /// Formatted area, starting with the structure header
+    formatted: &'a [u8],
+    /// Null terminated strings that follow the formatted area, including the final null byte
+    strings: &'a [u8],
+}
+
+impl<'a> SmbiosStructure<'a> {
+    /// Parses the structure at the start of `data`.
+    ///
+    /// Returns `None` if the structure does not fit in `data`.
+    fn from_bytes(data: &'a [u8]) -> Option<Self> {
+        let length = *data.get(1)? as usize;
+        if length < STRUCT_HEADER_SIZE {
+            return None;
+        }
+        let formatted = data.get(..length)?;
+
+        // The string set ends with two null bytes, even if there are no strings
+        let rest = &data[length..];
+        let strings_len = rest.windows(2).position(|pair| pair == [0, 0])? + 2;
+
+        Some(Self {
+            formatted,
+            strings: &rest[..strings_len],
+        })
+    }
+
+    fn struct_type(&self) -> u8 {
+        self.formatted[0]
+    }
+
+    /// Returns the string numbered `index`, starting from 1.
+    ///
+    /// Returns `None` if `index` is 0 (no string), out of range, or not valid UTF-8.
+    fn string(&self, index: u8) -> Option<&'a str> {
+        let index = (index as usize).checked_sub(1)?;
+        let string = self
+            .strings
+            .split(|&byte| byte == 0)
+            .take_while(|string| !string.is_empty())
+            .nth(index)?;
+        str::from_utf8(string).ok()
+    }
 }
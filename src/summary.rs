@@ -0,0 +1,101 @@
+use core::fmt::{self, Display, Formatter};
+use uefi::table::runtime::Time;
+
+use crate::{Framebuffer, Hex, InitrdFormat};
+
+/// Everything the bootloader is about to hand off to the kernel, collected in one place.
+///
+/// Formatting with `{}` prints an abbreviated single line summary, while `{:#}` prints every
+/// field on its own line.
+pub struct BootSummary<'a> {
+    pub kernel: &'a str,
+    /// Size of the kernel file in bytes, before decompression
+    pub kernel_size: usize,
+    pub entry_point: u64,
+    pub load_segments: usize,
+    pub initrd_size: usize,
+    pub initrd_format: InitrdFormat,
+    pub framebuffer: &'a Framebuffer,
+    /// Total memory in bytes, including used and reserved memory
+    pub total_memory: u64,
+    pub free_memory: u64,
+    /// Memory in bytes allocated by the loader for the kernel
+    pub loader_memory: u64,
+    pub acpi: bool,
+    pub smbios: bool,
+    pub num_cores: u16,
+    pub time: Option<Time>,
+}
+
+/// Formats an optional UEFI time as an ISO 8601 date and time.
+struct DateTime(Option<Time>);
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(time) => write!(
+                f,
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                time.year(),
+                time.month(),
+                time.day(),
+                time.hour(),
+                time.minute(),
+                time.second()
+            ),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+impl Display for BootSummary<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if !f.alternate() {
+            return write!(
+                f,
+                "Booting {} ({} KiB) at {}, {}x{}, {} MiB free",
+                self.kernel,
+                self.kernel_size / 1024,
+                Hex(self.entry_point),
+                self.framebuffer.width,
+                self.framebuffer.height,
+                self.free_memory / (1024 * 1024)
+            );
+        }
+
+        writeln!(f, "Boot summary:")?;
+        writeln!(
+            f,
+            "Kernel: {} ({} KiB, entry point {}, {} LOAD segments)",
+            self.kernel,
+            self.kernel_size / 1024,
+            Hex(self.entry_point),
+            self.load_segments
+        )?;
+        writeln!(
+            f,
+            "Initrd: {} KiB ({:?})",
+            self.initrd_size / 1024,
+            self.initrd_format
+        )?;
+        if self.framebuffer.is_null() {
+            writeln!(f, "Framebuffer: none")?;
+        } else {
+            writeln!(
+                f,
+                "Framebuffer: {}x{} ({:?})",
+                self.framebuffer.width, self.framebuffer.height, self.framebuffer.fb_type
+            )?;
+        }
+        writeln!(
+            f,
+            "Memory: {} MiB total, {} MiB free, {} KiB used by loader",
+            self.total_memory / (1024 * 1024),
+            self.free_memory / (1024 * 1024),
+            self.loader_memory / 1024
+        )?;
+        writeln!(f, "ACPI: {}, SMBIOS: {}", self.acpi, self.smbios)?;
+        writeln!(f, "Cores: {}", self.num_cores)?;
+        write!(f, "Time: {}", DateTime(self.time))
+    }
+}
@@ -4,6 +4,70 @@ use uefi::{
     Result as UefiResult,
 };
 
+#[cfg(target_arch = "x86_64")]
+use crate::{acpi::PmTimer, arch};
+
+/// Number of PM timer ticks to measure the TSC over, which is about 10ms.
+#[cfg(target_arch = "x86_64")]
+const CALIBRATION_TICKS: u64 = PmTimer::FREQUENCY / 100;
+/// Number of PM timer reads before giving up on a timer that does not count.
+#[cfg(target_arch = "x86_64")]
+const CALIBRATION_MAX_READS: usize = 1_000_000;
+
+/// Value of [`Time::time_zone`] when the firmware does not know the time zone.
+const UNSPECIFIED_TIMEZONE: i16 = 2047;
+
 pub fn get_time(st: &SystemTable<Boot>) -> UefiResult<Time> {
     st.runtime_services().get_time()
 }
+
+/// Converts `time` to the date and time in the BOOTBOOT struct, along with the time zone in
+/// minutes.
+///
+/// The date and time are 7 BCD bytes for the century, year, month, day, hour, minute, and second,
+/// followed by a zero byte. The time zone is 0 if the firmware does not know it.
+pub fn bootboot_datetime(time: &Time) -> ([u8; 8], i16) {
+    let bcd = |value: u8| ((value / 10) << 4) | (value % 10);
+    let datetime = [
+        bcd((time.year() / 100) as u8),
+        bcd((time.year() % 100) as u8),
+        bcd(time.month()),
+        bcd(time.day()),
+        bcd(time.hour()),
+        bcd(time.minute()),
+        bcd(time.second()),
+        0,
+    ];
+    let timezone = match time.time_zone() {
+        UNSPECIFIED_TIMEZONE => 0,
+        timezone => timezone,
+    };
+    (datetime, timezone)
+}
+
+/// Returns the frequency of the time stamp counter in Hz, measured against the ACPI PM timer.
+///
+/// Returns `None` if the PM timer does not count. HPET is not supported as a reference timer.
+#[cfg(target_arch = "x86_64")]
+pub fn calibrate_tsc(pm_timer: &PmTimer) -> Option<u64> {
+    let mask = if pm_timer.is_32bit {
+        u32::MAX
+    } else {
+        0x00ff_ffff
+    };
+    let read_timer = || unsafe { arch::inl(pm_timer.port) } & mask;
+
+    // Count TSC ticks until enough PM timer ticks have passed
+    // The PM timer may wrap around during calibration
+    let timer_start = read_timer();
+    let tsc_start = arch::rdtsc();
+    for _ in 0..CALIBRATION_MAX_READS {
+        let timer_ticks = (read_timer().wrapping_sub(timer_start) & mask) as u64;
+        if timer_ticks >= CALIBRATION_TICKS {
+            let tsc_ticks = arch::rdtsc() - tsc_start;
+            return Some(tsc_ticks * PmTimer::FREQUENCY / timer_ticks);
+        }
+    }
+
+    None
+}
@@ -1,6 +1,12 @@
-use core::{mem, num::Wrapping, slice};
+use crate::arch;
+use core::{
+    fmt::{self, Display, Formatter},
+    mem,
+    num::Wrapping,
+    slice,
+};
 
-/// An error resulting from parsing ACPI or SMBIOS tables.
+/// An error resulting from parsing ACPI or SMBIOS tables, or the environment.
 pub enum ParseError {
     FailedChecksum,
     InvalidPointer,
@@ -10,6 +16,18 @@ pub enum ParseError {
     TooLarge,
 }
 
+/// Lowest address that is treated as a valid pointer, as the first page is never used for firmware
+/// tables.
+const MIN_VALID_ADDRESS: u64 = 0x1000;
+
+/// Returns true if `addr` is a plausible pointer to firmware data.
+///
+/// This does not guarantee that `addr` is mapped, but catches null, near-null, and non-canonical
+/// pointers that would otherwise cause a fault when dereferenced.
+pub fn is_valid_pointer(addr: u64) -> bool {
+    addr >= MIN_VALID_ADDRESS && arch::is_canonical(addr)
+}
+
 /// A type that implements a checksum.
 pub trait Checksum {
     /// Gets the sum of every byte that composes this struct and returns the least significant
@@ -50,3 +68,31 @@ pub trait Magic<const SIZE: usize> {
     /// Returns this instance's magic numbers.
     fn magic(&self) -> &[u8; SIZE];
 }
+
+/// A value that is displayed as a zero-padded 64bit hexadecimal number (e.g.
+/// `0x00000000001f0000`).
+///
+/// Used to keep addresses in logs aligned.
+#[derive(Clone, Copy, Debug)]
+pub struct Hex(pub u64);
+
+impl Display for Hex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:016x}", self.0)
+    }
+}
+
+/// Bytes that are displayed as lowercase hexadecimal digits without separators (e.g. `1f00ab`).
+///
+/// Used for identifiers such as hashes and build-ids.
+#[derive(Clone, Copy, Debug)]
+pub struct HexBytes<'a>(pub &'a [u8]);
+
+impl Display for HexBytes<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}